@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+
+use crate::engine::apply::now_ms;
+use crate::server::Peer;
+
+/// Why a client was turned away.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Reason {
+    Auth,
+    Command,
+    Key,
+}
+
+impl Reason {
+    fn name(self) -> &'static str {
+        match self {
+            Reason::Auth => "auth",
+            Reason::Command => "command",
+            Reason::Key => "key",
+        }
+    }
+}
+
+struct Entry {
+    id: u64,
+    count: u64,
+    reason: Reason,
+    /// The command or key that was refused, or `AUTH`.
+    object: String,
+    username: String,
+    client: Peer,
+    created: u64,
+    updated: u64,
+}
+
+/// Recent ACL failures, newest first. Repeats of the same failure by the
+/// same user bump an entry's count instead of adding one, like Redis.
+pub struct AclLog {
+    max_len: usize,
+    entries: Mutex<(u64, VecDeque<Entry>)>,
+}
+
+impl AclLog {
+    pub fn new(max_len: usize) -> Self {
+        Self { max_len, entries: Mutex::new((0, VecDeque::new())) }
+    }
+
+    pub fn record(&self, reason: Reason, object: &str, username: &str, client: &Peer) {
+        if self.max_len == 0 {
+            return;
+        }
+        let now = now_ms();
+        let mut guard = self.entries.lock().unwrap();
+        let (next_id, entries) = &mut *guard;
+        let same = entries
+            .iter()
+            .position(|e| e.reason == reason && e.object == object && e.username == username);
+        let entry = match same.and_then(|idx| entries.remove(idx)) {
+            Some(entry) => Entry { count: entry.count + 1, client: client.clone(), updated: now, ..entry },
+            None => {
+                *next_id += 1;
+                Entry {
+                    id: *next_id - 1,
+                    count: 1,
+                    reason,
+                    object: object.to_string(),
+                    username: username.to_string(),
+                    client: client.clone(),
+                    created: now,
+                    updated: now,
+                }
+            }
+        };
+        entries.push_front(entry);
+        entries.truncate(self.max_len);
+    }
+
+    /// `ACL LOG`: the newest `count` entries, one per line, then a blank line.
+    pub fn get(&self, count: usize) -> String {
+        let now = now_ms();
+        let guard = self.entries.lock().unwrap();
+        let mut out = String::new();
+        for entry in guard.1.iter().take(count) {
+            let _ = writeln!(
+                out,
+                "entry_id={} count={} reason={} object={} username={} age_seconds={:.3} client={} \
+                 timestamp_created={} timestamp_last_updated={}",
+                entry.id,
+                entry.count,
+                entry.reason.name(),
+                entry.object,
+                entry.username,
+                now.saturating_sub(entry.updated) as f64 / 1000.0,
+                entry.client,
+                entry.created,
+                entry.updated
+            );
+        }
+        out.push('\n');
+        out
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().1.clear();
+    }
+}
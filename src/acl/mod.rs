@@ -0,0 +1,612 @@
+pub mod log;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::sync::{OnceLock, RwLock};
+use std::{fs, io};
+
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::config::Config;
+use crate::engine::ParsedCommand;
+use crate::engine::command::{AclCommand, ClientFilter};
+use crate::engine::parser::quote;
+use crate::server::clients::Client;
+use crate::shard_engine::router::ShardRouter;
+use log::{AclLog, Reason};
+
+pub const DEFAULT_USER: &str = "default";
+
+/// Every command rules can name, with its categories. Subcommands
+/// (`CLIENT KILL`, `SCRIPT FLUSH`, ...) go with their command.
+const COMMANDS: &[(&str, &[&str])] = &[
+    ("acl", &["admin"]),
+    ("asking", &["connection"]),
+    ("auth", &["connection"]),
+    ("bgsave", &["admin"]),
+    ("cdc", &["admin"]),
+    ("client", &["admin", "connection"]),
+    ("cluster", &["admin"]),
+    ("del", &["write"]),
+    ("delifver", &["write"]),
+    ("eval", &["scripting"]),
+    ("evalsha", &["scripting"]),
+    ("ex", &["read"]),
+    ("expire", &["write"]),
+    ("get", &["read"]),
+    ("getver", &["read"]),
+    ("info", &["admin"]),
+    ("lastsave", &["admin"]),
+    ("latency", &["admin"]),
+    ("migrate", &["admin", "write"]),
+    ("ping", &["connection"]),
+    ("psync", &["admin"]),
+    ("raft", &["admin"]),
+    ("replicaof", &["admin"]),
+    ("restore", &["write"]),
+    ("role", &["admin"]),
+    ("save", &["admin"]),
+    ("script", &["scripting"]),
+    ("set", &["write"]),
+    ("setex", &["write"]),
+    ("setifver", &["write"]),
+    ("slowlog", &["admin"]),
+    ("ttl", &["read"]),
+];
+
+const CATEGORIES: [&str; 5] = ["admin", "connection", "read", "scripting", "write"];
+
+// Credentials for links to other nodes, from `masteruser` / `masterauth`.
+static LINK_AUTH: OnceLock<(String, String)> = OnceLock::new();
+
+/// A user as set up by `ACL SETUSER`. A new one is off, with no password,
+/// commands or keys.
+#[derive(Clone, Default)]
+struct User {
+    enabled: bool,
+    /// Any password will do.
+    nopass: bool,
+    /// SHA-256 of each password, in hex.
+    passwords: BTreeSet<String>,
+    commands: BTreeSet<&'static str>,
+    /// Glob patterns of the keys the user may touch.
+    keys: Vec<String>,
+}
+
+impl User {
+    /// Applies one `ACL SETUSER` rule.
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_ascii_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allkeys" => self.keys = vec!["*".into()],
+            "resetkeys" => self.keys.clear(),
+            "allcommands" => self.apply("+@all")?,
+            "nocommands" => self.apply("-@all")?,
+            "reset" => *self = User::default(),
+            _ => {
+                let (op, arg) = rule.split_at(rule.chars().next().map_or(0, char::len_utf8));
+                match op {
+                    ">" => {
+                        self.nopass = false;
+                        self.passwords.insert(hash(arg));
+                    }
+                    "#" if arg.len() != 64 || !arg.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                        return Err("the password hash must be 64 hex characters".into());
+                    }
+                    "#" => {
+                        self.nopass = false;
+                        self.passwords.insert(arg.to_ascii_lowercase());
+                    }
+                    "<" | "!" => {
+                        let hashed = if op == "<" { hash(arg) } else { arg.to_ascii_lowercase() };
+                        if !self.passwords.remove(&hashed) {
+                            return Err("no such password".into());
+                        }
+                    }
+                    "~" => {
+                        if !self.keys.iter().any(|k| k == arg) {
+                            self.keys.push(arg.to_string());
+                        }
+                    }
+                    "+" | "-" => {
+                        let names = commands_named(&arg.to_ascii_lowercase())?;
+                        if op == "+" {
+                            self.commands.extend(names);
+                        } else {
+                            self.commands.retain(|c| !names.contains(c));
+                        }
+                    }
+                    _ => return Err("syntax error".into()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The rules that set the user up from scratch, as `ACL LIST` and the
+    /// ACL file show them. Passwords only ever appear hashed.
+    fn rules(&self) -> String {
+        let mut rules = String::from(if self.enabled { "on" } else { "off" });
+        if self.nopass {
+            rules.push_str(" nopass");
+        }
+        for password in &self.passwords {
+            let _ = write!(rules, " #{}", password);
+        }
+        for pattern in &self.keys {
+            let _ = write!(rules, " ~{}", pattern);
+        }
+        rules.push_str(&self.command_rules());
+        rules
+    }
+
+    fn command_rules(&self) -> String {
+        if self.commands.len() == COMMANDS.len() {
+            return " +@all".into();
+        }
+        let mut rules = String::from(" -@all");
+        for command in &self.commands {
+            let _ = write!(rules, " +{}", command);
+        }
+        rules
+    }
+
+    fn accepts(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.contains(&hash(password)))
+    }
+
+    fn may_touch(&self, key: &[u8]) -> bool {
+        self.keys.iter().any(|pattern| glob_match(pattern.as_bytes(), key))
+    }
+}
+
+/// `cmd`, or every command in `@category`, or all of them for `@all`.
+fn commands_named(name: &str) -> Result<Vec<&'static str>, String> {
+    let named: Vec<&'static str> = match name.strip_prefix('@') {
+        Some("all") => COMMANDS.iter().map(|(command, _)| *command).collect(),
+        Some(category) if CATEGORIES.contains(&category) => {
+            COMMANDS.iter().filter(|(_, cats)| cats.contains(&category)).map(|(command, _)| *command).collect()
+        }
+        Some(_) => return Err("unknown command category".into()),
+        None => COMMANDS.iter().filter(|(command, _)| *command == name).map(|(command, _)| *command).collect(),
+    };
+    if named.is_empty() {
+        return Err("unknown command".into());
+    }
+    Ok(named)
+}
+
+fn hash(password: &str) -> String {
+    let mut hex = String::with_capacity(64);
+    for byte in Sha256::digest(password.as_bytes()) {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Users and what they may do, checked before every command.
+pub struct Acl {
+    users: RwLock<BTreeMap<String, User>>,
+    /// Where `ACL SAVE` and `ACL LOAD` go, from `aclfile`.
+    file: Option<String>,
+    pub log: AclLog,
+}
+
+impl Acl {
+    /// Users from `aclfile` if there is one, or else just `default`, which
+    /// needs `requirepass` if that is set.
+    pub fn load(config: &Config) -> Result<Self, String> {
+        let users = match &config.aclfile {
+            Some(path) => read_file(path)?,
+            None => {
+                let mut users = defaults();
+                if let Some(password) = &config.requirepass {
+                    let default = users.get_mut(DEFAULT_USER).unwrap();
+                    default.apply("resetpass")?;
+                    default.apply(&format!(">{}", password))?;
+                }
+                users
+            }
+        };
+        Ok(Self {
+            users: RwLock::new(users),
+            file: config.aclfile.clone(),
+            log: AclLog::new(config.acllog_max_len),
+        })
+    }
+
+    /// Who a new connection starts out as: the user its client certificate
+    /// names if there is one, `default` if it needs no password, or no one
+    /// until `AUTH`.
+    pub fn login(&self, cert_user: Option<&str>) -> Option<String> {
+        let users = self.users.read().unwrap();
+        if let Some(name) = cert_user.filter(|name| users.get(*name).is_some_and(|u| u.enabled)) {
+            return Some(name.to_string());
+        }
+        users.get(DEFAULT_USER).filter(|u| u.enabled && u.nopass).map(|_| DEFAULT_USER.to_string())
+    }
+
+    /// The reply turning `parsed` away, or `None` if the client may run it.
+    pub fn check(&self, client: &Client, parsed: &ParsedCommand) -> Option<String> {
+        if let ParsedCommand::Auth { .. } = parsed {
+            return None;
+        }
+        let name = client.user();
+        let users = self.users.read().unwrap();
+        let Some((name, user)) = name.as_deref().and_then(|name| Some((name, users.get(name)?))) else {
+            return Some("NOAUTH Authentication required.\n".into());
+        };
+        let command = parsed.name();
+        if !user.commands.contains(command) {
+            self.log.record(Reason::Command, command, name, &client.addr);
+            return Some(format!("NOPERM User {} has no permissions to run the '{}' command\n", name, command));
+        }
+        let keys = match parsed {
+            ParsedCommand::Migrate { keys, .. } => keys.iter().map(|k| &k[..]).collect(),
+            parsed => parsed.keys(),
+        };
+        if let Some(key) = keys.into_iter().find(|key| !user.may_touch(key)) {
+            self.log.record(Reason::Key, &String::from_utf8_lossy(key), name, &client.addr);
+            return Some("NOPERM No permissions to access a key\n".into());
+        }
+        None
+    }
+
+    fn authenticate(&self, name: &str, password: &str) -> bool {
+        self.users.read().unwrap().get(name).is_some_and(|user| user.accepts(password))
+    }
+
+    /// Applies every rule or, if one is bad, none of them.
+    fn set_user(&self, name: &str, rules: &[String]) -> Result<(), String> {
+        let mut users = self.users.write().unwrap();
+        let mut user = users.get(name).cloned().unwrap_or_default();
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| format!("ERR Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    fn get_user(&self, name: &str) -> String {
+        let users = self.users.read().unwrap();
+        let Some(user) = users.get(name) else {
+            return "nil\n".into();
+        };
+        let mut out = String::new();
+        let _ = write!(out, "flags {}", if user.enabled { "on" } else { "off" });
+        out.push_str(if user.nopass { " nopass\n" } else { "\n" });
+        let passwords: Vec<&str> = user.passwords.iter().map(String::as_str).collect();
+        let _ = writeln!(out, "passwords {}", passwords.join(" "));
+        let _ = writeln!(out, "commands{}", user.command_rules());
+        let keys: Vec<String> = user.keys.iter().map(|k| format!("~{}", k)).collect();
+        let _ = writeln!(out, "keys {}", keys.join(" "));
+        out.push('\n');
+        out
+    }
+
+    /// `user <name> <rules>` lines, as in the ACL file.
+    fn list(&self) -> String {
+        let mut out = String::new();
+        for (name, user) in self.users.read().unwrap().iter() {
+            let _ = writeln!(out, "user {} {}", name, user.rules());
+        }
+        out
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = self.file.as_ref().ok_or("This instance is not configured to use an ACL file")?;
+        let tmp = format!("{}.tmp", path);
+        let write = || -> io::Result<()> {
+            fs::write(&tmp, self.list())?;
+            fs::File::open(&tmp)?.sync_all()?;
+            fs::rename(&tmp, path)
+        };
+        write().map_err(|e| format!("can't save {}: {}", path, e))
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        let path = self.file.as_ref().ok_or("This instance is not configured to use an ACL file")?;
+        *self.users.write().unwrap() = read_file(path)?;
+        Ok(())
+    }
+}
+
+/// `default` with no password, allowed everything, as Redis starts out.
+fn defaults() -> BTreeMap<String, User> {
+    let mut default = User::default();
+    for rule in ["on", "nopass", "allkeys", "allcommands"] {
+        default.apply(rule).unwrap();
+    }
+    BTreeMap::from([(DEFAULT_USER.to_string(), default)])
+}
+
+/// Reads `user <name> <rules...>` lines; `#` starts a comment. A missing
+/// file is no users but `default`, which the file can redefine.
+fn read_file(path: &str) -> Result<BTreeMap<String, User>, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("can't read {}: {}", path, e)),
+    };
+    let mut users = defaults();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |e: String| format!("{}:{}: {}", path, number + 1, e);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let ["user", name, rules @ ..] = tokens.as_slice() else {
+            return Err(invalid("expected 'user <name> <rules...>'".into()));
+        };
+        let mut user = User::default();
+        for rule in rules {
+            user.apply(rule).map_err(|e| invalid(format!("'{}': {}", rule, e)))?;
+        }
+        users.insert(name.to_string(), user);
+    }
+    Ok(users)
+}
+
+/// `AUTH [user] password`; the user is `default` if not given.
+pub fn auth(router: &ShardRouter, client: &Client, user: Option<String>, password: String) -> String {
+    let user = user.unwrap_or_else(|| DEFAULT_USER.into());
+    if router.acl().authenticate(&user, &password) {
+        client.set_user(Some(&user));
+        "OK\n".into()
+    } else {
+        router.acl().log.record(Reason::Auth, "AUTH", &user, &client.addr);
+        "WRONGPASS invalid username-password pair or user is disabled.\n".into()
+    }
+}
+
+/// Runs an `ACL` subcommand for `client`.
+pub fn execute(router: &ShardRouter, client: &Client, cmd: AclCommand) -> String {
+    let acl = router.acl();
+    match cmd {
+        AclCommand::SetUser { name, rules } => match acl.set_user(&name, &rules) {
+            Ok(()) => "OK\n".into(),
+            Err(e) => format!("{}\n", e),
+        },
+        AclCommand::GetUser { name } => acl.get_user(&name),
+        AclCommand::DelUser { names } if names.iter().any(|n| n == DEFAULT_USER) => {
+            "ERR The 'default' user cannot be removed\n".into()
+        }
+        AclCommand::DelUser { names } => {
+            let mut deleted = 0;
+            for name in names {
+                if acl.users.write().unwrap().remove(&name).is_some() {
+                    // Like Redis, a deleted user's connections go with it.
+                    router.clients().kill(&ClientFilter::User(name));
+                    deleted += 1;
+                }
+            }
+            format!("{}\n", deleted)
+        }
+        AclCommand::List => format!("{}\n", acl.list()),
+        AclCommand::Users => {
+            let mut out = String::new();
+            for name in acl.users.read().unwrap().keys() {
+                let _ = writeln!(out, "{}", name);
+            }
+            out.push('\n');
+            out
+        }
+        AclCommand::WhoAmI => format!("{}\n", client.user().as_deref().unwrap_or(DEFAULT_USER)),
+        AclCommand::Cat { category: None } => format!("{}\n\n", CATEGORIES.join("\n")),
+        AclCommand::Cat { category: Some(category) } => match commands_named(&format!("@{}", category)) {
+            Ok(commands) => format!("{}\n\n", commands.join("\n")),
+            Err(_) => format!("ERR Unknown category '{}'\n", category),
+        },
+        AclCommand::Log { count } => acl.log.get(count),
+        AclCommand::LogReset => {
+            acl.log.reset();
+            "OK\n".into()
+        }
+        AclCommand::Save => match acl.save() {
+            Ok(()) => "OK\n".into(),
+            Err(e) => format!("ERR {}\n", e),
+        },
+        AclCommand::Load => match acl.reload() {
+            Ok(()) => "OK\n".into(),
+            Err(e) => format!("ERR {}\n", e),
+        },
+    }
+}
+
+/// Sets the credentials `connect` logs in with.
+pub fn set_link_auth(config: &Config) {
+    if let Some(password) = &config.masterauth {
+        let _ = LINK_AUTH.set((config.masteruser.clone(), password.clone()));
+    }
+}
+
+/// Opens a connection to another node (replication, Raft, gossip, MIGRATE),
+/// logged in as `masteruser` if `masterauth` is set.
+pub async fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpStream> {
+    let mut socket = TcpStream::connect(addr).await?;
+    if let Some((user, password)) = LINK_AUTH.get() {
+        let auth = [b"AUTH ".as_slice(), &quote(user.as_bytes()), b" ", &quote(password.as_bytes()), b"\n"].concat();
+        socket.write_all(&auth).await?;
+        // A byte at a time, so nothing meant for the caller gets read.
+        let mut reply = Vec::new();
+        loop {
+            match socket.read_u8().await? {
+                b'\n' => break,
+                byte => reply.push(byte),
+            }
+        }
+        if reply != b"OK" {
+            return Err(io::Error::other(String::from_utf8_lossy(&reply).into_owned()));
+        }
+    }
+    Ok(socket)
+}
+
+/// Redis-style glob: `*`, `?`, `[abc]`, `[^a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The last `*` and where in `text` it would take over from next, for when
+    // what follows it stops matching.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    star = Some((p, t));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+                b'[' => match class(pattern, p, text[t]) {
+                    Some((true, next)) => {
+                        p = next;
+                        t += 1;
+                        continue;
+                    }
+                    Some((false, _)) => {}
+                    // An unterminated class is just a `[`.
+                    None if text[t] == b'[' => {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                    None => {}
+                },
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == text[t] {
+                        p += 2;
+                        t += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == text[t] {
+                        p += 1;
+                        t += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        match star {
+            Some((star_p, star_t)) => {
+                p = star_p + 1;
+                t = star_t + 1;
+                star = Some((star_p, star_t + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the `[...]` class starting at `pattern[start]`.
+/// Returns whether it matched and where the pattern goes on, or `None` if
+/// the class never ends.
+fn class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (low, high) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= (low..=high).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
+    (i < pattern.len()).then_some((matched != negate, i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::server::Peer;
+    use crate::server::clients::Clients;
+
+    #[test]
+    fn glob_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("", "", true),
+            ("", "a", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("a**", "a", true),
+            ("app:*", "app:", true),
+            ("app:*", "ap", false),
+            ("a*c", "abbbc", true),
+            ("a*c", "abcd", false),
+            ("*:*:end", "a:b:c:end", true),
+            ("a?c", "abc", true),
+            ("?", "", false),
+            ("[abc]x", "bx", true),
+            ("[^abc]x", "bx", false),
+            ("[^abc]x", "dx", true),
+            ("[a-z]1", "q1", true),
+            ("[z-a]1", "q1", true),
+            ("[a-z]1", "Q1", false),
+            ("[a\\]]", "]", true),
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("[abc", "[abc", true),
+            ("[abc", "a", false),
+        ];
+        for &(pattern, text, matches) in cases {
+            assert_eq!(glob_match(pattern.as_bytes(), text.as_bytes()), matches, "{:?} on {:?}", pattern, text);
+        }
+    }
+
+    #[test]
+    fn check_gates_commands_and_keys() {
+        let acl = Acl::load(&Config::default()).unwrap();
+        let rules = ["on", ">secret", "~app:*", "+@read", "+set"].map(String::from);
+        acl.set_user("app", &rules).unwrap();
+        let clients = Arc::new(Clients::default());
+        let client = clients.register(Peer::Unix("/tmp/test.sock".into()));
+        let get = |key: &'static str| ParsedCommand::Get { key: Bytes::from_static(key.as_bytes()) };
+
+        assert_eq!(acl.check(&client, &get("app:1")).as_deref(), Some("NOAUTH Authentication required.\n"));
+        let auth = ParsedCommand::Auth { user: Some("app".into()), password: "secret".into() };
+        assert_eq!(acl.check(&client, &auth), None);
+
+        client.set_user(Some("app"));
+        assert_eq!(acl.check(&client, &get("app:1")), None);
+        assert_eq!(acl.check(&client, &get("other")).as_deref(), Some("NOPERM No permissions to access a key\n"));
+        let del = ParsedCommand::Del { key: Bytes::from_static(b"app:1") };
+        assert!(acl.check(&client, &del).is_some_and(|e| e.starts_with("NOPERM User app")));
+
+        client.set_user(Some(DEFAULT_USER));
+        assert_eq!(acl.check(&client, &del), None);
+        client.set_user(Some("nobody"));
+        assert!(acl.check(&client, &get("app:1")).is_some_and(|e| e.starts_with("NOAUTH")));
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::UnixListener;
+use tokio::sync::mpsc;
+
+use crate::engine::{Command, ParsedCommand, parse_command};
+use crate::shard_engine::router::ShardRouter;
+
+// Lines buffered between the shards' feeds and the consumer's socket.
+const OUT_CAPACITY: usize = 1024;
+
+/// Streams every shard's committed writes to a consumer that sent
+/// `CDC SUBSCRIBE`, until it disconnects or a feed fails. Lines from
+/// different shards interleave; each shard's come in LSN order.
+pub async fn serve<S: AsyncRead + AsyncWrite>(stream: S, router: Arc<ShardRouter>, positions: Vec<Option<u64>>) {
+    let (mut reader, writer) = tokio::io::split(stream);
+    let mut writer = BufWriter::new(writer);
+    let shard_count = router.shard_count();
+    let error = if router.raft().is_some() {
+        Some("ERR CDC is not available in raft mode\n".to_string())
+    } else if positions.len() != 1 && positions.len() != shard_count {
+        Some(format!("ERR CDC SUBSCRIBE takes one position or one per shard ({})\n", shard_count))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        let _ = writer.write_all(error.as_bytes()).await;
+        let _ = writer.flush().await;
+        return;
+    }
+
+    let (out_tx, mut out_rx) = mpsc::channel(OUT_CAPACITY);
+    for shard in 0..shard_count {
+        let from = positions[shard.min(positions.len() - 1)];
+        router.send_to(shard, Command::CdcSubscribe { from, out: out_tx.clone() }).await;
+    }
+    drop(out_tx);
+
+    let mut discard = [0u8; 1024];
+    loop {
+        tokio::select! {
+            line = out_rx.recv() => {
+                let Some(line) = line else {
+                    return;
+                };
+                if writer.write_all(&line).await.is_err() {
+                    return;
+                }
+                let failed = line.starts_with(b"ERR");
+                if (failed || out_rx.is_empty()) && writer.flush().await.is_err() {
+                    return;
+                }
+                if failed {
+                    return;
+                }
+            }
+            // Consumers don't send anything else; this is how we notice
+            // they hung up while nothing is being written.
+            read = reader.read(&mut discard) => {
+                if !matches!(read, Ok(n) if n > 0) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Serves consumers on the Unix socket. They send the same
+/// `CDC SUBSCRIBE` line as over TCP.
+pub async fn run_unix(listener: UnixListener, router: Arc<ShardRouter>) {
+    loop {
+        let Ok((socket, _)) = listener.accept().await else {
+            continue;
+        };
+        let router = router.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(socket);
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.is_err() {
+                return;
+            }
+            match parse_command(&line.into()) {
+                Some(ParsedCommand::CdcSubscribe { positions }) => serve(reader.into_inner(), router, positions).await,
+                _ => {
+                    let _ = reader.get_mut().write_all(b"ERR expected CDC SUBSCRIBE\n").await;
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::time::{self, timeout};
+use tracing::error;
+
+use super::{Cluster, Node};
+use crate::acl;
+
+const GOSSIP_PERIOD: Duration = Duration::from_secs(1);
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Once a second, swaps views with every node we know of and every address
+/// we were asked to meet: `CLUSTER GOSSIP <our-id> <nodes-json>`, answered
+/// with the other side's ID and nodes the same way.
+pub fn spawn(cluster: Arc<Cluster>) {
+    tokio::spawn(async move {
+        let mut ticker = time::interval(GOSSIP_PERIOD);
+        loop {
+            ticker.tick().await;
+            for addr in targets(&cluster) {
+                let cluster = cluster.clone();
+                tokio::spawn(async move {
+                    // A node that's down just shows up as failing.
+                    let _ = timeout(GOSSIP_TIMEOUT, exchange(&cluster, &addr)).await;
+                });
+            }
+        }
+    });
+}
+
+fn targets(cluster: &Cluster) -> Vec<String> {
+    let state = cluster.state.read().unwrap();
+    let known = state.nodes.iter().filter(|(id, _)| **id != state.myid).map(|(_, n)| n.addr.clone());
+    known.chain(state.meets.iter().cloned()).collect()
+}
+
+async fn exchange(cluster: &Cluster, addr: &str) -> io::Result<()> {
+    let socket = acl::connect(addr).await?;
+    let mut reader = BufReader::new(socket);
+    reader.get_mut().write_all(format!("CLUSTER GOSSIP {}", message(cluster)).as_bytes()).await?;
+    let mut reply = String::new();
+    reader.read_line(&mut reply).await?;
+    let (sender, payload) = reply
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| io::Error::other(reply.trim().to_string()))?;
+    merge(cluster, sender, payload).map_err(io::Error::other)
+}
+
+/// Handles `CLUSTER GOSSIP` from another node and answers with our view.
+pub(super) fn receive(cluster: &Cluster, sender: &str, payload: &str) -> String {
+    match merge(cluster, sender, payload) {
+        Ok(()) => message(cluster),
+        Err(e) => format!("ERR {}\n", e),
+    }
+}
+
+/// `<our-id> <nodes-json>`. Neither part has whitespace, so it parses as
+/// two plain tokens.
+fn message(cluster: &Cluster) -> String {
+    let state = cluster.state.read().unwrap();
+    format!("{} {}\n", state.myid, serde_json::to_string(&state.nodes).unwrap())
+}
+
+fn merge(cluster: &Cluster, sender: &str, payload: &str) -> Result<(), String> {
+    let nodes: BTreeMap<String, Node> = serde_json::from_str(payload).map_err(|e| e.to_string())?;
+    if !nodes.contains_key(sender) {
+        return Err("gossip without the sender's own entry".into());
+    }
+    let mut state = cluster.state.write().unwrap();
+    if state.merge(sender, nodes)
+        && let Err(e) = state.save()
+    {
+        error!("can't save the cluster view: {}", e);
+    }
+    Ok(())
+}
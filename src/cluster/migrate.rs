@@ -0,0 +1,117 @@
+use std::io;
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+
+use crate::acl;
+use crate::engine::Command;
+use crate::engine::parser::quote;
+use crate::shard_engine::router::ShardRouter;
+
+/// A key read out for moving: its TTL in ms (0 for none), version and value.
+struct Dumped {
+    key: Bytes,
+    ttl_ms: u64,
+    version: u64,
+    value: Bytes,
+}
+
+/// `MIGRATE`: copies keys to another node with `ASKING` + `RESTORE`, then
+/// deletes them here unless `copy` is set. A key is only deleted if it's
+/// still the version that was sent; one written in the meantime stays, and
+/// a later `MIGRATE ... REPLACE` moves it.
+pub async fn migrate(
+    router: &ShardRouter,
+    addr: String,
+    keys: Vec<Bytes>,
+    copy: bool,
+    replace: bool,
+    timeout_ms: u64,
+) -> String {
+    let mut dumped = Vec::new();
+    for key in keys {
+        if let Some(d) = dump(router, key).await {
+            dumped.push(d);
+        }
+    }
+    if dumped.is_empty() {
+        return "NOKEY\n".into();
+    }
+    // RESTORE sends the value on one line, so a newline would split it and
+    // pair the target's replies with the wrong keys.
+    if let Some(d) = dumped.iter().find(|d| d.value.iter().any(|&b| b == b'\n' || b == b'\r')) {
+        return format!("ERR can't migrate {}: its value holds CR or LF\n", String::from_utf8_lossy(&d.key));
+    }
+
+    let replies = match timeout(Duration::from_millis(timeout_ms.max(1)), restore(&addr, &dumped, replace)).await {
+        Ok(Ok(replies)) => replies,
+        Ok(Err(e)) => return format!("IOERR error accessing {}: {}\n", addr, e),
+        Err(_) => return format!("IOERR timeout talking to {}\n", addr),
+    };
+
+    let mut error = None;
+    for (d, reply) in dumped.into_iter().zip(replies) {
+        if reply != "OK" {
+            error.get_or_insert(reply);
+            continue;
+        }
+        if !copy {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            router.route(Command::DelIfVer { key: d.key, version: d.version, resp: resp_tx.into() }).await;
+            let _ = resp_rx.await;
+        }
+    }
+    match error {
+        Some(reply) => format!("{}\n", reply),
+        None => "OK\n".into(),
+    }
+}
+
+async fn dump(router: &ShardRouter, key: Bytes) -> Option<Dumped> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    router.route(Command::Dump { key: key.clone(), resp: resp_tx.into() }).await;
+    let reply = resp_rx.await.ok()?;
+    let mut parts = reply.splitn(3, |&b| b == b' ');
+    let number = |part: &[u8]| std::str::from_utf8(part).ok()?.parse().ok();
+    let ttl_ms = number(parts.next()?)?;
+    let version = number(parts.next()?)?;
+    let value = reply.slice_ref(parts.next()?);
+    Some(Dumped { key, ttl_ms, version, value })
+}
+
+/// Sends every key in one go and returns the target's reply to each. Fails
+/// if the target refuses an `ASKING`, since its `RESTORE` didn't run as sent.
+async fn restore(addr: &str, dumped: &[Dumped], replace: bool) -> io::Result<Vec<String>> {
+    let socket = acl::connect(addr).await?;
+    let mut reader = BufReader::new(socket);
+    let mut request = Vec::new();
+    for d in dumped {
+        request.extend_from_slice(b"ASKING\nRESTORE ");
+        request.extend_from_slice(&quote(&d.key));
+        request.extend_from_slice(format!(" {} ", d.ttl_ms).as_bytes());
+        request.extend_from_slice(&quote(&d.value));
+        request.extend_from_slice(if replace { b" REPLACE\n" } else { b"\n" });
+    }
+    reader.get_mut().write_all(&request).await?;
+
+    let mut replies = Vec::with_capacity(dumped.len());
+    let mut line = String::new();
+    for _ in dumped {
+        for asking in [true, false] {
+            line.clear();
+            if reader.read_line(&mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let reply = line.trim();
+            if !asking {
+                replies.push(reply.to_string());
+            } else if reply != "OK" {
+                return Err(io::Error::other(format!("ASKING refused: {}", reply)));
+            }
+        }
+    }
+    Ok(replies)
+}
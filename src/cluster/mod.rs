@@ -0,0 +1,539 @@
+pub mod gossip;
+pub mod migrate;
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write as _;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use std::{fs, io};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+
+use crate::engine::Command;
+use crate::engine::ParsedCommand;
+use crate::engine::command::{ClusterCommand, SlotState};
+use crate::shard_engine::router::ShardRouter;
+
+pub const SLOT_COUNT: u16 = 16384;
+/// Where a node keeps its ID and its view of the cluster.
+const NODES_FILE: &str = "nodes.conf";
+// A node we haven't exchanged gossip with for this long shows as failing.
+const NODE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The part of `key` that picks its slot: the first non-empty `{...}`, or
+/// the whole key if there is none.
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(start) = key.iter().position(|&b| b == b'{')
+        && let Some(len) = key[start + 1..].iter().position(|&b| b == b'}')
+        && len > 0
+    {
+        return &key[start + 1..start + 1 + len];
+    }
+    key
+}
+
+/// The cluster slot of a key, as Redis Cluster computes it.
+pub fn key_slot(key: &[u8]) -> u16 {
+    crc16::State::<crc16::XMODEM>::calculate(hash_tag(key)) % SLOT_COUNT
+}
+
+/// This node's view of which node serves each of the 16384 slots.
+///
+/// Every node is the authority on the slots it claims, and its claims carry
+/// its config epoch. Claims spread by gossip, and where two nodes claim the
+/// same slot the higher epoch wins; a node that sees it lost a slot drops it
+/// from its own claims. Taking over a slot bumps the taker's epoch above any
+/// seen so far.
+pub struct Cluster {
+    myid: String,
+    state: RwLock<State>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct State {
+    myid: String,
+    nodes: BTreeMap<String, Node>,
+    /// Slots we serve but are moving out, to the node ID.
+    migrating: BTreeMap<u16, String>,
+    /// Slots we're taking in, from the node ID.
+    importing: BTreeMap<u16, String>,
+    /// `CLUSTER MEET` addresses we haven't heard back from yet.
+    #[serde(skip)]
+    meets: HashSet<String>,
+    #[serde(skip)]
+    owners: Vec<Option<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Node {
+    addr: String,
+    epoch: u64,
+    /// Claimed slots as inclusive ranges.
+    slots: Vec<(u16, u16)>,
+    #[serde(skip)]
+    last_seen: Option<Instant>,
+}
+
+/// Where a command for some slot has to go.
+pub enum Route {
+    Local,
+    /// Ours, but keys that have already moved are at this address.
+    Migrating(String),
+    Moved(String),
+    Unassigned,
+}
+
+impl Cluster {
+    /// Picks up the view saved in `nodes.conf`, or starts out as a new node
+    /// with no slots.
+    pub fn load(addr: String) -> io::Result<Self> {
+        let mut state = match fs::read_to_string(NODES_FILE) {
+            Ok(contents) => serde_json::from_str::<State>(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", NODES_FILE, e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let myid: String = (0..40).map(|_| char::from_digit(fastrand::u32(..16), 16).unwrap()).collect();
+                State {
+                    nodes: BTreeMap::new(),
+                    migrating: BTreeMap::new(),
+                    importing: BTreeMap::new(),
+                    meets: HashSet::new(),
+                    owners: Vec::new(),
+                    myid,
+                }
+            }
+            Err(e) => return Err(e),
+        };
+        let myid = state.myid.clone();
+        let me = state.nodes.entry(myid.clone()).or_insert_with(|| Node {
+            addr: addr.clone(),
+            epoch: 0,
+            slots: Vec::new(),
+            last_seen: None,
+        });
+        me.addr = addr;
+        state.rebuild();
+        state.save()?;
+        Ok(Self { myid, state: RwLock::new(state) })
+    }
+
+    pub fn route(&self, slot: u16, asking: bool) -> Route {
+        let state = self.state.read().unwrap();
+        let slot_idx = slot as usize;
+        match &state.owners[slot_idx] {
+            Some(owner) if *owner == self.myid => match state.migrating.get(&slot) {
+                Some(target) => match state.nodes.get(target) {
+                    Some(node) => Route::Migrating(node.addr.clone()),
+                    None => Route::Local,
+                },
+                None => Route::Local,
+            },
+            _ if asking && state.importing.contains_key(&slot) => Route::Local,
+            Some(owner) => Route::Moved(state.nodes[owner].addr.clone()),
+            None => Route::Unassigned,
+        }
+    }
+
+    /// Takes over unassigned slots.
+    fn add_slots(&self, slots: &[u16]) -> String {
+        let mut state = self.state.write().unwrap();
+        if let Some(slot) = slots.iter().find(|&&s| state.owners[s as usize].is_some()) {
+            return format!("ERR Slot {} is already busy\n", slot);
+        }
+        state.claim(slots);
+        save_reply(&state)
+    }
+
+    fn set_slot(&self, slot: u16, slot_state: SlotState) -> String {
+        let mut state = self.state.write().unwrap();
+        let owner = state.owners[slot as usize].clone();
+        let mine = owner.as_deref() == Some(self.myid.as_str());
+        match slot_state {
+            SlotState::Importing(node) => {
+                if mine {
+                    return format!("ERR I'm already the owner of hash slot {}\n", slot);
+                }
+                if !state.nodes.contains_key(&node) {
+                    return format!("ERR I don't know about node {}\n", node);
+                }
+                state.importing.insert(slot, node);
+            }
+            SlotState::Migrating(node) => {
+                if !mine {
+                    return format!("ERR I'm not the owner of hash slot {}\n", slot);
+                }
+                if !state.nodes.contains_key(&node) {
+                    return format!("ERR I don't know about node {}\n", node);
+                }
+                state.migrating.insert(slot, node);
+            }
+            SlotState::Stable => {
+                state.migrating.remove(&slot);
+                state.importing.remove(&slot);
+            }
+            SlotState::Node(node) if node == self.myid => {
+                state.importing.remove(&slot);
+                state.migrating.remove(&slot);
+                if !mine {
+                    state.claim(&[slot]);
+                }
+            }
+            SlotState::Node(node) => {
+                if !state.nodes.contains_key(&node) {
+                    return format!("ERR I don't know about node {}\n", node);
+                }
+                state.importing.remove(&slot);
+                state.migrating.remove(&slot);
+                // Hand the slot over in our view right away rather than wait
+                // for the new owner's claim to reach us.
+                let myid = self.myid.clone();
+                state.update_slots(&myid, |slots| {
+                    slots.remove(&slot);
+                });
+                state.update_slots(&node, |slots| {
+                    slots.insert(slot);
+                });
+                state.rebuild();
+            }
+        }
+        save_reply(&state)
+    }
+}
+
+impl State {
+    fn me(&self) -> &Node {
+        &self.nodes[&self.myid]
+    }
+
+    fn current_epoch(&self) -> u64 {
+        self.nodes.values().map(|n| n.epoch).max().unwrap_or(0)
+    }
+
+    /// Adds `slots` to our claims under a new epoch, so they win over any
+    /// older claims to them.
+    fn claim(&mut self, slots: &[u16]) {
+        let epoch = self.current_epoch() + 1;
+        let myid = self.myid.clone();
+        self.update_slots(&myid, |claimed| claimed.extend(slots));
+        self.nodes.get_mut(&myid).unwrap().epoch = epoch;
+        self.rebuild();
+    }
+
+    fn update_slots(&mut self, id: &str, update: impl FnOnce(&mut BTreeSet<u16>)) {
+        let Some(node) = self.nodes.get_mut(id) else {
+            return;
+        };
+        let mut slots = expand(&node.slots).collect();
+        update(&mut slots);
+        node.slots = ranges(slots.into_iter());
+    }
+
+    /// Works out every slot's owner from the nodes' claims, dropping ours
+    /// that someone else won.
+    fn rebuild(&mut self) {
+        let mut owners: Vec<Option<(u64, &str)>> = vec![None; SLOT_COUNT as usize];
+        for (id, node) in &self.nodes {
+            for slot in expand(&node.slots) {
+                // Equal epochs shouldn't happen; if they do, the lower ID wins.
+                let claim = &mut owners[slot as usize];
+                let wins = match *claim {
+                    None => true,
+                    Some((epoch, owner)) => node.epoch > epoch || (node.epoch == epoch && id.as_str() < owner),
+                };
+                if wins {
+                    *claim = Some((node.epoch, id));
+                }
+            }
+        }
+        let owners: Vec<Option<String>> = owners.into_iter().map(|o| o.map(|(_, id)| id.to_string())).collect();
+        let lost: Vec<u16> = expand(&self.me().slots)
+            .filter(|&slot| owners[slot as usize].as_deref() != Some(self.myid.as_str()))
+            .collect();
+        if !lost.is_empty() {
+            let myid = self.myid.clone();
+            self.update_slots(&myid, |slots| {
+                for slot in &lost {
+                    slots.remove(slot);
+                }
+            });
+            for slot in lost {
+                self.migrating.remove(&slot);
+            }
+        }
+        self.owners = owners;
+    }
+
+    /// Takes in another node's view. What `sender` says about itself always
+    /// counts; what it says about others only if it's newer than ours.
+    /// Returns whether anything changed.
+    fn merge(&mut self, sender: &str, nodes: BTreeMap<String, Node>) -> bool {
+        let mut changed = false;
+        for (id, mut node) in nodes {
+            if id == self.myid {
+                continue;
+            }
+            self.meets.remove(&node.addr);
+            let known = self.nodes.get(&id);
+            let newer = match known {
+                None => true,
+                Some(known) if id == sender => {
+                    known.epoch != node.epoch || known.slots != node.slots || known.addr != node.addr
+                }
+                Some(known) => node.epoch > known.epoch,
+            };
+            node.last_seen = known.and_then(|k| k.last_seen);
+            if id == sender {
+                node.last_seen = Some(Instant::now());
+            }
+            if newer {
+                changed = true;
+            }
+            if newer || id == sender {
+                self.nodes.insert(id, node);
+            }
+        }
+        if changed {
+            self.rebuild();
+        }
+        changed
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let tmp = format!("{}.tmp", NODES_FILE);
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(tmp, NODES_FILE)
+    }
+
+    fn is_online(&self, id: &str) -> bool {
+        id == self.myid || self.nodes[id].last_seen.is_some_and(|seen| seen.elapsed() < NODE_TIMEOUT)
+    }
+}
+
+fn save_reply(state: &State) -> String {
+    match state.save() {
+        Ok(()) => "OK\n".into(),
+        Err(e) => format!("ERR can't save {}: {}\n", NODES_FILE, e),
+    }
+}
+
+fn expand(ranges: &[(u16, u16)]) -> impl Iterator<Item = u16> + '_ {
+    ranges.iter().flat_map(|&(start, end)| start..=end)
+}
+
+/// Folds ascending slots into inclusive ranges.
+fn ranges(slots: impl Iterator<Item = u16>) -> Vec<(u16, u16)> {
+    let mut ranges: Vec<(u16, u16)> = Vec::new();
+    for slot in slots {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == slot => *end = slot,
+            _ => ranges.push((slot, slot)),
+        }
+    }
+    ranges
+}
+
+fn format_range(&(start, end): &(u16, u16)) -> String {
+    if start == end { start.to_string() } else { format!("{}-{}", start, end) }
+}
+
+/// Checks that the command's keys are served here. Returns the `MOVED`,
+/// `ASK` or error reply to send instead if they aren't. `flush` sends the
+/// client's held back commands, which have to reach our shards before we
+/// look keys up there.
+pub async fn redirect(
+    router: &ShardRouter,
+    parsed: &ParsedCommand,
+    asking: bool,
+    flush: impl Future<Output = ()>,
+) -> Option<String> {
+    let cluster = router.cluster()?;
+    let keys = parsed.keys();
+    let slot = key_slot(keys.first()?);
+    if keys.iter().any(|k| key_slot(k) != slot) {
+        return Some("CROSSSLOT Keys in request don't hash to the same slot\n".into());
+    }
+    match cluster.route(slot, asking) {
+        Route::Local => None,
+        Route::Moved(addr) => Some(format!("MOVED {} {}\n", slot, addr)),
+        Route::Unassigned => Some("CLUSTERDOWN Hash slot not served\n".into()),
+        // Keys still here are served here; the rest, new ones included, are
+        // the target's business.
+        Route::Migrating(addr) => {
+            flush.await;
+            for key in keys {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                router.route(Command::Ex { key: Bytes::copy_from_slice(key), resp: resp_tx.into() }).await;
+                if resp_rx.await.ok()? != "1" {
+                    return Some(format!("ASK {} {}\n", slot, addr));
+                }
+            }
+            None
+        }
+    }
+}
+
+/// Runs a `CLUSTER` subcommand.
+pub async fn execute(router: &ShardRouter, cmd: ClusterCommand) -> String {
+    let Some(cluster) = router.cluster() else {
+        return "ERR This instance has cluster support disabled\n".into();
+    };
+    match cmd {
+        ClusterCommand::Info => info(cluster),
+        ClusterCommand::MyId => format!("{}\n", cluster.myid),
+        ClusterCommand::Nodes => nodes(cluster),
+        ClusterCommand::Slots => slots(cluster),
+        ClusterCommand::Shards => shards(cluster),
+        ClusterCommand::KeySlot { key } => format!("{}\n", key_slot(key.as_bytes())),
+        ClusterCommand::Meet { addr } => {
+            cluster.state.write().unwrap().meets.insert(addr);
+            "OK\n".into()
+        }
+        ClusterCommand::AddSlots { slots } => cluster.add_slots(&slots),
+        ClusterCommand::SetSlot { slot, state } => cluster.set_slot(slot, state),
+        ClusterCommand::GetKeysInSlot { slot, count } => {
+            // Keys come one per line, and a blank line ends the list.
+            let mut reply = String::new();
+            let replies = router.broadcast(|resp| Command::KeysInSlot { slot, count, resp }).await;
+            for key in replies.iter().flat_map(|r| r.lines()).take(count) {
+                reply.push_str(key);
+                reply.push('\n');
+            }
+            reply.push('\n');
+            reply
+        }
+        ClusterCommand::CountKeysInSlot { slot } => {
+            let replies = router.broadcast(|resp| Command::CountKeysInSlot { slot, resp }).await;
+            let count: usize = replies.iter().filter_map(|r| r.parse::<usize>().ok()).sum();
+            format!("{}\n", count)
+        }
+        ClusterCommand::Gossip { sender, payload } => gossip::receive(cluster, &sender, &payload),
+    }
+}
+
+/// `CLUSTER INFO`, ending with a blank line.
+fn info(cluster: &Cluster) -> String {
+    let state = cluster.state.read().unwrap();
+    let assigned = state.owners.iter().filter(|o| o.is_some()).count();
+    let size = state.nodes.values().filter(|n| !n.slots.is_empty()).count();
+    let mut info = String::new();
+    let _ = writeln!(info, "cluster_enabled:1");
+    let _ = writeln!(info, "cluster_state:{}", if assigned == SLOT_COUNT as usize { "ok" } else { "fail" });
+    let _ = writeln!(info, "cluster_slots_assigned:{}", assigned);
+    let _ = writeln!(info, "cluster_known_nodes:{}", state.nodes.len());
+    let _ = writeln!(info, "cluster_size:{}", size);
+    let _ = writeln!(info, "cluster_current_epoch:{}", state.current_epoch());
+    let _ = writeln!(info, "cluster_my_epoch:{}", state.me().epoch);
+    info.push('\n');
+    info
+}
+
+/// `CLUSTER NODES` in Redis' format, one node per line and a blank line at
+/// the end. There is no separate cluster bus, so its port is the client one.
+fn nodes(cluster: &Cluster) -> String {
+    let state = cluster.state.read().unwrap();
+    let mut reply = String::new();
+    for (id, node) in &state.nodes {
+        let myself = *id == state.myid;
+        let port = node.addr.rsplit_once(':').map_or("", |(_, port)| port);
+        let flags = match (myself, state.is_online(id)) {
+            (true, _) => "myself,master",
+            (false, true) => "master",
+            (false, false) => "master,fail?",
+        };
+        let link = if state.is_online(id) { "connected" } else { "disconnected" };
+        let _ = write!(reply, "{} {}@{} {} - 0 0 {} {}", id, node.addr, port, flags, node.epoch, link);
+        for range in &node.slots {
+            let _ = write!(reply, " {}", format_range(range));
+        }
+        if myself {
+            for (slot, to) in &state.migrating {
+                let _ = write!(reply, " [{}->-{}]", slot, to);
+            }
+            for (slot, from) in &state.importing {
+                let _ = write!(reply, " [{}-<-{}]", slot, from);
+            }
+        }
+        reply.push('\n');
+    }
+    reply.push('\n');
+    reply
+}
+
+/// `CLUSTER SLOTS`: `<start> <end> <host> <port> <node-id>` per range of
+/// slots, and a blank line at the end.
+fn slots(cluster: &Cluster) -> String {
+    let state = cluster.state.read().unwrap();
+    let mut claims: Vec<(u16, u16, &str)> = Vec::new();
+    for (slot, owner) in state.owners.iter().enumerate() {
+        let Some(owner) = owner else {
+            continue;
+        };
+        match claims.last_mut() {
+            Some((_, end, id)) if *id == owner && *end as usize + 1 == slot => *end = slot as u16,
+            _ => claims.push((slot as u16, slot as u16, owner)),
+        }
+    }
+    let mut reply = String::new();
+    for (start, end, id) in claims {
+        let addr = &state.nodes[id].addr;
+        let (host, port) = addr.rsplit_once(':').unwrap_or((addr, ""));
+        let _ = writeln!(reply, "{} {} {} {} {}", start, end, host, port, id);
+    }
+    reply.push('\n');
+    reply
+}
+
+/// `CLUSTER SHARDS`: one line per node that serves slots, and a blank line
+/// at the end. Every shard is a single primary.
+fn shards(cluster: &Cluster) -> String {
+    let state = cluster.state.read().unwrap();
+    let mut reply = String::new();
+    for (id, node) in state.nodes.iter().filter(|(_, n)| !n.slots.is_empty()) {
+        let (host, port) = node.addr.rsplit_once(':').unwrap_or((&node.addr, ""));
+        let slots: Vec<String> = node.slots.iter().map(format_range).collect();
+        let _ = writeln!(
+            reply,
+            "slots={} id={} endpoint={} port={} role=master health={}",
+            slots.join(","),
+            id,
+            host,
+            port,
+            if state.is_online(id) { "online" } else { "fail" }
+        );
+    }
+    reply.push('\n');
+    reply
+}
+
+/// The cluster section of `INFO`.
+pub fn info_section(router: &ShardRouter) -> String {
+    format!("# Cluster\ncluster_enabled:{}\n", router.cluster().is_some() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_tag_is_the_first_non_empty_braces() {
+        assert_eq!(hash_tag(b"{user:42}:profile"), b"user:42");
+        assert_eq!(hash_tag(b"a{b}c{d}"), b"b");
+        assert_eq!(hash_tag(b"{}key"), b"{}key");
+        assert_eq!(hash_tag(b"{}{x}"), b"{}{x}");
+        assert_eq!(hash_tag(b"{open"), b"{open");
+        assert_eq!(hash_tag(b"close}{"), b"close}{");
+        assert_eq!(hash_tag(b"plain"), b"plain");
+    }
+
+    #[test]
+    fn key_slot_matches_redis_cluster() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"bar"), 5061);
+        assert_eq!(key_slot(b""), 0);
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"{user1000}.followers"));
+        assert_eq!(key_slot(b"{foo}bar"), key_slot(b"foo"));
+        assert!(key_slot(&[0xff; 64]) < SLOT_COUNT);
+    }
+}
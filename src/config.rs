@@ -0,0 +1,520 @@
+use std::time::Duration;
+use std::{env, fs};
+
+use tracing_subscriber::EnvFilter;
+
+/// What a shard does when a write would take it past its share of `maxmemory`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvictionPolicy {
+    NoEviction,
+    AllKeysLru,
+    AllKeysLfu,
+    AllKeysRandom,
+    VolatileLru,
+    VolatileLfu,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_lowercase().as_str() {
+            "noeviction" => Self::NoEviction,
+            "allkeys-lru" => Self::AllKeysLru,
+            "allkeys-lfu" => Self::AllKeysLfu,
+            "allkeys-random" => Self::AllKeysRandom,
+            "volatile-lru" => Self::VolatileLru,
+            "volatile-lfu" => Self::VolatileLfu,
+            "volatile-random" => Self::VolatileRandom,
+            "volatile-ttl" => Self::VolatileTtl,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NoEviction => "noeviction",
+            Self::AllKeysLru => "allkeys-lru",
+            Self::AllKeysLfu => "allkeys-lfu",
+            Self::AllKeysRandom => "allkeys-random",
+            Self::VolatileLru => "volatile-lru",
+            Self::VolatileLfu => "volatile-lfu",
+            Self::VolatileRandom => "volatile-random",
+            Self::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    /// Volatile policies only ever pick keys that have a TTL.
+    pub fn volatile_only(&self) -> bool {
+        matches!(
+            self,
+            Self::VolatileLru | Self::VolatileLfu | Self::VolatileRandom | Self::VolatileTtl
+        )
+    }
+}
+
+/// Whether TLS clients must present a certificate signed by `tls-ca-cert-file`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TlsAuthClients {
+    No,
+    Yes,
+    /// Checked if presented, but not required.
+    Optional,
+}
+
+/// The memory limit as seen by a single shard.
+#[derive(Clone, Copy)]
+pub struct EvictionConfig {
+    /// Bytes this shard may use, 0 for no limit.
+    pub maxmemory: usize,
+    pub policy: EvictionPolicy,
+    pub samples: usize,
+}
+
+/// When a shard takes a snapshot on its own.
+#[derive(Clone, Copy)]
+pub struct SnapshotConfig {
+    /// Snapshot this often if anything was written since the last one.
+    pub interval: Duration,
+    /// Snapshot as soon as this many writes piled up, 0 to disable.
+    pub writes: u64,
+}
+
+/// How much a client connection may get away with.
+#[derive(Clone, Copy)]
+pub struct ClientLimits {
+    /// Longest request line, in bytes.
+    pub max_request: usize,
+    /// Close connections that sent nothing for this long.
+    pub idle_timeout: Option<Duration>,
+    pub output: OutputBufferLimit,
+    /// Commands a client may have dispatched but not yet answered.
+    pub max_inflight: usize,
+}
+
+/// `client-output-buffer-limit hard soft soft-seconds`, as in Redis: a client
+/// is closed once replies waiting for it pass `hard` bytes, or stay above
+/// `soft` for `soft_seconds`. 0 turns either off.
+#[derive(Clone, Copy)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+/// Settings handed to each shard engine.
+#[derive(Clone, Copy)]
+pub struct ShardConfig {
+    pub eviction: EvictionConfig,
+    pub snapshot: SnapshotConfig,
+    /// Bytes of recent WAL records kept for followers to resume from.
+    pub repl_backlog: usize,
+    pub lua_time_limit: u64,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    /// The config file given on the command line, if any.
+    pub config_file: Option<String>,
+    /// Plaintext port, 0 for none.
+    pub port: u16,
+    /// Listening sockets per TCP port, each with its own accept loop. More
+    /// than one are bound with `SO_REUSEPORT`, and the kernel spreads new
+    /// connections across them.
+    pub acceptors: usize,
+    /// TLS port, off if unset.
+    pub tls_port: Option<u16>,
+    /// PEM certificate chain and private key served on `tls_port`.
+    pub tls_cert_file: Option<String>,
+    pub tls_key_file: Option<String>,
+    /// PEM CA certificates that sign client certificates.
+    pub tls_ca_cert_file: Option<String>,
+    pub tls_auth_clients: TlsAuthClients,
+    /// Log a client with a certificate in as the ACL user its subject CN
+    /// names.
+    pub tls_auth_clients_user: bool,
+    /// Unix socket path serving clients, next to the TCP ports or instead
+    /// of them.
+    pub unixsocket: Option<String>,
+    /// Mode bits for `unixsocket`, such as `0o770`; the umask's if unset.
+    pub unixsocketperm: Option<u32>,
+    /// Primary to follow at startup, as `(host, port)`.
+    pub replicaof: Option<(String, u16)>,
+    /// Bytes across all shards, 0 for no limit.
+    pub maxmemory: usize,
+    pub maxmemory_policy: EvictionPolicy,
+    pub maxmemory_samples: usize,
+    /// Seconds between snapshots of a shard that has pending writes.
+    pub snapshot_interval: u64,
+    /// Writes to a shard that trigger a snapshot early, 0 to disable.
+    pub snapshot_writes: u64,
+    /// Replication backlog across all shards.
+    pub repl_backlog_size: usize,
+    /// Milliseconds a script may run before it is aborted, 0 for no limit.
+    pub lua_time_limit: u64,
+    /// `host:port` of every initial member of the Raft groups, this node
+    /// included. Empty unless running in Raft mode.
+    pub raft_peers: Vec<String>,
+    /// How this node appears in `raft_peers`; `127.0.0.1:<port>` if unset.
+    pub raft_address: Option<String>,
+    /// Serve a share of the cluster's hash slots, redirecting the rest.
+    pub cluster_enabled: bool,
+    /// How other nodes and clients reach us; `127.0.0.1:<port>` if unset.
+    pub cluster_announce: Option<String>,
+    /// Unix socket path serving CDC consumers, besides `CDC SUBSCRIBE`.
+    pub cdc_socket: Option<String>,
+    /// Port serving Prometheus metrics on `/metrics`, off if unset.
+    pub metrics_port: Option<u16>,
+    /// CPU cores running the shards, shard `i` on entry `i % len`. Each core
+    /// gets a thread of its own. Unset leaves shards on the I/O workers.
+    pub shard_cores: Option<Vec<usize>>,
+    /// CPU cores for the I/O workers, one worker pinned to each. Unset runs
+    /// the default number of workers wherever the OS likes.
+    pub io_cores: Option<Vec<usize>>,
+    /// Commands taking at least this many microseconds go to the slowlog;
+    /// negative turns it off.
+    pub slowlog_log_slower_than: i64,
+    /// Slowlog entries kept.
+    pub slowlog_max_len: usize,
+    /// Which logs to print, as `tracing` filter directives (`info`,
+    /// `rustkv=debug`, ...). `RUST_LOG` wins if set.
+    pub log_level: String,
+    /// One JSON object per line instead of plain text.
+    pub log_json: bool,
+    /// Connections served at once; more are turned away.
+    pub maxclients: usize,
+    /// Longest request line in bytes; longer ones close the connection.
+    pub max_request_size: usize,
+    /// Seconds a client may stay silent before it's closed, 0 for ever.
+    pub timeout: u64,
+    /// Seconds between TCP keepalive probes on client connections, 0 to
+    /// leave them off.
+    pub tcp_keepalive: u64,
+    pub client_output_buffer_limit: OutputBufferLimit,
+    /// Commands one client may have in flight at once. A pipeline runs
+    /// that many ahead of its oldest unanswered command.
+    pub pipeline_depth: usize,
+    /// File holding the ACL users, read at startup and by `ACL LOAD`.
+    pub aclfile: Option<String>,
+    /// Password for the `default` user when there's no `aclfile`.
+    pub requirepass: Option<String>,
+    /// ACL failures kept for `ACL LOG`.
+    pub acllog_max_len: usize,
+    /// User this node logs in as on other nodes.
+    pub masteruser: String,
+    /// Password for `masteruser`; links log in only if it's set.
+    pub masterauth: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            config_file: None,
+            port: 3000,
+            acceptors: 1,
+            tls_port: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuthClients::No,
+            tls_auth_clients_user: false,
+            unixsocket: None,
+            unixsocketperm: None,
+            replicaof: None,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            maxmemory_samples: 5,
+            snapshot_interval: 10,
+            snapshot_writes: 100_000,
+            repl_backlog_size: 16 * 1024 * 1024,
+            lua_time_limit: 5_000,
+            raft_peers: Vec::new(),
+            raft_address: None,
+            cluster_enabled: false,
+            cluster_announce: None,
+            cdc_socket: None,
+            metrics_port: None,
+            shard_cores: None,
+            io_cores: None,
+            slowlog_log_slower_than: 10_000,
+            slowlog_max_len: 128,
+            log_level: "info".into(),
+            log_json: false,
+            maxclients: 10_000,
+            max_request_size: 64 * 1024 * 1024,
+            timeout: 0,
+            tcp_keepalive: 300,
+            client_output_buffer_limit: OutputBufferLimit { hard: 0, soft: 0, soft_seconds: 0 },
+            pipeline_depth: 256,
+            aclfile: None,
+            requirepass: None,
+            acllog_max_len: 128,
+            masteruser: "default".into(),
+            masterauth: None,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the config from `rustkv [config-file] [--name value ...]`.
+    /// The file holds one `name value` pair per line, `#` starts a comment,
+    /// and command line options win over the file.
+    pub fn from_args() -> Result<Self, String> {
+        let mut config = Config::default();
+        let mut args = env::args().skip(1).peekable();
+
+        if let Some(path) = args.next_if(|a| !a.starts_with("--")) {
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| format!("can't read config file {}: {}", path, e))?;
+            for line in contents.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                config.set(name, value.trim())?;
+            }
+            config.config_file = Some(path);
+        }
+
+        while let Some(arg) = args.next() {
+            let name = arg
+                .strip_prefix("--")
+                .ok_or_else(|| format!("unexpected argument '{}'", arg))?;
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value for --{}", name))?;
+            config.set(name, &value)?;
+        }
+
+        if config.replicaof.is_some() && !config.raft_peers.is_empty() {
+            return Err("replicaof can't be combined with raft-peers".into());
+        }
+        if config.cluster_enabled && (config.replicaof.is_some() || !config.raft_peers.is_empty()) {
+            return Err("cluster-enabled can't be combined with replicaof or raft-peers".into());
+        }
+        if config.tls_port.is_some() && (config.tls_cert_file.is_none() || config.tls_key_file.is_none()) {
+            return Err("tls-port needs tls-cert-file and tls-key-file".into());
+        }
+        if config.tls_auth_clients != TlsAuthClients::No && config.tls_ca_cert_file.is_none() {
+            return Err("tls-auth-clients needs tls-ca-cert-file".into());
+        }
+        if config.port == 0 && config.tls_port.is_none() && config.unixsocket.is_none() {
+            return Err("port 0 turns off the plaintext port, so tls-port or unixsocket is needed".into());
+        }
+        // Links between nodes don't speak TLS.
+        if config.port == 0 && (config.cluster_enabled || !config.raft_peers.is_empty()) {
+            return Err("cluster and raft mode need the plaintext port".into());
+        }
+        if let (Some(shard_cores), Some(io_cores)) = (&config.shard_cores, &config.io_cores)
+            && let Some(core) = shard_cores.iter().find(|core| io_cores.contains(core))
+        {
+            return Err(format!("core {} is in both shard-cores and io-cores", core));
+        }
+        if config.aclfile.is_some() && config.requirepass.is_some() {
+            return Err("requirepass can't be combined with aclfile; set the default user's password there".into());
+        }
+        Ok(config)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value '{}' for {}", value, name);
+        match name.to_ascii_lowercase().as_str() {
+            "port" => self.port = value.parse().map_err(|_| invalid())?,
+            "acceptors" => self.acceptors = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?,
+            "tls-port" => self.tls_port = Some(value.parse().map_err(|_| invalid())?),
+            "tls-cert-file" => self.tls_cert_file = Some(value.to_string()),
+            "tls-key-file" => self.tls_key_file = Some(value.to_string()),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = Some(value.to_string()),
+            "tls-auth-clients" => {
+                self.tls_auth_clients = match value.to_ascii_lowercase().as_str() {
+                    "no" => TlsAuthClients::No,
+                    "yes" => TlsAuthClients::Yes,
+                    "optional" => TlsAuthClients::Optional,
+                    _ => return Err(invalid()),
+                }
+            }
+            "tls-auth-clients-user" => {
+                self.tls_auth_clients_user = match value.to_ascii_lowercase().as_str() {
+                    "cn" => true,
+                    "off" => false,
+                    _ => return Err(invalid()),
+                }
+            }
+            "replicaof" => self.replicaof = parse_replicaof(value).ok_or_else(invalid)?,
+            "maxmemory" => self.maxmemory = parse_memory(value).ok_or_else(invalid)?,
+            "maxmemory-policy" => {
+                self.maxmemory_policy = EvictionPolicy::parse(value).ok_or_else(invalid)?
+            }
+            "maxmemory-samples" => {
+                self.maxmemory_samples = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?
+            }
+            "snapshot-interval" => {
+                self.snapshot_interval = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?
+            }
+            "snapshot-writes" => self.snapshot_writes = value.parse().map_err(|_| invalid())?,
+            "repl-backlog-size" => {
+                self.repl_backlog_size = parse_memory(value).filter(|&n| n > 0).ok_or_else(invalid)?
+            }
+            "lua-time-limit" => self.lua_time_limit = value.parse().map_err(|_| invalid())?,
+            "raft-peers" => {
+                self.raft_peers = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|peer| !peer.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            "raft-address" => self.raft_address = Some(value.to_string()),
+            "cluster-enabled" => {
+                self.cluster_enabled = match value.to_ascii_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err(invalid()),
+                }
+            }
+            "cluster-announce" => self.cluster_announce = Some(value.to_string()),
+            "cdc-socket" => self.cdc_socket = Some(value.to_string()),
+            "unixsocket" => self.unixsocket = Some(value.to_string()),
+            "unixsocketperm" => self.unixsocketperm = Some(u32::from_str_radix(value, 8).map_err(|_| invalid())?),
+            "slowlog-log-slower-than" => {
+                self.slowlog_log_slower_than = value.parse().map_err(|_| invalid())?
+            }
+            "slowlog-max-len" => self.slowlog_max_len = value.parse().map_err(|_| invalid())?,
+            "log-level" => {
+                EnvFilter::try_new(value).map_err(|_| invalid())?;
+                self.log_level = value.to_string();
+            }
+            "log-format" => {
+                self.log_json = match value.to_ascii_lowercase().as_str() {
+                    "json" => true,
+                    "text" => false,
+                    _ => return Err(invalid()),
+                }
+            }
+            "maxclients" => {
+                self.maxclients = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?
+            }
+            "max-request-size" => {
+                self.max_request_size = parse_memory(value).filter(|&n| n > 0).ok_or_else(invalid)?
+            }
+            "timeout" => self.timeout = value.parse().map_err(|_| invalid())?,
+            "tcp-keepalive" => self.tcp_keepalive = value.parse().map_err(|_| invalid())?,
+            "client-output-buffer-limit" => {
+                self.client_output_buffer_limit = parse_output_buffer_limit(value).ok_or_else(invalid)?
+            }
+            "pipeline-depth" => {
+                self.pipeline_depth = value.parse().ok().filter(|&n| n > 0).ok_or_else(invalid)?
+            }
+            "aclfile" => self.aclfile = Some(value.to_string()),
+            "requirepass" => self.requirepass = Some(value.to_string()),
+            "acllog-max-len" => self.acllog_max_len = value.parse().map_err(|_| invalid())?,
+            "masteruser" => self.masteruser = value.to_string(),
+            "masterauth" => self.masterauth = Some(value.to_string()),
+            "metrics-port" => self.metrics_port = Some(value.parse().map_err(|_| invalid())?),
+            "shard-cores" => self.shard_cores = Some(parse_cores(value).ok_or_else(invalid)?),
+            "io-cores" => self.io_cores = Some(parse_cores(value).ok_or_else(invalid)?),
+            _ => return Err(format!("unknown config option '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// This node's address in the Raft groups, if Raft mode is on.
+    pub fn raft_address(&self) -> Option<String> {
+        if self.raft_peers.is_empty() {
+            return None;
+        }
+        Some(self.raft_address.clone().unwrap_or_else(|| format!("127.0.0.1:{}", self.port)))
+    }
+
+    /// How this node appears to the rest of the cluster, if cluster mode is on.
+    pub fn cluster_address(&self) -> Option<String> {
+        if !self.cluster_enabled {
+            return None;
+        }
+        Some(self.cluster_announce.clone().unwrap_or_else(|| format!("127.0.0.1:{}", self.port)))
+    }
+
+    pub fn client_limits(&self) -> ClientLimits {
+        ClientLimits {
+            max_request: self.max_request_size,
+            idle_timeout: (self.timeout > 0).then(|| Duration::from_secs(self.timeout)),
+            output: self.client_output_buffer_limit,
+            max_inflight: self.pipeline_depth,
+        }
+    }
+
+    /// Per-shard settings; the global memory limit is split evenly.
+    pub fn shard(&self, shard_count: usize) -> ShardConfig {
+        ShardConfig {
+            eviction: EvictionConfig {
+                maxmemory: self.maxmemory / shard_count,
+                policy: self.maxmemory_policy,
+                samples: self.maxmemory_samples,
+            },
+            snapshot: SnapshotConfig {
+                interval: Duration::from_secs(self.snapshot_interval),
+                writes: self.snapshot_writes,
+            },
+            repl_backlog: self.repl_backlog_size / shard_count,
+            lua_time_limit: self.lua_time_limit,
+        }
+    }
+}
+
+/// Parses sizes like `1048576`, `512kb`, `100mb` or `2gb`.
+pub fn parse_memory(value: &str) -> Option<usize> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => lower.split_at(idx),
+        None => (lower.as_str(), ""),
+    };
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" | "kb" => 1024,
+        "m" | "mb" => 1024 * 1024,
+        "g" | "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+/// Parses a list of CPU core ids like `0,2,4-7`. Order and repeats count:
+/// they're how `shard-cores` maps shards to cores.
+fn parse_cores(value: &str) -> Option<Vec<usize>> {
+    let mut cores = Vec::new();
+    for part in value.split(',').map(str::trim) {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last): (usize, usize) = (first.parse().ok()?, last.parse().ok()?);
+                if first > last {
+                    return None;
+                }
+                cores.extend(first..=last);
+            }
+            None => cores.push(part.parse().ok()?),
+        }
+    }
+    Some(cores)
+}
+
+/// Parses `hard soft soft-seconds`, the sizes as in `parse_memory`.
+fn parse_output_buffer_limit(value: &str) -> Option<OutputBufferLimit> {
+    match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+        [hard, soft, soft_seconds] => Some(OutputBufferLimit {
+            hard: parse_memory(hard)?,
+            soft: parse_memory(soft)?,
+            soft_seconds: soft_seconds.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+/// Parses `host port`, or `no one` for no primary.
+fn parse_replicaof(value: &str) -> Option<Option<(String, u16)>> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    match parts.as_slice() {
+        [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => Some(None),
+        [host, port] => Some(Some((host.to_string(), port.parse().ok()?))),
+        _ => None,
+    }
+}
@@ -1,9 +1,5 @@
-use super::ParsedCommand;
-use std::{
-    cmp::Reverse,
-    collections::{BinaryHeap, HashMap},
-    time::{SystemTime, UNIX_EPOCH},
-};
+use super::{Keyspace, ParsedCommand};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn now_ms() -> u64 {
     SystemTime::now()
@@ -12,33 +8,32 @@ pub fn now_ms() -> u64 {
         .as_millis() as u64
 }
 
-pub fn apply_db(
-    db: &mut HashMap<String, String>,
-    ttl_db: &mut HashMap<String, u64>,
-    expiry_heap: &mut BinaryHeap<Reverse<(u64, String)>>,
-    cmd: ParsedCommand,
-) {
+/// Applies a mutation to the shard state as of `now`. Every call bumps the
+/// shard version, both when serving live commands and when replaying the WAL
+/// (with the record's timestamp), so keys come out identical after a restart.
+pub fn apply_db(keyspace: &mut Keyspace, cmd: ParsedCommand, now: u64) {
+    let version = keyspace.next_version();
     match cmd {
         ParsedCommand::Set { key, value } => {
-            db.insert(key.clone(), value);
-            ttl_db.remove(&key);
+            keyspace.persist(&key);
+            keyspace.insert(key, value, version, now);
         }
         ParsedCommand::SetEx { key, value, ttl } => {
-            let expiry = now_ms() + ttl * 1000;
-            db.insert(key.clone(), value);
-            ttl_db.insert(key.clone(), expiry);
-            expiry_heap.push(Reverse((expiry, key.clone())));
+            keyspace.insert(key.clone(), value, version, now);
+            keyspace.set_expiry(key, now + ttl * 1000);
         }
-        ParsedCommand::Expire { key, ttl } => {
-            if db.contains_key(&key) {
-                let expiry = now_ms() + ttl * 1000;
-                ttl_db.insert(key.clone(), expiry);
-                expiry_heap.push(Reverse((expiry, key.clone())));
+        ParsedCommand::Expire { key, ttl } if keyspace.set_version(&key, version) => {
+            keyspace.set_expiry(key, now + ttl * 1000);
+        }
+        ParsedCommand::Restore { key, value, ttl_ms, .. } => {
+            keyspace.persist(&key);
+            keyspace.insert(key.clone(), value, version, now);
+            if ttl_ms > 0 {
+                keyspace.set_expiry(key, now + ttl_ms);
             }
         }
         ParsedCommand::Del { key } => {
-            db.remove(&key);
-            ttl_db.remove(&key);
+            keyspace.remove(&key);
         }
         _ => {}
     }
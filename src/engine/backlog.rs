@@ -0,0 +1,207 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::Sender;
+
+/// What a shard sends down a follower's replication stream.
+pub enum ReplFrame {
+    /// Start over from this snapshot file, taken at `version`.
+    FullSync { version: u64, snapshot: File },
+    /// The follower's data is still good; records resume where it left off.
+    Continue,
+    /// One encoded `WalRecord`.
+    Record(Arc<[u8]>),
+}
+
+/// Recent WAL records kept in memory, so a follower that lost its link for a
+/// moment can catch up without a full sync.
+pub struct Backlog {
+    records: VecDeque<Arc<[u8]>>,
+    /// LSN of the record just before the first one kept.
+    base_lsn: u64,
+    bytes: usize,
+    limit: usize,
+    /// While set, records after this LSN stay even over `limit`: followers
+    /// waiting on the snapshot taken there need them.
+    keep_from: Option<u64>,
+}
+
+impl Backlog {
+    pub fn new(limit: usize, lsn: u64) -> Self {
+        Self {
+            records: VecDeque::new(),
+            base_lsn: lsn,
+            bytes: 0,
+            limit,
+            keep_from: None,
+        }
+    }
+
+    pub fn push(&mut self, record: Arc<[u8]>) {
+        self.bytes += record.len();
+        self.records.push_back(record);
+        while self.bytes > self.limit
+            && self.records.len() > 1
+            && self.keep_from.is_none_or(|lsn| self.base_lsn < lsn)
+        {
+            let dropped = self.records.pop_front().unwrap();
+            self.bytes -= dropped.len();
+            self.base_lsn += 1;
+        }
+    }
+
+    /// The records after `lsn`, or `None` if some of them are gone.
+    pub fn since(&self, lsn: u64) -> Option<impl Iterator<Item = &Arc<[u8]>>> {
+        let last_lsn = self.base_lsn + self.records.len() as u64;
+        if lsn < self.base_lsn || lsn > last_lsn {
+            return None;
+        }
+        Some(self.records.iter().skip((lsn - self.base_lsn) as usize))
+    }
+}
+
+/// The followers of one shard and the backlog they resume from. The backlog
+/// only exists once a follower has connected, so a standalone node doesn't
+/// pay for copying every write.
+#[derive(Default)]
+pub struct Replicas {
+    backlog: Option<Backlog>,
+    streams: Vec<Sender<ReplFrame>>,
+}
+
+impl Replicas {
+    /// Hands a freshly committed record to the backlog and every follower.
+    /// Followers that can't keep up are dropped and have to resync.
+    pub fn feed(&mut self, record: &[u8]) {
+        let Some(backlog) = &mut self.backlog else {
+            return;
+        };
+        let record: Arc<[u8]> = record.into();
+        self.streams
+            .retain(|stream| stream.try_send(ReplFrame::Record(record.clone())).is_ok());
+        backlog.push(record);
+    }
+
+    /// True if the backlog has every record after `lsn`.
+    pub fn covers(&self, lsn: u64) -> bool {
+        self.backlog.as_ref().is_some_and(|b| b.since(lsn).is_some())
+    }
+
+    /// Lets a follower that has everything up to `lsn` resume from the
+    /// backlog. Gives the stream back if it needs a full sync instead.
+    pub fn resume(
+        &mut self,
+        stream: Sender<ReplFrame>,
+        lsn: Option<u64>,
+        limit: usize,
+        version: u64,
+    ) -> Result<(), Sender<ReplFrame>> {
+        let backlog = self.backlog.get_or_insert_with(|| Backlog::new(limit, version));
+        let Some(mut records) = lsn.and_then(|lsn| backlog.since(lsn)) else {
+            return Err(stream);
+        };
+        if stream.try_send(ReplFrame::Continue).is_ok()
+            && records.all(|r| stream.try_send(ReplFrame::Record(r.clone())).is_ok())
+        {
+            self.streams.push(stream);
+        }
+        Ok(())
+    }
+
+    /// Keeps every record after `version` until `release`, however big the
+    /// backlog grows, for followers waiting on the snapshot taken there.
+    pub fn hold(&mut self, version: u64) {
+        if let Some(backlog) = &mut self.backlog {
+            backlog.keep_from = Some(backlog.keep_from.map_or(version, |lsn| lsn.min(version)));
+        }
+    }
+
+    /// Lets the backlog shrink back to its limit once the followers `hold`
+    /// was for are attached.
+    pub fn release(&mut self) {
+        if let Some(backlog) = &mut self.backlog {
+            backlog.keep_from = None;
+        }
+    }
+
+    /// Starts streaming to a follower that was sent the snapshot taken at
+    /// `version`. False if the backlog no longer reaches back that far, and
+    /// the follower has to start over.
+    pub fn attach(&mut self, stream: Sender<ReplFrame>, version: u64, snapshot: File) -> bool {
+        let Some(mut records) = self.backlog.as_ref().and_then(|b| b.since(version)) else {
+            return false;
+        };
+        if stream.try_send(ReplFrame::FullSync { version, snapshot }).is_ok()
+            && records.all(|r| stream.try_send(ReplFrame::Record(r.clone())).is_ok())
+        {
+            self.streams.push(stream);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn record(byte: u8) -> Arc<[u8]> {
+        vec![byte; 10].into()
+    }
+
+    fn since(backlog: &Backlog, lsn: u64) -> Option<Vec<u8>> {
+        backlog.since(lsn).map(|records| records.map(|r| r[0]).collect())
+    }
+
+    #[test]
+    fn since_covers_only_what_is_kept() {
+        // Records 6 to 8, after a snapshot at 5.
+        let mut backlog = Backlog::new(100, 5);
+        assert_eq!(since(&backlog, 5), Some(vec![]));
+        for lsn in 6..=8 {
+            backlog.push(record(lsn));
+        }
+        assert_eq!(since(&backlog, 4), None);
+        assert_eq!(since(&backlog, 5), Some(vec![6, 7, 8]));
+        assert_eq!(since(&backlog, 7), Some(vec![8]));
+        assert_eq!(since(&backlog, 8), Some(vec![]));
+        assert_eq!(since(&backlog, 9), None);
+    }
+
+    #[test]
+    fn trimming_moves_the_start() {
+        let mut backlog = Backlog::new(25, 0);
+        for lsn in 1..=4 {
+            backlog.push(record(lsn));
+        }
+        assert_eq!(since(&backlog, 1), None);
+        assert_eq!(since(&backlog, 2), Some(vec![3, 4]));
+        // The newest record stays, however big.
+        backlog.push(vec![5; 100].into());
+        assert_eq!(since(&backlog, 4), Some(vec![5]));
+    }
+
+    #[test]
+    fn held_records_outlive_the_limit() {
+        let (stream, mut rx) = mpsc::channel(16);
+        let mut replicas = Replicas::default();
+        assert!(replicas.resume(stream.clone(), None, 25, 0).is_err());
+        replicas.hold(0);
+        for lsn in 1..=4 {
+            replicas.feed(&record(lsn));
+        }
+        assert!(replicas.covers(0));
+        let snapshot = File::open("/dev/null").unwrap();
+        assert!(replicas.attach(stream, 0, snapshot));
+        assert!(matches!(rx.try_recv(), Ok(ReplFrame::FullSync { version: 0, .. })));
+        for lsn in 1..=4 {
+            assert!(matches!(rx.try_recv(), Ok(ReplFrame::Record(r)) if r[0] == lsn));
+        }
+
+        replicas.release();
+        replicas.feed(&record(5));
+        assert!(!replicas.covers(0));
+        assert!(replicas.covers(3));
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use tokio::fs;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task;
+
+use super::command::{WalEntry, WalRecord};
+use super::parser::quote;
+use super::wal::{closed_segments, decode_segment, segment_path};
+
+/// Records queued for a consumer before it's cut off for falling behind.
+pub(super) const LIVE_CAPACITY: usize = 10_000;
+
+/// Streams one shard's committed writes to a CDC consumer, as lines of
+/// `<shard> <lsn> <timestamp-ms> <command>`.
+///
+/// Records after `from` (everything on disk for 0, nothing old for `None`)
+/// up to `history_end` come from the WAL segments; the ones after that from
+/// `live`, which the WAL task fed from the moment it flushed `history_end`
+/// to disk. A position whose segment was already purged after a snapshot,
+/// a jump in LSNs (a follower loading a full sync) or a consumer too slow
+/// for `live` end the stream with an `ERR` line.
+pub(super) fn spawn_feed(
+    shard_id: usize,
+    from: Option<u64>,
+    history_end: u64,
+    live: Receiver<Vec<u8>>,
+    out: Sender<Vec<u8>>,
+) {
+    task::spawn(async move {
+        if let Err(e) = feed(shard_id, from, history_end, live, &out).await {
+            let _ = out.send(format!("ERR CDC shard {}: {}\n", shard_id, e).into_bytes()).await;
+        }
+    });
+}
+
+async fn feed(
+    shard_id: usize,
+    from: Option<u64>,
+    history_end: u64,
+    mut live: Receiver<Vec<u8>>,
+    out: &Sender<Vec<u8>>,
+) -> Result<(), String> {
+    let mut next = from.map_or(history_end, |lsn| lsn.min(history_end)) + 1;
+    if from.is_some() && next <= history_end {
+        let mut segments: Vec<(Option<u64>, PathBuf)> = closed_segments(shard_id)
+            .await
+            .into_iter()
+            .map(|(lsn, path)| (Some(lsn), path))
+            .collect();
+        segments.push((None, segment_path(shard_id).into()));
+
+        for (last_lsn, path) in segments {
+            if last_lsn.is_some_and(|lsn| lsn < next) {
+                continue;
+            }
+            // Purged since we listed it; the check below catches the hole.
+            let Ok(data) = fs::read(&path).await else {
+                continue;
+            };
+            let records = decode_segment(&data, |count| last_lsn.unwrap_or(0).saturating_sub(count as u64));
+            for record in records {
+                if record.lsn < next || record.lsn > history_end {
+                    continue;
+                }
+                // Starting from 0 means from the oldest record still around.
+                if from == Some(0) && next == 1 {
+                    next = record.lsn;
+                }
+                if record.lsn != next {
+                    return Err(format!("position {} is no longer retained", next - 1));
+                }
+                send(shard_id, record, out).await?;
+                next += 1;
+            }
+        }
+        if next <= history_end && from != Some(0) {
+            return Err(format!("position {} is no longer retained", next - 1));
+        }
+        next = next.max(history_end + 1);
+    }
+
+    while let Some(encoded) = live.recv().await {
+        let record: WalRecord = bincode::deserialize(&encoded).map_err(|e| e.to_string())?;
+        if record.lsn != next {
+            return Err(format!("history jumped from {} to {}", next - 1, record.lsn));
+        }
+        send(shard_id, record, out).await?;
+        next += 1;
+    }
+    Err("consumer fell behind".into())
+}
+
+async fn send(shard_id: usize, record: WalRecord, out: &Sender<Vec<u8>>) -> Result<(), String> {
+    let mut line = format!("{} {} {} ", shard_id, record.lsn, record.timestamp).into_bytes();
+    line.extend(command(&record.entry));
+    line.push(b'\n');
+    // The consumer went away; nothing left to report to.
+    out.send(line).await.map_err(|_| String::new())
+}
+
+/// The write as the command that would redo it.
+fn command(entry: &WalEntry) -> Vec<u8> {
+    let number = |n: &u64| n.to_string().into_bytes();
+    let words = match entry {
+        WalEntry::Set { key, value } => vec![b"SET".to_vec(), quote(key), quote(value)],
+        WalEntry::SetEx { key, value, ttl } => vec![b"SETEX".to_vec(), quote(key), quote(value), number(ttl)],
+        WalEntry::Del { key } => vec![b"DEL".to_vec(), quote(key)],
+        WalEntry::Expire { key, ttl } => vec![b"EXPIRE".to_vec(), quote(key), number(ttl)],
+        WalEntry::Restore { key, value, ttl_ms } => {
+            vec![b"RESTORE".to_vec(), quote(key), number(ttl_ms), quote(value), b"REPLACE".to_vec()]
+        }
+    };
+    words.join(&b' ')
+}
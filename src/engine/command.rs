@@ -1,75 +1,476 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use tracing::Span;
+
+use super::backlog::ReplFrame;
+use super::raft::RaftMsg;
+use super::reply::Resp;
 
 pub enum Command {
     Set {
-        key: String,
-        value: String,
-        resp: oneshot::Sender<String>,
+        key: Bytes,
+        value: Bytes,
+        resp: Resp,
     },
     SetEx {
-        key: String,
-        value: String,
+        key: Bytes,
+        value: Bytes,
         ttl: u64,
-        resp: oneshot::Sender<String>,
+        resp: Resp,
     },
     Get {
-        key: String,
-        resp: oneshot::Sender<String>,
+        key: Bytes,
+        resp: Resp,
     },
     Del {
-        key: String,
-        resp: oneshot::Sender<String>,
+        key: Bytes,
+        resp: Resp,
     },
     Ex {
-        key: String,
-        resp: oneshot::Sender<String>,
+        key: Bytes,
+        resp: Resp,
     },
     Expire {
-        key: String,
+        key: Bytes,
         ttl: u64,
-        resp: oneshot::Sender<String>,
+        resp: Resp,
     },
     Ttl {
-        key: String,
-        resp: oneshot::Sender<String>,
+        key: Bytes,
+        resp: Resp,
     },
     Ping {
-        resp: oneshot::Sender<String>,
+        resp: Resp,
+    },
+    GetVer {
+        key: Bytes,
+        resp: Resp,
+    },
+    SetIfVer {
+        key: Bytes,
+        value: Bytes,
+        version: u64,
+        resp: Resp,
+    },
+    DelIfVer {
+        key: Bytes,
+        version: u64,
+        resp: Resp,
+    },
+    Eval {
+        sha: String,
+        source: Arc<str>,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        resp: Resp,
+    },
+    /// Drops the shard's compiled scripts after SCRIPT FLUSH.
+    ScriptFlush {
+        resp: Resp,
+    },
+    Save {
+        background: bool,
+        resp: Resp,
+    },
+    LastSave {
+        resp: Resp,
+    },
+    /// A follower wants this shard's writes. `lsn` is how far it got, if it
+    /// may resume from there rather than start over from a snapshot.
+    Psync {
+        lsn: Option<u64>,
+        stream: mpsc::Sender<ReplFrame>,
+    },
+    /// Encoded `WalRecord`s streamed from the primary, applied on a follower.
+    ApplyRecords {
+        records: Vec<Vec<u8>>,
+        resp: Resp,
+    },
+    /// A snapshot file from the primary that replaces this shard's data.
+    LoadSnapshot {
+        snapshot: Vec<u8>,
+        resp: Resp,
+    },
+    ReplOffset {
+        resp: Resp,
+    },
+    /// A CDC consumer wants this shard's writes after `from`, or only new
+    /// ones with `None`, as lines sent to `out`.
+    CdcSubscribe {
+        from: Option<u64>,
+        out: mpsc::Sender<Vec<u8>>,
+    },
+    /// A message from another node of this shard's Raft group.
+    Raft {
+        from: String,
+        msg: RaftMsg,
+    },
+    RaftChange {
+        add: bool,
+        node: String,
+        resp: Resp,
+    },
+    RaftInfo {
+        resp: Resp,
+    },
+    /// Writes a key moved here from another node, with `ttl_ms` of 0 for no
+    /// TTL. Refused if the key exists, unless `replace` is set.
+    Restore {
+        key: Bytes,
+        value: Bytes,
+        ttl_ms: u64,
+        replace: bool,
+        resp: Resp,
+    },
+    /// A key as `<ttl_ms> <version> <value>` for `MIGRATE`, or `nil`.
+    Dump {
+        key: Bytes,
+        resp: Resp,
+    },
+    /// Up to `count` of this shard's keys in a cluster slot, one per line.
+    KeysInSlot {
+        slot: u16,
+        count: usize,
+        resp: Resp,
+    },
+    CountKeysInSlot {
+        slot: u16,
+        resp: Resp,
     },
+    /// This shard's keys that belong on another of `shards` shards.
+    Strays {
+        shards: usize,
+        out: oneshot::Sender<Vec<Stray>>,
+    },
+}
+
+/// A key read out of a shard it doesn't belong on, to move it: its TTL in
+/// ms (0 for none), version and value.
+pub struct Stray {
+    pub key: Bytes,
+    pub value: Bytes,
+    pub ttl_ms: u64,
+    pub version: u64,
 }
 
 pub enum ParsedCommand {
     Set {
-        key: String,
-        value: String,
+        key: Bytes,
+        value: Bytes,
     },
     SetEx {
-        key: String,
-        value: String,
+        key: Bytes,
+        value: Bytes,
         ttl: u64,
     },
     Get {
-        key: String,
+        key: Bytes,
     },
     Del {
-        key: String,
+        key: Bytes,
     },
     Ex {
-        key: String,
+        key: Bytes,
     },
     Expire {
-        key: String,
+        key: Bytes,
         ttl: u64,
     },
     Ttl {
-        key: String,
+        key: Bytes,
+    },
+    Ping,
+    GetVer {
+        key: Bytes,
+    },
+    SetIfVer {
+        key: Bytes,
+        value: Bytes,
+        version: u64,
+    },
+    DelIfVer {
+        key: Bytes,
+        version: u64,
+    },
+    Eval {
+        script: String,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+    },
+    EvalSha {
+        sha: String,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+    },
+    ScriptLoad {
+        script: String,
+    },
+    ScriptExists {
+        shas: Vec<String>,
+    },
+    ScriptFlush,
+    ScriptKill,
+    Save,
+    BgSave,
+    LastSave,
+    Psync {
+        shard: usize,
+        replid: String,
+        lsn: u64,
+        port: u16,
     },
-    Ping
+    ReplicaOf {
+        primary: Option<(String, u16)>,
+    },
+    Role,
+    Info {
+        section: Option<String>,
+    },
+    /// A peer opening its link for Raft messages.
+    RaftLink,
+    /// Turns the connection into a stream of committed writes. One position
+    /// for every shard, or one per shard; `None` for only new writes.
+    CdcSubscribe {
+        positions: Vec<Option<u64>>,
+    },
+    RaftChange {
+        add: bool,
+        node: String,
+    },
+    Cluster(ClusterCommand),
+    Client(ClientCommand),
+    /// Logs in as `user`, or `default` if not given.
+    Auth {
+        user: Option<String>,
+        password: String,
+    },
+    Acl(AclCommand),
+    /// Lets the next command use a slot this node is importing.
+    Asking,
+    Migrate {
+        addr: String,
+        keys: Vec<Bytes>,
+        copy: bool,
+        replace: bool,
+        timeout_ms: u64,
+    },
+    Restore {
+        key: Bytes,
+        value: Bytes,
+        ttl_ms: u64,
+        replace: bool,
+    },
+    /// Latency percentiles of the given commands, or all if empty.
+    LatencyHistogram {
+        commands: Vec<String>,
+    },
+    LatencyReset,
+    SlowlogGet {
+        count: usize,
+    },
+    SlowlogLen,
+    SlowlogReset,
+}
+
+pub enum ClusterCommand {
+    Info,
+    MyId,
+    Nodes,
+    Slots,
+    Shards,
+    KeySlot { key: String },
+    Meet { addr: String },
+    AddSlots { slots: Vec<u16> },
+    SetSlot { slot: u16, state: SlotState },
+    GetKeysInSlot { slot: u16, count: usize },
+    CountKeysInSlot { slot: u16 },
+    /// Another node's view of the cluster, which we answer with ours.
+    Gossip { sender: String, payload: String },
+}
+
+pub enum ClientCommand {
+    List,
+    Info,
+    Id,
+    SetName { name: String },
+    GetName,
+    /// `legacy` is the `CLIENT KILL addr` form, which replies OK or an error
+    /// rather than a count.
+    Kill { filter: ClientFilter, legacy: bool },
+    Pause { timeout_ms: u64, writes_only: bool },
+    Unpause,
+}
+
+/// Which clients `CLIENT KILL` closes.
+pub enum ClientFilter {
+    Id(u64),
+    Addr(String),
+    User(String),
+}
+
+pub enum AclCommand {
+    SetUser { name: String, rules: Vec<String> },
+    GetUser { name: String },
+    DelUser { names: Vec<String> },
+    List,
+    Users,
+    WhoAmI,
+    /// The categories, or the commands in one.
+    Cat { category: Option<String> },
+    Log { count: usize },
+    LogReset,
+    Save,
+    Load,
+}
+
+/// `CLUSTER SETSLOT <slot> IMPORTING|MIGRATING|NODE <node-id>` or `STABLE`.
+pub enum SlotState {
+    Importing(String),
+    Migrating(String),
+    Node(String),
+    Stable,
+}
+
+impl ParsedCommand {
+    /// The name `LATENCY` and `SLOWLOG` file the command under.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParsedCommand::Set { .. } => "set",
+            ParsedCommand::SetEx { .. } => "setex",
+            ParsedCommand::Get { .. } => "get",
+            ParsedCommand::Del { .. } => "del",
+            ParsedCommand::Ex { .. } => "ex",
+            ParsedCommand::Expire { .. } => "expire",
+            ParsedCommand::Ttl { .. } => "ttl",
+            ParsedCommand::Ping => "ping",
+            ParsedCommand::GetVer { .. } => "getver",
+            ParsedCommand::SetIfVer { .. } => "setifver",
+            ParsedCommand::DelIfVer { .. } => "delifver",
+            ParsedCommand::Eval { .. } => "eval",
+            ParsedCommand::EvalSha { .. } => "evalsha",
+            ParsedCommand::ScriptLoad { .. }
+            | ParsedCommand::ScriptExists { .. }
+            | ParsedCommand::ScriptFlush
+            | ParsedCommand::ScriptKill => "script",
+            ParsedCommand::Save => "save",
+            ParsedCommand::BgSave => "bgsave",
+            ParsedCommand::LastSave => "lastsave",
+            ParsedCommand::Psync { .. } => "psync",
+            ParsedCommand::ReplicaOf { .. } => "replicaof",
+            ParsedCommand::Role => "role",
+            ParsedCommand::Info { .. } => "info",
+            ParsedCommand::RaftLink | ParsedCommand::RaftChange { .. } => "raft",
+            ParsedCommand::CdcSubscribe { .. } => "cdc",
+            ParsedCommand::Cluster(_) => "cluster",
+            ParsedCommand::Client(_) => "client",
+            ParsedCommand::Auth { .. } => "auth",
+            ParsedCommand::Acl(_) => "acl",
+            ParsedCommand::Asking => "asking",
+            ParsedCommand::Migrate { .. } => "migrate",
+            ParsedCommand::Restore { .. } => "restore",
+            ParsedCommand::LatencyHistogram { .. } | ParsedCommand::LatencyReset => "latency",
+            ParsedCommand::SlowlogGet { .. } | ParsedCommand::SlowlogLen | ParsedCommand::SlowlogReset => "slowlog",
+        }
+    }
+
+    /// Commands refused on a read-only follower. Scripts count as writes
+    /// since we can't tell up front whether they write.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            ParsedCommand::Set { .. }
+                | ParsedCommand::SetEx { .. }
+                | ParsedCommand::Del { .. }
+                | ParsedCommand::Expire { .. }
+                | ParsedCommand::SetIfVer { .. }
+                | ParsedCommand::DelIfVer { .. }
+                | ParsedCommand::Eval { .. }
+                | ParsedCommand::EvalSha { .. }
+                | ParsedCommand::Migrate { .. }
+                | ParsedCommand::Restore { .. }
+        )
+    }
+
+    /// The key that picks the shard a command runs on, as in
+    /// `Command::primary_key`, or `None` for commands that don't run on one.
+    pub fn shard_key(&self) -> Option<&[u8]> {
+        match self {
+            ParsedCommand::Set { key, .. }
+            | ParsedCommand::SetEx { key, .. }
+            | ParsedCommand::Get { key }
+            | ParsedCommand::Del { key }
+            | ParsedCommand::Ex { key }
+            | ParsedCommand::Expire { key, .. }
+            | ParsedCommand::Ttl { key }
+            | ParsedCommand::GetVer { key }
+            | ParsedCommand::SetIfVer { key, .. }
+            | ParsedCommand::DelIfVer { key, .. }
+            | ParsedCommand::Restore { key, .. } => Some(key),
+            ParsedCommand::Eval { keys, .. } | ParsedCommand::EvalSha { keys, .. } => {
+                Some(keys.first().map_or(&b""[..], |k| &k[..]))
+            }
+            ParsedCommand::Ping => Some(&b""[..]),
+            _ => None,
+        }
+    }
+
+    /// The keys a command touches, which decide the cluster slot it runs on.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            ParsedCommand::Set { key, .. }
+            | ParsedCommand::SetEx { key, .. }
+            | ParsedCommand::Get { key }
+            | ParsedCommand::Del { key }
+            | ParsedCommand::Ex { key }
+            | ParsedCommand::Expire { key, .. }
+            | ParsedCommand::Ttl { key }
+            | ParsedCommand::GetVer { key }
+            | ParsedCommand::SetIfVer { key, .. }
+            | ParsedCommand::DelIfVer { key, .. }
+            | ParsedCommand::Restore { key, .. } => vec![key],
+            ParsedCommand::Eval { keys, .. } | ParsedCommand::EvalSha { keys, .. } => {
+                keys.iter().map(|k| &k[..]).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
 }
 
 impl Command {
-    pub fn primary_key(&self) -> &str {
+    /// Same names as `ParsedCommand::name` for the commands clients send.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set { .. } => "set",
+            Command::SetEx { .. } => "setex",
+            Command::Get { .. } => "get",
+            Command::Del { .. } => "del",
+            Command::Ex { .. } => "ex",
+            Command::Expire { .. } => "expire",
+            Command::Ttl { .. } => "ttl",
+            Command::Ping { .. } => "ping",
+            Command::GetVer { .. } => "getver",
+            Command::SetIfVer { .. } => "setifver",
+            Command::DelIfVer { .. } => "delifver",
+            Command::Eval { .. } => "eval",
+            Command::ScriptFlush { .. } => "script",
+            Command::Save { .. } => "save",
+            Command::LastSave { .. } => "lastsave",
+            Command::Psync { .. } => "psync",
+            Command::ApplyRecords { .. } | Command::LoadSnapshot { .. } | Command::ReplOffset { .. } => "replication",
+            Command::CdcSubscribe { .. } => "cdc",
+            Command::Raft { .. } | Command::RaftChange { .. } | Command::RaftInfo { .. } => "raft",
+            Command::Restore { .. } => "restore",
+            Command::Dump { .. } => "dump",
+            Command::KeysInSlot { .. } | Command::CountKeysInSlot { .. } => "cluster",
+            Command::Strays { .. } => "strays",
+        }
+    }
+
+    pub fn primary_key(&self) -> &[u8] {
         match self {
             Command::Set { key, .. } => key,
             Command::Get { key, .. } => key,
@@ -78,33 +479,141 @@ impl Command {
             Command::Expire { key, .. } => key,
             Command::Ex { key, .. } => key,
             Command::Ttl { key, .. } => key,
-            Command::Ping { .. } => "",
+            Command::Ping { .. } => b"",
+            Command::GetVer { key, .. } => key,
+            Command::SetIfVer { key, .. } => key,
+            Command::DelIfVer { key, .. } => key,
+            Command::Eval { keys, .. } => keys.first().map_or(&b""[..], |k| &k[..]),
+            Command::Restore { key, .. } => key,
+            Command::Dump { key, .. } => key,
+            Command::ScriptFlush { .. }
+            | Command::Save { .. }
+            | Command::LastSave { .. }
+            | Command::Psync { .. }
+            | Command::ApplyRecords { .. }
+            | Command::LoadSnapshot { .. }
+            | Command::ReplOffset { .. }
+            | Command::CdcSubscribe { .. }
+            | Command::Raft { .. }
+            | Command::RaftChange { .. }
+            | Command::RaftInfo { .. }
+            | Command::KeysInSlot { .. }
+            | Command::CountKeysInSlot { .. }
+            | Command::Strays { .. } => b"",
         }
     }
+
+    /// Writes that can add data, refused with OOM when the shard is full.
+    pub fn may_grow_memory(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. } | Command::SetEx { .. } | Command::SetIfVer { .. } | Command::Eval { .. } | Command::Restore { .. }
+        )
+    }
+
+    /// Answers the command with `reply` without executing it.
+    pub fn reject(self, reply: &str) {
+        let resp = match self {
+            Command::Set { resp, .. }
+            | Command::SetEx { resp, .. }
+            | Command::Get { resp, .. }
+            | Command::Del { resp, .. }
+            | Command::Ex { resp, .. }
+            | Command::Expire { resp, .. }
+            | Command::Ttl { resp, .. }
+            | Command::Ping { resp }
+            | Command::GetVer { resp, .. }
+            | Command::SetIfVer { resp, .. }
+            | Command::DelIfVer { resp, .. }
+            | Command::Eval { resp, .. }
+            | Command::ScriptFlush { resp }
+            | Command::Save { resp, .. }
+            | Command::LastSave { resp }
+            | Command::ApplyRecords { resp, .. }
+            | Command::LoadSnapshot { resp, .. }
+            | Command::ReplOffset { resp }
+            | Command::RaftChange { resp, .. }
+            | Command::RaftInfo { resp }
+            | Command::Restore { resp, .. }
+            | Command::Dump { resp, .. }
+            | Command::KeysInSlot { resp, .. }
+            | Command::CountKeysInSlot { resp, .. } => resp,
+            Command::Psync { .. } | Command::Raft { .. } | Command::CdcSubscribe { .. } | Command::Strays { .. } => return,
+        };
+        let _ = resp.send(Bytes::copy_from_slice(reply.as_bytes()));
+    }
+}
+
+/// Commands on their way to a shard, in the order it should run them: one,
+/// or a client's batch. Each comes with the span of the request it's for,
+/// so the shard's logs can be tied back to it, and the whole message is
+/// stamped with when it was queued.
+pub struct Queued {
+    pub cmds: Vec<(Command, Span)>,
+    pub at: Instant,
 }
 
 pub enum WalCommand {
     Write(Vec<u8>),
-    Truncate,
+    /// Writes out what's buffered and syncs the segment to disk, then
+    /// answers. Dropped unanswered if that fails.
+    Sync(oneshot::Sender<()>),
+    /// Closes the current segment, whose records all have an LSN of at most
+    /// `last_lsn`, and starts a new one.
+    Rotate { last_lsn: u64 },
+    /// Deletes closed segments that only hold records up to `upto_lsn`.
+    Purge { upto_lsn: u64 },
+    /// Starts a CDC feed; records up to `history_end` are all in this
+    /// command's queue ahead of it.
+    Subscribe {
+        from: Option<u64>,
+        history_end: u64,
+        out: mpsc::Sender<Vec<u8>>,
+    },
 }
 
+/// One WAL record. `lsn` is the shard version the write produced, so replay
+/// can skip records a snapshot already contains.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WalRecord<E = WalEntry> {
+    pub lsn: u64,
+    pub timestamp: u64,
+    pub entry: E,
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum WalEntry {
     Set {
-        key: String,
-        value: String,
+        key: Bytes,
+        value: Bytes,
     },
     SetEx {
-        key: String,
-        value: String,
+        key: Bytes,
+        value: Bytes,
         ttl: u64,
     },
     Del {
-        key: String,
+        key: Bytes,
     },
     Expire {
-        key: String,
+        key: Bytes,
         ttl: u64,
+    },
+    Restore {
+        key: Bytes,
+        value: Bytes,
+        ttl_ms: u64,
+    },
+}
+
+impl From<WalEntry> for ParsedCommand {
+    fn from(entry: WalEntry) -> Self {
+        match entry {
+            WalEntry::Set { key, value } => ParsedCommand::Set { key, value },
+            WalEntry::SetEx { key, value, ttl } => ParsedCommand::SetEx { key, value, ttl },
+            WalEntry::Del { key } => ParsedCommand::Del { key },
+            WalEntry::Expire { key, ttl } => ParsedCommand::Expire { key, ttl },
+            WalEntry::Restore { key, value, ttl_ms } => ParsedCommand::Restore { key, value, ttl_ms, replace: true },
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,31 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+/// A value stored in a shard together with the version of its last write.
+///
+/// Versions come from a per-shard counter that is bumped on every logged
+/// mutation, so a key that is deleted and recreated never reuses an old
+/// version.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub value: Bytes,
+    pub version: u64,
+    /// Seconds timestamp of the last access, for the LRU policies and LFU
+    /// decay. Not persisted.
+    #[serde(skip)]
+    pub access: u32,
+    /// Logarithmic access counter for the LFU policies. Not persisted.
+    #[serde(skip)]
+    pub freq: u8,
+}
+
+impl Entry {
+    pub fn new(value: Bytes, version: u64) -> Self {
+        Self {
+            value,
+            version,
+            access: 0,
+            freq: 0,
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::mem;
+
+use bytes::Bytes;
+use indexmap::IndexSet;
+
+use super::apply::now_ms;
+
+// 8 levels of 64 one-millisecond slots cover 2^48 ms (~8900 years), so any
+// realistic expiry timestamp fits without an overflow list.
+const SLOT_BITS: usize = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const LEVELS: usize = 8;
+const MAX_EXPIRY: u64 = (1 << (SLOT_BITS * LEVELS)) - 1;
+
+/// Hierarchical timing wheel holding the keys that have a TTL.
+///
+/// A key with expiry `e` sits on the level of the highest 6-bit group in
+/// which `e` differs from the wheel's cursor, in the slot given by that group
+/// of `e`. Since its position can be recomputed from `(e, cursor)`, cancelling
+/// is a single hash removal and the wheel never keeps entries for keys that
+/// were deleted, overwritten or re-expired.
+pub struct TimingWheel {
+    cursor: u64,
+    slots: Vec<HashMap<Bytes, u64>>,
+    /// Keys whose expiry the cursor has reached, waiting to be removed.
+    due: IndexSet<Bytes>,
+    len: usize,
+}
+
+impl Default for TimingWheel {
+    fn default() -> Self {
+        Self::new(now_ms())
+    }
+}
+
+impl TimingWheel {
+    pub fn new(now: u64) -> Self {
+        Self {
+            cursor: now.min(MAX_EXPIRY),
+            slots: vec![HashMap::new(); SLOTS * LEVELS],
+            due: IndexSet::new(),
+            len: 0,
+        }
+    }
+
+    pub fn insert(&mut self, key: Bytes, expiry: u64) {
+        self.len += 1;
+        self.place(key, expiry.min(MAX_EXPIRY));
+    }
+
+    /// Removes `key`, which must have been inserted with this `expiry`.
+    pub fn cancel(&mut self, key: &[u8], expiry: u64) {
+        let expiry = expiry.min(MAX_EXPIRY);
+        let removed = if expiry <= self.cursor {
+            self.due.swap_remove(key)
+        } else {
+            let idx = self.slot_index(expiry);
+            self.slots[idx].remove(key).is_some()
+        };
+        if removed {
+            self.len -= 1;
+        }
+    }
+
+    /// Moves the cursor up to `now`, collecting every key that came due.
+    pub fn advance(&mut self, now: u64) {
+        let now = now.min(MAX_EXPIRY);
+        if self.len == self.due.len() {
+            // Nothing scheduled, no slots to walk through.
+            self.cursor = self.cursor.max(now);
+            return;
+        }
+        while self.cursor < now {
+            self.cursor += 1;
+            // Crossing a boundary of a higher level brings one of its slots
+            // within range; spread those keys over the levels below.
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level;
+                if self.cursor & ((1 << shift) - 1) == 0 {
+                    let slot = (self.cursor >> shift) as usize & (SLOTS - 1);
+                    for (key, expiry) in mem::take(&mut self.slots[level * SLOTS + slot]) {
+                        self.place(key, expiry);
+                    }
+                }
+            }
+            let slot = self.cursor as usize & (SLOTS - 1);
+            for (key, _) in mem::take(&mut self.slots[slot]) {
+                self.due.insert(key);
+            }
+        }
+    }
+
+    /// Pops one key whose expiry the cursor has reached, if any.
+    pub fn pop_due(&mut self) -> Option<Bytes> {
+        let key = self.due.pop()?;
+        self.len -= 1;
+        Some(key)
+    }
+
+    pub fn has_due(&self) -> bool {
+        !self.due.is_empty()
+    }
+
+    fn place(&mut self, key: Bytes, expiry: u64) {
+        if expiry <= self.cursor {
+            self.due.insert(key);
+        } else {
+            let idx = self.slot_index(expiry);
+            self.slots[idx].insert(key, expiry);
+        }
+    }
+
+    fn slot_index(&self, expiry: u64) -> usize {
+        let diff = expiry ^ self.cursor;
+        let level = (63 - diff.leading_zeros() as usize) / SLOT_BITS;
+        let slot = (expiry >> (SLOT_BITS * level)) as usize & (SLOTS - 1);
+        level * SLOTS + slot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn due(wheel: &mut TimingWheel) -> Vec<Bytes> {
+        let mut keys: Vec<Bytes> = std::iter::from_fn(|| wheel.pop_due()).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn keys_cascade_down_and_come_due_on_time() {
+        let start = 1_000_003;
+        let mut wheel = TimingWheel::new(start);
+        // One level apart and right around the slot boundaries, so keys come
+        // down through every level in between.
+        let offsets = [1, 2, 63, 64, 65, 4_095, 4_096, 4_097, 262_143, 262_144, 300_001];
+        for offset in offsets {
+            wheel.insert(Bytes::from(offset.to_string()), start + offset);
+        }
+        for offset in offsets {
+            wheel.advance(start + offset - 1);
+            assert_eq!(due(&mut wheel), Vec::<Bytes>::new(), "early at {}", offset);
+            wheel.advance(start + offset);
+            assert_eq!(due(&mut wheel), vec![Bytes::from(offset.to_string())], "late at {}", offset);
+        }
+        assert_eq!(wheel.len, 0);
+    }
+
+    #[test]
+    fn cancel_finds_keys_after_they_cascade() {
+        let mut wheel = TimingWheel::new(0);
+        wheel.insert(Bytes::from_static(b"a"), 5_000);
+        wheel.insert(Bytes::from_static(b"b"), 5_000);
+        // Both have moved down a level or two by now.
+        wheel.advance(4_990);
+        wheel.cancel(b"a", 5_000);
+        wheel.advance(10_000);
+        assert_eq!(due(&mut wheel), vec![Bytes::from_static(b"b")]);
+        assert_eq!(wheel.len, 0);
+    }
+
+    #[test]
+    fn reexpired_and_past_keys() {
+        let mut wheel = TimingWheel::new(100);
+        wheel.insert(Bytes::from_static(b"k"), 200);
+        wheel.cancel(b"k", 200);
+        wheel.insert(Bytes::from_static(b"k"), 300);
+        wheel.advance(250);
+        assert!(!wheel.has_due());
+        wheel.advance(300);
+        assert_eq!(due(&mut wheel), vec![Bytes::from_static(b"k")]);
+
+        // Already expired: due without the cursor moving.
+        wheel.insert(Bytes::from_static(b"old"), 50);
+        assert_eq!(due(&mut wheel), vec![Bytes::from_static(b"old")]);
+        wheel.insert(Bytes::from_static(b"old"), 50);
+        wheel.cancel(b"old", 50);
+        assert!(!wheel.has_due());
+        assert_eq!(wheel.len, 0);
+    }
+}
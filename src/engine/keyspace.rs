@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use indexmap::IndexMap;
+
+use super::Entry;
+use super::expiry::TimingWheel;
+use super::snapshot::SnapshotState;
+use crate::config::EvictionPolicy;
+
+// Rough per-item costs on top of the key and value bytes: map slot, `Bytes`
+// headers and the entry metadata. A TTL costs its `ttl_db` slot plus the
+// key in the timing wheel. Only used for maxmemory accounting.
+const ENTRY_OVERHEAD: usize = 80;
+const TTL_OVERHEAD: usize = 80;
+
+// LFU counter tuning, same meaning as Redis' lfu-log-factor / lfu-decay-time.
+const LFU_INIT_VAL: u8 = 5;
+const LFU_LOG_FACTOR: f64 = 10.0;
+const LFU_DECAY_SECS: u32 = 60;
+
+fn clock_secs(now_ms: u64) -> u32 {
+    (now_ms / 1000) as u32
+}
+
+/// The data owned by one shard: values, TTLs and the expiry wheel, plus an
+/// estimate of the memory they take.
+///
+/// Both maps are `IndexMap`s so eviction can sample random keys in O(1).
+#[derive(Default)]
+pub struct Keyspace {
+    db: IndexMap<Bytes, Entry>,
+    ttl_db: IndexMap<Bytes, u64>,
+    expiry_wheel: TimingWheel,
+    version: u64,
+    used_memory: usize,
+    /// Keys removed because their TTL passed, since startup.
+    expired: u64,
+    /// Client reads that found their key, and ones that didn't.
+    hits: u64,
+    misses: u64,
+    capture: Option<Capture>,
+}
+
+/// A snapshot being written out a chunk at a time, see `begin_snapshot`.
+struct Capture {
+    /// Keys at `db` indices below this have been written out.
+    cursor: usize,
+    /// Keys changed since the snapshot began before being written out, with
+    /// their state at that point (`None` if they didn't exist yet).
+    dirty: HashMap<Bytes, Option<(Entry, Option<u64>)>>,
+}
+
+impl Keyspace {
+    pub fn from_snapshot(snapshot: SnapshotState, now: u64) -> Self {
+        let mut keyspace = Keyspace {
+            version: snapshot.version,
+            ..Default::default()
+        };
+        for (key, mut entry) in snapshot.db {
+            entry.access = clock_secs(now);
+            entry.freq = LFU_INIT_VAL;
+            keyspace.used_memory += key.len() + entry.value.len() + ENTRY_OVERHEAD;
+            keyspace.db.insert(key, entry);
+        }
+        for (key, expiry) in snapshot.ttl_db {
+            if keyspace.db.contains_key(&key) {
+                keyspace.set_expiry(key, expiry);
+            }
+        }
+        keyspace
+    }
+
+    pub fn db(&self) -> &IndexMap<Bytes, Entry> {
+        &self.db
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn next_version(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
+
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    /// Number of keys with a TTL.
+    pub fn expiring_keys(&self) -> usize {
+        self.ttl_db.len()
+    }
+
+    pub fn expired_keys(&self) -> u64 {
+        self.expired
+    }
+
+    /// Hits and misses of `get`.
+    pub fn lookups(&self) -> (u64, u64) {
+        (self.hits, self.misses)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.db.contains_key(key)
+    }
+
+    pub fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.ttl_db.get(key).copied()
+    }
+
+    /// Looks a key up without expiring it or counting it as an access.
+    pub fn peek(&self, key: &[u8], now: u64) -> Option<(&Entry, Option<u64>)> {
+        let entry = self.db.get(key)?;
+        let expiry = self.expiry(key);
+        match expiry {
+            Some(exp) if exp <= now => None,
+            _ => Some((entry, expiry)),
+        }
+    }
+
+    /// Looks a key up for a client read, dropping it if its TTL has passed
+    /// and updating the LRU / LFU bookkeeping otherwise.
+    pub fn get(&mut self, key: &[u8], now: u64) -> Option<&Entry> {
+        self.expire_if_needed(key, now);
+        let Some(entry) = self.db.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        touch(entry, now);
+        Some(entry)
+    }
+
+    /// Removes the key if its TTL has passed. Returns true if it did.
+    pub fn expire_if_needed(&mut self, key: &[u8], now: u64) -> bool {
+        match self.ttl_db.get(key) {
+            Some(&expiry) if expiry <= now => {
+                self.remove(key);
+                self.expired += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn insert(&mut self, key: Bytes, value: Bytes, version: u64, now: u64) {
+        self.before_write(&key);
+        let (key, value) = (detach(&key), detach(&value));
+        let mut entry = Entry::new(value, version);
+        entry.access = clock_secs(now);
+        entry.freq = LFU_INIT_VAL;
+        let added = key.len() + entry.value.len() + ENTRY_OVERHEAD;
+        if let Some(old) = self.db.insert(key.clone(), entry) {
+            self.used_memory -= key.len() + old.value.len() + ENTRY_OVERHEAD;
+        }
+        self.used_memory += added;
+    }
+
+    /// Bumps the version of an existing key. Returns false if it doesn't exist.
+    pub fn set_version(&mut self, key: &[u8], version: u64) -> bool {
+        self.before_write(key);
+        match self.db.get_mut(key) {
+            Some(entry) => {
+                entry.version = version;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        self.before_write(key);
+        let (idx, _, entry) = self.db.swap_remove_full(key)?;
+        // The last key was moved into `idx`. If that takes it from the part
+        // of the map a running snapshot hasn't reached into the part it has
+        // passed, save it now or it would be skipped.
+        if let Some(capture) = &mut self.capture
+            && idx < capture.cursor
+            && self.db.len() >= capture.cursor
+        {
+            let (moved, moved_entry) = self.db.get_index(idx).unwrap();
+            if !capture.dirty.contains_key(moved) {
+                let expiry = self.ttl_db.get(moved).copied();
+                capture.dirty.insert(moved.clone(), Some((moved_entry.clone(), expiry)));
+            }
+        }
+        self.used_memory -= key.len() + entry.value.len() + ENTRY_OVERHEAD;
+        self.persist(key);
+        Some(entry)
+    }
+
+    pub fn set_expiry(&mut self, key: Bytes, expiry: u64) {
+        self.before_write(&key);
+        // Shares the stored key when there is one.
+        let key = self.db.get_key_value(&key).map_or_else(|| detach(&key), |(stored, _)| stored.clone());
+        match self.ttl_db.insert(key.clone(), expiry) {
+            Some(old) => self.expiry_wheel.cancel(&key, old),
+            None => self.used_memory += 2 * key.len() + TTL_OVERHEAD,
+        }
+        self.expiry_wheel.insert(key, expiry);
+    }
+
+    /// Drops the TTL of a key, if it has one.
+    pub fn persist(&mut self, key: &[u8]) {
+        self.before_write(key);
+        if let Some(expiry) = self.ttl_db.swap_remove(key) {
+            self.expiry_wheel.cancel(key, expiry);
+            self.used_memory -= 2 * key.len() + TTL_OVERHEAD;
+        }
+    }
+
+    /// Removes up to `limit` keys whose TTL has passed. Returns how many
+    /// were removed.
+    pub fn remove_expired(&mut self, now: u64, limit: usize) -> usize {
+        self.expiry_wheel.advance(now);
+        let mut expired_count = 0;
+        while expired_count < limit {
+            let Some(key) = self.expiry_wheel.pop_due() else {
+                break;
+            };
+            self.remove(&key);
+            expired_count += 1;
+        }
+        self.expired += expired_count as u64;
+        expired_count
+    }
+
+    /// True if keys are known to be expired but haven't been removed yet.
+    pub fn has_expired(&self) -> bool {
+        self.expiry_wheel.has_due()
+    }
+
+    /// Starts a point-in-time snapshot that is then written out by calling
+    /// `snapshot_chunk` until it returns true, while the shard keeps serving
+    /// writes. Returns the snapshot's version and key count.
+    pub fn begin_snapshot(&mut self) -> (u64, u64) {
+        self.capture = Some(Capture {
+            cursor: 0,
+            dirty: HashMap::new(),
+        });
+        (self.version, self.db.len() as u64)
+    }
+
+    /// Drops a running snapshot, e.g. after its writer failed.
+    pub fn abort_snapshot(&mut self) {
+        self.capture = None;
+    }
+
+    /// Hands up to `max` keys of the running snapshot to `emit`, as they
+    /// were when it began. Returns true once every key has been emitted.
+    pub fn snapshot_chunk(&mut self, max: usize, mut emit: impl FnMut(&[u8], &Entry, Option<u64>)) -> bool {
+        let Some(capture) = &mut self.capture else {
+            return true;
+        };
+        let end = (capture.cursor + max).min(self.db.len());
+        for (key, entry) in &self.db[capture.cursor..end] {
+            // Changed keys go out at the end, with their saved state.
+            if !capture.dirty.contains_key(key) {
+                emit(key, entry, self.ttl_db.get(key).copied());
+            }
+        }
+        capture.cursor = end;
+        if end < self.db.len() {
+            return false;
+        }
+
+        for (key, state) in self.capture.take().unwrap().dirty {
+            if let Some((entry, expiry)) = state {
+                emit(&key, &entry, expiry);
+            }
+        }
+        true
+    }
+
+    /// Saves the state of `key` for a running snapshot before it changes.
+    fn before_write(&mut self, key: &[u8]) {
+        let Some(capture) = &mut self.capture else {
+            return;
+        };
+        if capture.dirty.contains_key(key) {
+            return;
+        }
+        match self.db.get_full(key) {
+            // Already written out.
+            Some((idx, _, _)) if idx < capture.cursor => {}
+            Some((_, _, entry)) => {
+                let expiry = self.ttl_db.get(key).copied();
+                capture.dirty.insert(Bytes::copy_from_slice(key), Some((entry.clone(), expiry)));
+            }
+            None => {
+                capture.dirty.insert(Bytes::copy_from_slice(key), None);
+            }
+        }
+    }
+
+    /// Chooses a key to evict under `policy` by sampling `samples` random
+    /// candidates, Redis-style. Returns `None` when nothing may be evicted.
+    pub fn pick_victim(&self, policy: EvictionPolicy, samples: usize, now: u64) -> Option<Bytes> {
+        let pool_len = if policy.volatile_only() { self.ttl_db.len() } else { self.db.len() };
+        if policy == EvictionPolicy::NoEviction || pool_len == 0 {
+            return None;
+        }
+
+        let sample = || -> (&Bytes, &Entry, Option<u64>) {
+            let idx = fastrand::usize(..pool_len);
+            if policy.volatile_only() {
+                let (key, expiry) = self.ttl_db.get_index(idx).unwrap();
+                (key, &self.db[key], Some(*expiry))
+            } else {
+                let (key, entry) = self.db.get_index(idx).unwrap();
+                (key, entry, self.expiry(key))
+            }
+        };
+
+        if matches!(policy, EvictionPolicy::AllKeysRandom | EvictionPolicy::VolatileRandom) {
+            return Some(sample().0.clone());
+        }
+
+        // Lower scores are evicted first.
+        let score = |entry: &Entry, expiry: Option<u64>| -> u64 {
+            match policy {
+                EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu => {
+                    decayed_freq(entry, now) as u64
+                }
+                EvictionPolicy::VolatileTtl => expiry.unwrap_or(u64::MAX),
+                _ => entry.access as u64,
+            }
+        };
+
+        (0..samples.max(1))
+            .map(|_| sample())
+            .min_by_key(|(_, entry, expiry)| score(entry, *expiry))
+            .map(|(key, _, _)| key.clone())
+    }
+}
+
+/// Request keys and values are slices of a client's read buffer. Stored on
+/// their own, they don't keep the rest of it alive. This is the one copy a
+/// write makes; it costs a memcpy per write, where sharing the slice could
+/// keep a whole pipelined buffer alive for every small key in it.
+fn detach(bytes: &[u8]) -> Bytes {
+    Bytes::copy_from_slice(bytes)
+}
+
+fn decayed_freq(entry: &Entry, now: u64) -> u8 {
+    let periods = clock_secs(now).saturating_sub(entry.access) / LFU_DECAY_SECS;
+    entry.freq.saturating_sub(periods.min(255) as u8)
+}
+
+fn touch(entry: &mut Entry, now: u64) {
+    let mut freq = decayed_freq(entry, now);
+    if freq < 255 {
+        let base = freq.saturating_sub(LFU_INIT_VAL) as f64;
+        if fastrand::f64() < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+            freq += 1;
+        }
+    }
+    entry.freq = freq;
+    entry.access = clock_secs(now);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::apply::now_ms;
+
+    fn key(name: &str) -> Bytes {
+        Bytes::copy_from_slice(name.as_bytes())
+    }
+
+    // Offsets from `now` rather than absolute times: the timing wheel starts
+    // at the wall clock and walks every millisecond up to the given time.
+    fn fill(now: u64, keys: &[(&str, u64, Option<u64>)]) -> Keyspace {
+        let mut keyspace = Keyspace::default();
+        for &(name, inserted, expiry) in keys {
+            keyspace.insert(key(name), key("value"), 1, now - inserted);
+            if let Some(expiry) = expiry {
+                keyspace.set_expiry(key(name), now + expiry);
+            }
+        }
+        keyspace
+    }
+
+    // Enough samples that every key of a handful is seen.
+    fn victim(keyspace: &Keyspace, policy: EvictionPolicy, now: u64) -> Option<Bytes> {
+        keyspace.pick_victim(policy, 200, now)
+    }
+
+    #[test]
+    fn volatile_ttl_picks_the_soonest_expiry() {
+        let now = now_ms();
+        let keyspace = fill(now, &[("forever", 0, None), ("later", 0, Some(60_000)), ("soon", 0, Some(1_000))]);
+        assert_eq!(victim(&keyspace, EvictionPolicy::VolatileTtl, now), Some(key("soon")));
+    }
+
+    #[test]
+    fn volatile_lru_picks_the_oldest_key_with_a_ttl() {
+        let now = now_ms();
+        let keyspace =
+            fill(now, &[("oldest", 900_000, None), ("old", 600_000, Some(60_000)), ("new", 0, Some(60_000))]);
+        assert_eq!(victim(&keyspace, EvictionPolicy::VolatileLru, now), Some(key("old")));
+        assert_eq!(victim(&keyspace, EvictionPolicy::AllKeysLru, now), Some(key("oldest")));
+        // Nothing to pick from without TTLs.
+        let keyspace = fill(now, &[("a", 0, None)]);
+        assert_eq!(victim(&keyspace, EvictionPolicy::VolatileLru, now), None);
+    }
+
+    #[test]
+    fn allkeys_lfu_picks_the_least_used() {
+        let now = now_ms();
+        let mut keyspace = fill(now, &[("cold", 0, None), ("hot", 0, None)]);
+        for _ in 0..100 {
+            keyspace.get(b"hot", now);
+        }
+        assert_eq!(victim(&keyspace, EvictionPolicy::AllKeysLfu, now), Some(key("cold")));
+    }
+
+    #[test]
+    fn noeviction_never_picks() {
+        let now = now_ms();
+        let keyspace = fill(now, &[("a", 0, None), ("b", 0, Some(1_000))]);
+        assert_eq!(victim(&keyspace, EvictionPolicy::NoEviction, now), None);
+    }
+
+    #[test]
+    fn memory_returns_to_where_it_started() {
+        let now = now_ms();
+        let mut keyspace = Keyspace::default();
+        let start = keyspace.used_memory();
+
+        keyspace.insert(key("k"), key("small"), 1, now);
+        let one = keyspace.used_memory();
+        keyspace.insert(key("k"), Bytes::from(vec![b'v'; 1000]), 2, now);
+        assert_eq!(keyspace.used_memory(), one + 995);
+        keyspace.set_expiry(key("k"), now + 1_000);
+        keyspace.set_expiry(key("k"), now + 2_000);
+        keyspace.remove(b"k");
+        assert_eq!(keyspace.used_memory(), start);
+
+        keyspace.insert(key("t"), key("v"), 3, now);
+        keyspace.set_expiry(key("t"), now + 1_000);
+        keyspace.persist(b"t");
+        keyspace.set_expiry(key("t"), now + 1_000);
+        assert_eq!(keyspace.remove_expired(now + 1_000, 10), 1);
+        assert_eq!(keyspace.used_memory(), start);
+    }
+}
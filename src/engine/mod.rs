@@ -1,11 +1,21 @@
 pub mod apply;
+pub mod backlog;
+pub mod cdc;
 pub mod command;
+pub mod entry;
+pub mod expiry;
+pub mod keyspace;
 pub mod parser;
+pub mod raft;
+pub mod reply;
+pub mod script;
 pub mod snapshot;
 pub mod wal;
 
 pub use apply::apply_db;
-pub use command::{Command, ParsedCommand, WalCommand};
+pub use command::{Command, ParsedCommand, Queued, Stray, WalCommand};
+pub use entry::Entry;
+pub use keyspace::Keyspace;
 pub use parser::parse_command;
-pub use snapshot::save_snapshot;
+pub use reply::Resp;
 pub use wal::{start_engine, start_wal_task};
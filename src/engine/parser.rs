@@ -1,35 +1,482 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use bytes::Bytes;
+
 use super::ParsedCommand;
+use super::command::{AclCommand, ClientCommand, ClientFilter, ClusterCommand, SlotState};
+use crate::cluster::SLOT_COUNT;
+
+/// Splits a request line on whitespace. A token wrapped in double or single
+/// quotes may contain spaces (needed for script bodies). Inside double quotes
+/// `\"` and `\\` stand for `"` and `\`, and any other backslash is kept as
+/// is; single quotes have no escapes. A quote that is never closed is read as
+/// part of a plain token. Tokens borrow from `line`, which may hold any bytes,
+/// unless they had escapes to undo.
+fn tokenize(line: &[u8]) -> Vec<Cow<'_, [u8]>> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_ascii_start();
+    while !rest.is_empty() {
+        let (token, used) = quoted(rest).unwrap_or_else(|| {
+            let end = rest.iter().position(u8::is_ascii_whitespace).unwrap_or(rest.len());
+            (Cow::Borrowed(&rest[..end]), end)
+        });
+        tokens.push(token);
+        rest = rest[used..].trim_ascii_start();
+    }
+    tokens
+}
+
+/// The quoted token `rest` starts with and how many bytes it takes up, or
+/// `None` if `rest` doesn't start with a quote that closes.
+fn quoted(rest: &[u8]) -> Option<(Cow<'_, [u8]>, usize)> {
+    match *rest.first()? {
+        b'"' => {
+            let body = &rest[1..];
+            let mut unescaped: Option<Vec<u8>> = None;
+            let mut i = 0;
+            loop {
+                match *body.get(i)? {
+                    b'"' => break,
+                    b'\\' if matches!(body.get(i + 1), Some(b'"' | b'\\')) => {
+                        unescaped.get_or_insert_with(|| body[..i].to_vec()).push(body[i + 1]);
+                        i += 2;
+                    }
+                    b => {
+                        if let Some(unescaped) = &mut unescaped {
+                            unescaped.push(b);
+                        }
+                        i += 1;
+                    }
+                }
+            }
+            Some((unescaped.map_or(Cow::Borrowed(&body[..i]), Cow::Owned), i + 2))
+        }
+        b'\'' => {
+            let end = rest[1..].iter().position(|&b| b == b'\'')? + 1;
+            Some((Cow::Borrowed(&rest[1..end]), end + 1))
+        }
+        _ => None,
+    }
+}
+
+/// Writes `token` so `tokenize` reads it back unchanged. Anything it could
+/// have read in the first place can be written this way.
+pub fn quote(token: &[u8]) -> Vec<u8> {
+    let mut quoted = Vec::with_capacity(token.len() + 2);
+    let plain = !token.is_empty() && !token.iter().any(u8::is_ascii_whitespace) && !matches!(token[0], b'"' | b'\'');
+    if plain {
+        quoted.extend_from_slice(token);
+    } else if !token.contains(&b'\'') && token.iter().any(|&b| b == b'"' || b == b'\\') {
+        // Single quotes spare the escapes.
+        quoted.push(b'\'');
+        quoted.extend_from_slice(token);
+        quoted.push(b'\'');
+    } else {
+        quoted.push(b'"');
+        for &b in token {
+            if b == b'"' || b == b'\\' {
+                quoted.push(b'\\');
+            }
+            quoted.push(b);
+        }
+        quoted.push(b'"');
+    }
+    quoted
+}
+
+/// A token as `Bytes`: a slice of `line` sharing its memory, or a copy if
+/// `tokenize` had to undo escapes in it.
+fn share(line: &Bytes, token: &[u8]) -> Bytes {
+    let (start, end) = (line.as_ptr() as usize, line.as_ptr() as usize + line.len());
+    let at = token.as_ptr() as usize;
+    if start <= at && at + token.len() <= end {
+        line.slice_ref(token)
+    } else {
+        Bytes::copy_from_slice(token)
+    }
+}
+
+/// Reads a token that has to be text, like a number.
+fn parse<T: FromStr>(token: &[u8]) -> Option<T> {
+    std::str::from_utf8(token).ok()?.parse().ok()
+}
+
+/// Splits `numkeys key... arg...` as used by EVAL and EVALSHA.
+fn keys_and_args(line: &Bytes, numkeys: &[u8], rest: &[&[u8]]) -> Option<(Vec<Bytes>, Vec<Bytes>)> {
+    let numkeys = parse::<usize>(numkeys)?;
+    if numkeys > rest.len() {
+        return None;
+    }
+    let (keys, args) = rest.split_at(numkeys);
+    Some((
+        keys.iter().map(|k| share(line, k)).collect(),
+        args.iter().map(|a| share(line, a)).collect(),
+    ))
+}
+
+fn parse_slot(slot: &str) -> Option<u16> {
+    slot.parse::<u16>().ok().filter(|&s| s < SLOT_COUNT)
+}
+
+/// `MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [KEYS key...]`.
+/// There is only one database, so `destination-db` is ignored.
+fn parse_migrate(
+    line: &Bytes,
+    host: &[u8],
+    port: &[u8],
+    key: &[u8],
+    timeout: &[u8],
+    options: &[&[u8]],
+) -> Option<ParsedCommand> {
+    let host = std::str::from_utf8(host).ok()?;
+    let port = parse::<u16>(port)?;
+    let mut keys = Vec::new();
+    let (mut copy, mut replace) = (false, false);
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            b"COPY" => copy = true,
+            b"REPLACE" => replace = true,
+            b"KEYS" if key.is_empty() => {
+                keys.extend(options.by_ref().map(|k| share(line, k)));
+            }
+            _ => return None,
+        }
+    }
+    if !key.is_empty() {
+        keys.push(share(line, key));
+    }
+    if keys.is_empty() {
+        return None;
+    }
+    Some(ParsedCommand::Migrate {
+        addr: format!("{}:{}", host, port),
+        keys,
+        copy,
+        replace,
+        timeout_ms: parse(timeout)?,
+    })
+}
+
+fn parse_cluster(args: &[&str]) -> Option<ClusterCommand> {
+    let (subcommand, args) = args.split_first()?;
+    Some(match (*subcommand, args) {
+        ("INFO", []) => ClusterCommand::Info,
+        ("MYID", []) => ClusterCommand::MyId,
+        ("NODES", []) => ClusterCommand::Nodes,
+        ("SLOTS", []) => ClusterCommand::Slots,
+        ("SHARDS", []) => ClusterCommand::Shards,
+        ("KEYSLOT", [key]) => ClusterCommand::KeySlot { key: key.to_string() },
+        ("MEET", [host, port]) => ClusterCommand::Meet {
+            addr: format!("{}:{}", host, port.parse::<u16>().ok()?),
+        },
+        ("ADDSLOTS", slots) if !slots.is_empty() => ClusterCommand::AddSlots {
+            slots: slots.iter().map(|s| parse_slot(s)).collect::<Option<_>>()?,
+        },
+        ("ADDSLOTSRANGE", ranges) if !ranges.is_empty() && ranges.len() % 2 == 0 => {
+            let mut slots = Vec::new();
+            for range in ranges.chunks(2) {
+                let (start, end) = (parse_slot(range[0])?, parse_slot(range[1])?);
+                if start > end {
+                    return None;
+                }
+                slots.extend(start..=end);
+            }
+            ClusterCommand::AddSlots { slots }
+        }
+        ("SETSLOT", [slot, rest @ ..]) => ClusterCommand::SetSlot {
+            slot: parse_slot(slot)?,
+            state: match (*rest.first()?, &rest[1..]) {
+                ("IMPORTING", [node]) => SlotState::Importing(node.to_string()),
+                ("MIGRATING", [node]) => SlotState::Migrating(node.to_string()),
+                ("NODE", [node]) => SlotState::Node(node.to_string()),
+                ("STABLE", []) => SlotState::Stable,
+                _ => return None,
+            },
+        },
+        ("GETKEYSINSLOT", [slot, count]) => ClusterCommand::GetKeysInSlot {
+            slot: parse_slot(slot)?,
+            count: count.parse().ok()?,
+        },
+        ("COUNTKEYSINSLOT", [slot]) => ClusterCommand::CountKeysInSlot { slot: parse_slot(slot)? },
+        ("GOSSIP", [sender, payload]) => ClusterCommand::Gossip {
+            sender: sender.to_string(),
+            payload: payload.to_string(),
+        },
+        _ => return None,
+    })
+}
+
+fn parse_client(args: &[&str]) -> Option<ClientCommand> {
+    Some(match args {
+        ["LIST"] => ClientCommand::List,
+        ["INFO"] => ClientCommand::Info,
+        ["ID"] => ClientCommand::Id,
+        ["SETNAME", name] => ClientCommand::SetName { name: name.to_string() },
+        ["GETNAME"] => ClientCommand::GetName,
+        ["KILL", addr] => ClientCommand::Kill {
+            filter: ClientFilter::Addr(addr.to_string()),
+            legacy: true,
+        },
+        ["KILL", "ID", id] => ClientCommand::Kill {
+            filter: ClientFilter::Id(id.parse().ok()?),
+            legacy: false,
+        },
+        ["KILL", "ADDR", addr] => ClientCommand::Kill {
+            filter: ClientFilter::Addr(addr.to_string()),
+            legacy: false,
+        },
+        ["KILL", "USER", user] => ClientCommand::Kill {
+            filter: ClientFilter::User(user.to_string()),
+            legacy: false,
+        },
+        ["PAUSE", timeout, mode @ ..] => ClientCommand::Pause {
+            timeout_ms: timeout.parse().ok()?,
+            writes_only: match mode {
+                [] | ["ALL"] => false,
+                ["WRITE"] => true,
+                _ => return None,
+            },
+        },
+        ["UNPAUSE"] => ClientCommand::Unpause,
+        _ => return None,
+    })
+}
 
-pub fn parse_command(input: &str) -> Option<ParsedCommand> {
-    match input.split_whitespace().collect::<Vec<_>>().as_slice() {
-        ["SET", key, value] => Some(ParsedCommand::Set {
-            key: key.to_string(),
-            value: value.to_string(),
+fn parse_acl(args: &[&str]) -> Option<AclCommand> {
+    Some(match args {
+        ["SETUSER", name, rules @ ..] => AclCommand::SetUser {
+            name: name.to_string(),
+            rules: rules.iter().map(|r| r.to_string()).collect(),
+        },
+        ["GETUSER", name] => AclCommand::GetUser { name: name.to_string() },
+        ["DELUSER", names @ ..] if !names.is_empty() => AclCommand::DelUser {
+            names: names.iter().map(|n| n.to_string()).collect(),
+        },
+        ["LIST"] => AclCommand::List,
+        ["USERS"] => AclCommand::Users,
+        ["WHOAMI"] => AclCommand::WhoAmI,
+        ["CAT"] => AclCommand::Cat { category: None },
+        ["CAT", category] => AclCommand::Cat { category: Some(category.to_lowercase()) },
+        ["LOG"] => AclCommand::Log { count: 10 },
+        ["LOG", "RESET"] => AclCommand::LogReset,
+        ["LOG", count] => AclCommand::Log { count: count.parse().ok()? },
+        ["SAVE"] => AclCommand::Save,
+        ["LOAD"] => AclCommand::Load,
+        _ => return None,
+    })
+}
+
+/// Parses one request line, without its `\n`. Keys and values come out as
+/// slices of `line`, sharing its memory, unless they were quoted with escapes.
+/// Quoted, they may hold any bytes but a newline.
+pub fn parse_command(line: &Bytes) -> Option<ParsedCommand> {
+    let tokens = tokenize(line);
+    let tokens: Vec<&[u8]> = tokens.iter().map(|token| &token[..]).collect();
+    let bytes = |token: &[u8]| share(line, token);
+    match tokens.as_slice() {
+        [b"SET", key, value] => Some(ParsedCommand::Set {
+            key: bytes(key),
+            value: bytes(value),
+        }),
+        [b"SETEX", key, value, ttl] => Some(ParsedCommand::SetEx {
+            key: bytes(key),
+            value: bytes(value),
+            ttl: parse(ttl)?,
+        }),
+        [b"GET", key] => Some(ParsedCommand::Get {
+            key: bytes(key),
+        }),
+        [b"DEL", key] => Some(ParsedCommand::Del {
+            key: bytes(key),
+        }),
+        [b"EX", key] => Some(ParsedCommand::Ex {
+            key: bytes(key),
+        }),
+        [b"EXPIRE", key, ttl] => Some(ParsedCommand::Expire {
+            key: bytes(key),
+            ttl: parse(ttl)?,
+        }),
+        [b"TTL", key] => Some(ParsedCommand::Ttl {
+            key: bytes(key),
+        }),
+        [b"GETVER", key] => Some(ParsedCommand::GetVer {
+            key: bytes(key),
+        }),
+        [b"SETIFVER", key, value, version] => Some(ParsedCommand::SetIfVer {
+            key: bytes(key),
+            value: bytes(value),
+            version: parse(version)?,
+        }),
+        [b"DELIFVER", key, version] => Some(ParsedCommand::DelIfVer {
+            key: bytes(key),
+            version: parse(version)?,
+        }),
+        [b"EVAL", script, numkeys, rest @ ..] => {
+            let (keys, args) = keys_and_args(line, numkeys, rest)?;
+            Some(ParsedCommand::Eval {
+                script: String::from_utf8(script.to_vec()).ok()?,
+                keys,
+                args,
+            })
+        }
+        [b"EVALSHA", sha, numkeys, rest @ ..] => {
+            let (keys, args) = keys_and_args(line, numkeys, rest)?;
+            Some(ParsedCommand::EvalSha {
+                sha: parse(sha)?,
+                keys,
+                args,
+            })
+        }
+        [b"MIGRATE", host, port, key, _db, timeout, options @ ..] => {
+            parse_migrate(line, host, port, key, timeout, options)
+        }
+        [b"RESTORE", key, ttl_ms, value] => Some(ParsedCommand::Restore {
+            key: bytes(key),
+            value: bytes(value),
+            ttl_ms: parse(ttl_ms)?,
+            replace: false,
+        }),
+        [b"RESTORE", key, ttl_ms, value, b"REPLACE"] => Some(ParsedCommand::Restore {
+            key: bytes(key),
+            value: bytes(value),
+            ttl_ms: parse(ttl_ms)?,
+            replace: true,
+        }),
+        // Everything else is made of names and numbers.
+        tokens => {
+            let tokens = tokens.iter().map(|t| std::str::from_utf8(t).ok()).collect::<Option<Vec<_>>>()?;
+            parse_text(&tokens)
+        }
+    }
+}
+
+fn parse_text(tokens: &[&str]) -> Option<ParsedCommand> {
+    match tokens {
+        ["PING"] => Some(ParsedCommand::Ping),
+        ["SCRIPT", "LOAD", script] => Some(ParsedCommand::ScriptLoad {
+            script: script.to_string(),
         }),
-        ["SETEX", key, value, ttl] => Some(ParsedCommand::SetEx {
-            key: key.to_string(),
-            value: value.to_string(),
-            ttl: ttl.parse::<u64>().unwrap(),
+        ["SCRIPT", "EXISTS", shas @ ..] if !shas.is_empty() => Some(ParsedCommand::ScriptExists {
+            shas: shas.iter().map(|s| s.to_string()).collect(),
         }),
-        ["GET", key] => Some(ParsedCommand::Get {
-            key: key.to_string(),
+        ["SCRIPT", "FLUSH"] => Some(ParsedCommand::ScriptFlush),
+        ["SCRIPT", "KILL"] => Some(ParsedCommand::ScriptKill),
+        ["SAVE"] => Some(ParsedCommand::Save),
+        ["BGSAVE"] => Some(ParsedCommand::BgSave),
+        ["LASTSAVE"] => Some(ParsedCommand::LastSave),
+        ["PSYNC", shard, replid, lsn, port] => Some(ParsedCommand::Psync {
+            shard: shard.parse().ok()?,
+            replid: replid.to_string(),
+            lsn: lsn.parse().ok()?,
+            port: port.parse().ok()?,
         }),
-        ["DEL", key] => Some(ParsedCommand::Del {
-            key: key.to_string(),
+        ["REPLICAOF", "NO", "ONE"] => Some(ParsedCommand::ReplicaOf { primary: None }),
+        ["REPLICAOF", host, port] => Some(ParsedCommand::ReplicaOf {
+            primary: Some((host.to_string(), port.parse().ok()?)),
         }),
-        ["EX", key] => Some(ParsedCommand::Ex {
-            key: key.to_string(),
+        ["ROLE"] => Some(ParsedCommand::Role),
+        ["LATENCY", "HISTOGRAM", commands @ ..] => Some(ParsedCommand::LatencyHistogram {
+            commands: commands.iter().map(|c| c.to_string()).collect(),
         }),
-        ["EXPIRE", key, ttl] => Some(ParsedCommand::Expire {
-            key: key.to_string(),
-            ttl: ttl.parse::<u64>().unwrap(),
+        ["LATENCY", "RESET"] => Some(ParsedCommand::LatencyReset),
+        ["SLOWLOG", "GET"] => Some(ParsedCommand::SlowlogGet { count: 10 }),
+        ["SLOWLOG", "GET", count] => Some(ParsedCommand::SlowlogGet { count: count.parse().ok()? }),
+        ["SLOWLOG", "LEN"] => Some(ParsedCommand::SlowlogLen),
+        ["SLOWLOG", "RESET"] => Some(ParsedCommand::SlowlogReset),
+        ["INFO"] => Some(ParsedCommand::Info { section: None }),
+        ["INFO", section] => Some(ParsedCommand::Info {
+            section: Some(section.to_lowercase()),
         }),
-        ["TTL", key] => Some(ParsedCommand::Ttl {
-            key: key.to_string(),
+        ["RAFT", "LINK"] => Some(ParsedCommand::RaftLink),
+        ["RAFT", "ADD", node] => Some(ParsedCommand::RaftChange {
+            add: true,
+            node: node.to_string(),
         }),
-        ["PING"] => Some(ParsedCommand::Ping {
-            
+        ["RAFT", "REMOVE", node] => Some(ParsedCommand::RaftChange {
+            add: false,
+            node: node.to_string(),
+        }),
+        ["CLUSTER", args @ ..] => parse_cluster(args).map(ParsedCommand::Cluster),
+        ["CLIENT", args @ ..] => parse_client(args).map(ParsedCommand::Client),
+        ["AUTH", password] => Some(ParsedCommand::Auth {
+            user: None,
+            password: password.to_string(),
+        }),
+        ["AUTH", user, password] => Some(ParsedCommand::Auth {
+            user: Some(user.to_string()),
+            password: password.to_string(),
+        }),
+        ["ACL", args @ ..] => parse_acl(args).map(ParsedCommand::Acl),
+        ["ASKING"] => Some(ParsedCommand::Asking),
+        ["CDC", "SUBSCRIBE", positions @ ..] if !positions.is_empty() => Some(ParsedCommand::CdcSubscribe {
+            positions: positions
+                .iter()
+                .map(|p| if *p == "$" { Some(None) } else { p.parse().ok().map(Some) })
+                .collect::<Option<_>>()?,
         }),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: impl AsRef<[u8]>) -> Vec<Vec<u8>> {
+        tokenize(line.as_ref()).into_iter().map(Cow::into_owned).collect()
+    }
+
+    #[test]
+    fn quotes_group_and_escapes_undo() {
+        assert_eq!(tokens("SET a  b"), vec![b"SET".to_vec(), b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(tokens(r#"SET a "b c""#)[2], b"b c");
+        assert_eq!(tokens(r#"SET a 'say "hi"'"#)[2], br#"say "hi""#);
+        assert_eq!(tokens(r#"SET a "say \"hi\" \\o/""#)[2], br#"say "hi" \o/"#);
+        // Other backslashes, like in a Lua script, and single quotes are left alone.
+        assert_eq!(tokens(r#"EVAL "return 'a\nb'" 0"#)[1], br"return 'a\nb'");
+        assert_eq!(tokens(r"SET a 'b\'")[2], br"b\");
+    }
+
+    #[test]
+    fn unclosed_quotes_are_plain_text() {
+        assert_eq!(tokens(r#"SET a "b"#), vec![b"SET".to_vec(), b"a".to_vec(), br#""b"#.to_vec()]);
+        assert_eq!(tokens("SET a 'b")[2], b"'b");
+        assert_eq!(tokens(r#"SET a "b\""#)[2], br#""b\""#);
+        assert_eq!(tokens(r#"SET a "b c"#), vec![b"SET".to_vec(), b"a".to_vec(), br#""b"#.to_vec(), b"c".to_vec()]);
+        assert_eq!(tokens(r#"SET a '' "#)[2], b"");
+    }
+
+    #[test]
+    fn quoted_tokens_read_back_unchanged() {
+        let samples: [&[u8]; 10] = [
+            b"plain",
+            b"",
+            b"two words",
+            br#""starts with a quote"#,
+            b"'single'",
+            br#"it's "both" kinds"#,
+            br"back\slash",
+            br"ends in \",
+            b"tab\tand\rcr",
+            &[0, 0xff, b' ', 0x80],
+        ];
+        for sample in samples {
+            let mut line = b"SET ".to_vec();
+            line.extend_from_slice(&quote(sample));
+            assert_eq!(tokens(&line), vec![b"SET".to_vec(), sample.to_vec()], "{:?}", String::from_utf8_lossy(&line));
+        }
+    }
+
+    #[test]
+    fn tokens_share_the_line_unless_unescaped() {
+        let line = Bytes::from_static(br#"SET "k" "v\"1""#);
+        let Some(ParsedCommand::Set { key, value }) = parse_command(&line) else {
+            panic!("not a SET");
+        };
+        assert_eq!((&key[..], &value[..]), (&b"k"[..], &br#"v"1"#[..]));
+        assert!(line.as_ptr_range().contains(&key.as_ptr()));
+        assert!(!line.as_ptr_range().contains(&value.as_ptr()));
+    }
+}
@@ -0,0 +1,1237 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::task;
+use tokio::time::Instant;
+use tracing::error;
+
+use super::command::WalRecord;
+use super::snapshot::snapshot_path;
+use super::wal::{closed_segments, segment_path};
+use super::{Command, Keyspace, ParsedCommand, Resp, WalCommand, apply_db};
+use crate::engine::apply::now_ms;
+use crate::raft::RaftNet;
+
+// Raft segments reuse the WAL files under their own magic, so a standalone
+// WAL is never mistaken for a Raft log.
+pub const RAFT_MAGIC: &[u8; 4] = b"CKR1";
+
+pub const RAFT_TICK: Duration = Duration::from_millis(50);
+const HEARTBEAT_PERIOD: Duration = Duration::from_millis(100);
+// Milliseconds a follower waits without hearing from a leader before it
+// campaigns, picked at random from this range each time.
+const ELECTION_TIMEOUT_MS: Range<u64> = 500..1000;
+const MAX_APPEND_ENTRIES: usize = 1024;
+// How long after a majority last answered it the leader keeps serving
+// reads. Followers don't vote for anyone else within the shortest election
+// timeout of hearing from it; the margin is for clocks running at
+// slightly different rates.
+const LEASE_MS: u64 = ELECTION_TIMEOUT_MS.start * 9 / 10;
+// A follower that still lacks the snapshot after this long is sent it again.
+const SNAPSHOT_RETRY: Duration = Duration::from_secs(5);
+
+const LOST_WRITE: &str = "TRYAGAIN Leadership changed before the write committed\n";
+// For writes this node can no longer follow up on, which may or may not
+// have committed.
+const MAYBE_LOST: &str = "ERR write may have been lost, retry\n";
+
+/// A write as it goes through the log. Conditions are checked when the
+/// entry is applied, so every node comes to the same outcome.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum RaftOp {
+    /// Appended by a new leader so entries from earlier terms can commit.
+    Noop,
+    /// The voters from this entry on.
+    Members(Vec<String>),
+    Set { key: Bytes, value: Bytes },
+    SetEx { key: Bytes, value: Bytes, ttl: u64 },
+    Del { key: Bytes },
+    Expire { key: Bytes, ttl: u64 },
+    SetIfVer { key: Bytes, value: Bytes, version: u64 },
+    DelIfVer { key: Bytes, version: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RaftEntry {
+    pub term: u64,
+    pub op: RaftOp,
+}
+
+/// A log entry; `lsn` is its index. Every entry bumps the shard version
+/// when applied, so the version is always the index last applied.
+pub type RaftRecord = WalRecord<RaftEntry>;
+
+/// What the nodes of a shard's group send each other.
+#[derive(Serialize, Deserialize)]
+pub enum RaftMsg {
+    Vote {
+        term: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    VoteReply {
+        term: u64,
+        granted: bool,
+    },
+    Append {
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<RaftRecord>,
+        commit: u64,
+        /// When the leader sent this, in milliseconds since it took over.
+        sent: u64,
+    },
+    /// `index` is the last entry the follower now shares with the leader,
+    /// or on failure where the leader should back up to. `sent` is echoed
+    /// from the `Append` answered, 0 for a snapshot.
+    AppendReply {
+        term: u64,
+        success: bool,
+        index: u64,
+        sent: u64,
+    },
+    /// A snapshot file taken at `index`, for a follower too far behind to
+    /// catch up from the log. Answered with an `AppendReply`.
+    Snapshot {
+        term: u64,
+        index: u64,
+        index_term: u64,
+        members: Vec<String>,
+        data: Vec<u8>,
+    },
+    /// A membership change passed on to the leader.
+    Change {
+        add: bool,
+        node: String,
+    },
+}
+
+/// Kept in `raft_{id}.meta` and synced before it is acted on.
+#[derive(Serialize, Deserialize, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<String>,
+    /// Where the snapshot file leaves off; the log holds what comes after.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_members: Vec<String>,
+}
+
+enum Role {
+    Follower,
+    Candidate { votes: HashSet<String> },
+    /// `since` is when it took over; `Append`s are stamped relative to it.
+    Leader { peers: HashMap<String, Progress>, since: Instant },
+}
+
+struct Progress {
+    /// Next entry to send; moved on as soon as entries are sent.
+    next: u64,
+    /// Last entry known to be on the follower.
+    matched: u64,
+    /// `sent` of the latest `Append` the follower answered.
+    acked: Option<u64>,
+    snapshot_sent: Option<Instant>,
+}
+
+/// One shard's member of its Raft group, driven by the engine loop.
+pub struct RaftNode {
+    shard_id: usize,
+    net: Arc<RaftNet>,
+    state: HardState,
+    /// The entries after `state.snapshot_index`.
+    log: VecDeque<RaftRecord>,
+    /// Last entry known to be synced to disk. Entries after it don't count
+    /// towards a commit here and aren't acknowledged to the leader.
+    durable: u64,
+    members: Vec<String>,
+    /// Index of the entry `members` came from.
+    members_index: u64,
+    commit: u64,
+    role: Role,
+    leader: Option<String>,
+    last_heartbeat: Instant,
+    /// When the leader was last heard from.
+    last_contact: Instant,
+    election_deadline: Instant,
+    /// Clients waiting for the entry at an index, with the term it was
+    /// proposed in. A different term there means the write was lost.
+    waiters: HashMap<u64, (u64, Resp)>,
+    /// Timestamp of the last entry applied. Keys expire by this clock, so a
+    /// follower whose clock runs ahead can't drop a key the leader still has.
+    clock: u64,
+}
+
+impl RaftNode {
+    /// Restores the node from `raft_{id}.meta` and the log segments, on top
+    /// of a snapshot at `version`. Nothing is applied until a leader says
+    /// it is committed.
+    pub async fn load(shard_id: usize, net: Arc<RaftNet>, version: u64) -> Self {
+        let mut state: HardState = fs::read(meta_path(shard_id))
+            .ok()
+            .and_then(|data| bincode::deserialize(&data).ok())
+            .unwrap_or_default();
+
+        // Later records replace everything from their index on, the same
+        // way appends that conflicted with the leader truncated the log.
+        let mut records = BTreeMap::new();
+        let mut segments: Vec<_> = closed_segments(shard_id).await.into_iter().map(|(_, path)| path).collect();
+        segments.push(segment_path(shard_id).into());
+        for path in segments {
+            let Ok(data) = tokio::fs::read(&path).await else {
+                continue;
+            };
+            for record in decode_segment(&data) {
+                records.split_off(&record.lsn);
+                records.insert(record.lsn, record);
+            }
+        }
+
+        // The snapshot file gets written before the meta file, so after a
+        // crash in between the log still has the entries to tell us about it.
+        if version != state.snapshot_index {
+            state.snapshot_index = version;
+            state.snapshot_term = records.get(&version).map_or(0, |r: &RaftRecord| r.entry.term);
+            if let Some(members) = records.range(..=version).rev().find_map(|(_, r)| members_of(r)) {
+                state.snapshot_members = members.clone();
+            }
+        }
+        let log: VecDeque<RaftRecord> = records
+            .into_values()
+            .skip_while(|r| r.lsn <= version)
+            .enumerate()
+            .take_while(|(i, r)| r.lsn == version + 1 + *i as u64)
+            .map(|(_, r)| r)
+            .collect();
+
+        let durable = version + log.len() as u64;
+        let mut node = Self {
+            shard_id,
+            net,
+            state,
+            log,
+            durable,
+            members: Vec::new(),
+            members_index: 0,
+            commit: version,
+            role: Role::Follower,
+            leader: None,
+            last_heartbeat: Instant::now(),
+            last_contact: Instant::now(),
+            election_deadline: Instant::now(),
+            waiters: HashMap::new(),
+            clock: 0,
+        };
+        node.reload_members();
+        node.reset_election_timer();
+        node
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.state.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.back().map_or(self.state.snapshot_term, |r| r.entry.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.state.snapshot_index {
+            Some(self.state.snapshot_term)
+        } else if index > self.state.snapshot_index && index <= self.last_index() {
+            Some(self.record(index).entry.term)
+        } else {
+            None
+        }
+    }
+
+    fn record(&self, index: u64) -> &RaftRecord {
+        &self.log[(index - self.state.snapshot_index - 1) as usize]
+    }
+
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    fn me(&self) -> &str {
+        self.net.me()
+    }
+
+    fn majority(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    /// The reply for commands sent to a node that isn't the shard's leader.
+    fn redirect(&self) -> String {
+        match &self.leader {
+            Some(leader) => format!("NOTLEADER {}\n", leader),
+            None => "TRYAGAIN No leader for this shard yet\n".into(),
+        }
+    }
+
+    /// Takes client commands in Raft mode. Writes go into the log and are
+    /// answered once applied; only the leader serves keys. Gives back what
+    /// the engine should run as usual.
+    pub async fn handle(&mut self, cmd: Command, full: bool, wal_tx: &Sender<WalCommand>) -> Option<Command> {
+        if matches!(
+            cmd,
+            Command::ScriptFlush { .. }
+                | Command::Save { .. }
+                | Command::LastSave { .. }
+                | Command::ReplOffset { .. }
+                | Command::Raft { .. }
+                | Command::RaftChange { .. }
+                | Command::RaftInfo { .. }
+                | Command::Strays { .. }
+        ) {
+            return Some(cmd);
+        }
+        if matches!(
+            cmd,
+            Command::Psync { .. } | Command::ApplyRecords { .. } | Command::LoadSnapshot { .. }
+                | Command::Restore { .. }
+                | Command::CdcSubscribe { .. }
+        ) {
+            cmd.reject("ERR not available in raft mode\n");
+            return None;
+        }
+        if !self.is_leader() {
+            cmd.reject(&self.redirect());
+            return None;
+        }
+        if full && cmd.may_grow_memory() {
+            cmd.reject(super::wal::OOM_ERROR);
+            return None;
+        }
+
+        let (op, resp) = match cmd {
+            Command::Set { key, value, resp } => (RaftOp::Set { key, value }, resp),
+            Command::SetEx { key, value, ttl, resp } => (RaftOp::SetEx { key, value, ttl }, resp),
+            Command::Del { key, resp } => (RaftOp::Del { key }, resp),
+            Command::Expire { key, ttl, resp } => (RaftOp::Expire { key, ttl }, resp),
+            Command::SetIfVer { key, value, version, resp } => (RaftOp::SetIfVer { key, value, version }, resp),
+            Command::DelIfVer { key, version, resp } => (RaftOp::DelIfVer { key, version }, resp),
+            Command::Eval { resp, .. } => {
+                let _ = resp.send("ERR scripts are not supported in raft mode\n".into());
+                return None;
+            }
+            Command::Ping { resp } => return Some(Command::Ping { resp }),
+            read => {
+                if !self.can_read() {
+                    // A round of heartbeats renews the lease if we're still leading.
+                    for peer in self.peers() {
+                        self.send_append(&peer);
+                    }
+                    read.reject("TRYAGAIN Leadership not confirmed yet\n");
+                    return None;
+                }
+                return Some(read);
+            }
+        };
+        self.propose(op, Some(resp), wal_tx).await;
+        None
+    }
+
+    /// Appends `op` to the log as leader and starts replicating it.
+    async fn propose(&mut self, op: RaftOp, resp: Option<Resp>, wal_tx: &Sender<WalCommand>) {
+        let index = self.last_index() + 1;
+        // Entry timestamps never go back, or a key could expire and return.
+        let timestamp = now_ms().max(self.log.back().map_or(self.clock, |r| r.timestamp));
+        let term = self.state.term;
+        if let Some(resp) = resp {
+            self.waiters.insert(index, (term, resp));
+        }
+        self.append(vec![RaftRecord { lsn: index, timestamp, entry: RaftEntry { term, op } }], wal_tx).await;
+        self.advance_commit();
+        for peer in self.peers() {
+            self.send_append(&peer);
+        }
+    }
+
+    /// Logs records that follow on from the end of the log. They are only
+    /// durable once `sync` has run.
+    async fn append(&mut self, records: Vec<RaftRecord>, wal_tx: &Sender<WalCommand>) {
+        let mut encoded = Vec::new();
+        for record in &records {
+            bincode::serialize_into(&mut encoded, record).unwrap();
+        }
+        let _ = wal_tx.send(WalCommand::Write(encoded)).await;
+
+        for record in records {
+            let members = members_of(&record).cloned();
+            self.log.push_back(record);
+            // A new membership counts as soon as it is in the log.
+            if let Some(members) = members {
+                self.set_members(members, self.last_index());
+            }
+        }
+    }
+
+    /// Waits for the log to reach the disk, then counts it as this node's
+    /// towards a commit. Called once per batch of appends, so one sync
+    /// covers all of them.
+    pub async fn sync(&mut self, wal_tx: &Sender<WalCommand>) {
+        let last_index = self.last_index();
+        if self.durable >= last_index {
+            return;
+        }
+        let (done, synced) = oneshot::channel();
+        if wal_tx.send(WalCommand::Sync(done)).await.is_err() || synced.await.is_err() {
+            return;
+        }
+        self.durable = last_index;
+        self.advance_commit();
+    }
+
+    fn set_members(&mut self, members: Vec<String>, index: u64) {
+        let last_index = self.last_index();
+        if let Role::Leader { peers, .. } = &mut self.role {
+            peers.retain(|peer, _| members.contains(peer));
+            for peer in &members {
+                if peer != self.net.me() && !peers.contains_key(peer) {
+                    peers.insert(peer.clone(), Progress::new(last_index));
+                }
+            }
+        }
+        self.members = members;
+        self.members_index = index;
+    }
+
+    /// Finds the latest membership after the log changed under it.
+    fn reload_members(&mut self) {
+        let (members, index) = match self.log.iter().rev().find_map(|r| members_of(r).map(|m| (m.clone(), r.lsn))) {
+            Some(found) => found,
+            None if !self.state.snapshot_members.is_empty() => {
+                (self.state.snapshot_members.clone(), self.state.snapshot_index)
+            }
+            None => (self.net.bootstrap().to_vec(), 0),
+        };
+        self.set_members(members, index);
+    }
+
+    fn peers(&self) -> Vec<String> {
+        match &self.role {
+            Role::Leader { peers, .. } => peers.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Runs timers: heartbeats on the leader, elections everywhere else.
+    pub async fn tick(&mut self, wal_tx: &Sender<WalCommand>) {
+        if self.is_leader() {
+            if self.last_heartbeat.elapsed() >= HEARTBEAT_PERIOD {
+                self.last_heartbeat = Instant::now();
+                for peer in self.peers() {
+                    self.send_append(&peer);
+                }
+            }
+        } else if Instant::now() >= self.election_deadline {
+            self.reset_election_timer();
+            // Nodes waiting to be added don't get a say yet.
+            if self.members.iter().any(|m| m == self.me()) {
+                self.campaign(wal_tx).await;
+            }
+        }
+    }
+
+    async fn campaign(&mut self, wal_tx: &Sender<WalCommand>) {
+        self.state.term += 1;
+        self.state.voted_for = Some(self.me().to_string());
+        self.save_state();
+        self.leader = None;
+        self.role = Role::Candidate { votes: HashSet::from([self.me().to_string()]) };
+        if self.majority() == 1 {
+            self.become_leader(wal_tx).await;
+            return;
+        }
+        let vote = || RaftMsg::Vote { term: self.state.term, last_index: self.last_index(), last_term: self.last_term() };
+        for member in &self.members {
+            if member != self.me() {
+                self.net.send(member, self.shard_id, &vote());
+            }
+        }
+    }
+
+    async fn become_leader(&mut self, wal_tx: &Sender<WalCommand>) {
+        let last_index = self.last_index();
+        let peers = self
+            .members
+            .iter()
+            .filter(|m| *m != self.me())
+            .map(|m| (m.clone(), Progress::new(last_index)))
+            .collect();
+        self.role = Role::Leader { peers, since: Instant::now() };
+        self.leader = Some(self.me().to_string());
+        self.last_heartbeat = Instant::now();
+        self.propose(RaftOp::Noop, None, wal_tx).await;
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<&str>) {
+        if term > self.state.term {
+            self.state.term = term;
+            self.state.voted_for = None;
+            self.save_state();
+            self.leader = None;
+        }
+        if !matches!(self.role, Role::Follower) {
+            self.step_down();
+        }
+        if let Some(leader) = leader {
+            self.leader = Some(leader.to_string());
+            self.last_contact = Instant::now();
+            self.reset_election_timer();
+        }
+    }
+
+    /// Gives up leading. Clients waiting on entries that haven't committed
+    /// yet are told so, since the new leader may discard them.
+    fn step_down(&mut self) {
+        self.role = Role::Follower;
+        let commit = self.commit;
+        self.fail_waiters(|index| index > commit, MAYBE_LOST);
+    }
+
+    /// Answers the clients waiting on the entries `lost` picks with `reply`.
+    fn fail_waiters(&mut self, lost: impl Fn(u64) -> bool, reply: &'static str) {
+        let lost: Vec<u64> = self.waiters.keys().copied().filter(|&i| lost(i)).collect();
+        for index in lost {
+            if let Some((_, resp)) = self.waiters.remove(&index) {
+                let _ = resp.send(reply.into());
+            }
+        }
+    }
+
+    fn reset_election_timer(&mut self) {
+        let timeout = Duration::from_millis(fastrand::u64(ELECTION_TIMEOUT_MS));
+        self.election_deadline = Instant::now() + timeout;
+    }
+
+    /// True while a leader has been heard from within the shortest election
+    /// timeout. Votes are ignored then, so a node that was removed from the
+    /// group and keeps campaigning can't depose a working leader, and a
+    /// leader's read lease holds. A node that just started counts as having
+    /// heard from one, since it may have answered a leader before it went down.
+    fn leader_alive(&self) -> bool {
+        self.is_leader() || self.last_contact.elapsed() < Duration::from_millis(ELECTION_TIMEOUT_MS.start)
+    }
+
+    /// Handles a message from another node of the group. Snapshots go
+    /// through `accept_snapshot` and `installed` instead.
+    pub async fn step(&mut self, from: &str, msg: RaftMsg, wal_tx: &Sender<WalCommand>) {
+        match msg {
+            RaftMsg::Vote { term, last_index, last_term } => {
+                if term > self.state.term && self.leader_alive() {
+                    return;
+                }
+                if term > self.state.term {
+                    self.become_follower(term, None);
+                }
+                let up_to_date = (last_term, last_index) >= (self.last_term(), self.last_index());
+                let granted = term == self.state.term
+                    && up_to_date
+                    && self.state.voted_for.as_deref().is_none_or(|v| v == from);
+                if granted {
+                    self.state.voted_for = Some(from.to_string());
+                    self.save_state();
+                    self.reset_election_timer();
+                }
+                self.send(from, RaftMsg::VoteReply { term: self.state.term, granted });
+            }
+            RaftMsg::VoteReply { term, granted } => {
+                if term > self.state.term {
+                    self.become_follower(term, None);
+                    return;
+                }
+                let majority = self.majority();
+                let Role::Candidate { votes } = &mut self.role else {
+                    return;
+                };
+                if term == self.state.term && granted && self.members.iter().any(|m| m == from) {
+                    votes.insert(from.to_string());
+                    if votes.len() >= majority {
+                        self.become_leader(wal_tx).await;
+                    }
+                }
+            }
+            RaftMsg::Append { term, prev_index, prev_term, entries, commit, sent } => {
+                if term < self.state.term {
+                    let index = self.last_index();
+                    self.send(from, RaftMsg::AppendReply { term: self.state.term, success: false, index, sent });
+                    return;
+                }
+                self.become_follower(term, Some(from));
+                self.append_entries(from, (prev_index, prev_term), entries, commit, sent, wal_tx).await;
+            }
+            RaftMsg::AppendReply { term, success, index, sent } => {
+                if term > self.state.term {
+                    self.become_follower(term, None);
+                    return;
+                }
+                let Role::Leader { peers, .. } = &mut self.role else {
+                    return;
+                };
+                let Some(progress) = peers.get_mut(from) else {
+                    return;
+                };
+                if term != self.state.term {
+                    return;
+                }
+                progress.acked = progress.acked.max(Some(sent));
+                if success {
+                    progress.matched = progress.matched.max(index);
+                    progress.next = progress.next.max(index + 1);
+                    self.advance_commit();
+                } else {
+                    progress.next = progress.next.min(index + 1).max(progress.matched + 1);
+                }
+                if !success || self.peer_next(from) <= self.last_index() {
+                    self.send_append(from);
+                }
+            }
+            RaftMsg::Change { add, node } => {
+                if self.is_leader() {
+                    self.change(add, node, wal_tx).await;
+                }
+            }
+            RaftMsg::Snapshot { .. } => {}
+        }
+    }
+
+    async fn append_entries(
+        &mut self,
+        from: &str,
+        (mut prev_index, mut prev_term): (u64, u64),
+        mut entries: Vec<RaftRecord>,
+        commit: u64,
+        sent: u64,
+        wal_tx: &Sender<WalCommand>,
+    ) {
+        // What the snapshot covers is committed, so it matches any leader.
+        if prev_index < self.state.snapshot_index {
+            let covered = (self.state.snapshot_index - prev_index) as usize;
+            entries.drain(..covered.min(entries.len()));
+            prev_index = self.state.snapshot_index;
+            prev_term = self.state.snapshot_term;
+        }
+        if self.term_at(prev_index) != Some(prev_term) {
+            let hint = prev_index.saturating_sub(1).min(self.last_index());
+            self.send(from, RaftMsg::AppendReply { term: self.state.term, success: false, index: hint, sent });
+            return;
+        }
+
+        let last = prev_index + entries.len() as u64;
+        let mut new = Vec::new();
+        for record in entries {
+            if new.is_empty() {
+                match self.term_at(record.lsn) {
+                    Some(term) if term == record.entry.term => continue,
+                    Some(_) => self.truncate(record.lsn),
+                    None => {}
+                }
+            }
+            new.push(record);
+        }
+        if !new.is_empty() {
+            self.append(new, wal_tx).await;
+        }
+        // The leader counts what we acknowledge towards its commits, so it
+        // has to be on disk first.
+        self.sync(wal_tx).await;
+        if self.durable < last {
+            return;
+        }
+        if commit > self.commit {
+            self.commit = commit.min(last);
+        }
+        self.send(from, RaftMsg::AppendReply { term: self.state.term, success: true, index: last, sent });
+    }
+
+    /// Drops the entries from `index` on, which a new leader overwrote.
+    fn truncate(&mut self, index: u64) {
+        self.log.truncate((index - self.state.snapshot_index - 1) as usize);
+        self.durable = self.durable.min(index - 1);
+        self.fail_waiters(|i| i >= index, LOST_WRITE);
+        if self.members_index >= index {
+            self.reload_members();
+        }
+    }
+
+    fn peer_next(&self, peer: &str) -> u64 {
+        match &self.role {
+            Role::Leader { peers, .. } => peers.get(peer).map_or(u64::MAX, |p| p.next),
+            _ => u64::MAX,
+        }
+    }
+
+    /// Sends `peer` the entries from where it is at, or the snapshot if the
+    /// log no longer has them. Doubles as a heartbeat.
+    fn send_append(&mut self, peer: &str) {
+        let (term, commit, snapshot_index, last_index) =
+            (self.state.term, self.commit, self.state.snapshot_index, self.last_index());
+        let next = self.peer_next(peer);
+        if next == u64::MAX {
+            return;
+        }
+        if next <= snapshot_index {
+            self.send_snapshot(peer);
+            return;
+        }
+        let prev_index = next - 1;
+        let prev_term = self.term_at(prev_index).unwrap_or(0);
+        let count = (last_index + 1 - next).min(MAX_APPEND_ENTRIES as u64);
+        let entries: Vec<RaftRecord> = (next..next + count).map(|i| self.record(i).clone()).collect();
+        let Role::Leader { peers, since } = &mut self.role else {
+            return;
+        };
+        if let Some(progress) = peers.get_mut(peer) {
+            progress.next += count;
+        }
+        let sent = since.elapsed().as_millis() as u64;
+        self.send(peer, RaftMsg::Append { term, prev_index, prev_term, entries, commit, sent });
+    }
+
+    fn send_snapshot(&mut self, peer: &str) {
+        let Role::Leader { peers, .. } = &mut self.role else {
+            return;
+        };
+        let Some(progress) = peers.get_mut(peer) else {
+            return;
+        };
+        if progress.snapshot_sent.is_some_and(|sent| sent.elapsed() < SNAPSHOT_RETRY) {
+            return;
+        }
+        // Opened here so a newer snapshot replacing the file can't end up
+        // paired with this one's index.
+        let Ok(mut file) = File::open(snapshot_path(self.shard_id)) else {
+            return;
+        };
+        progress.snapshot_sent = Some(Instant::now());
+        let (net, peer, shard_id) = (self.net.clone(), peer.to_string(), self.shard_id);
+        let (term, index, index_term, members) = (
+            self.state.term,
+            self.state.snapshot_index,
+            self.state.snapshot_term,
+            self.state.snapshot_members.clone(),
+        );
+        task::spawn_blocking(move || {
+            let mut data = Vec::new();
+            if file.read_to_end(&mut data).is_ok() {
+                net.send(&peer, shard_id, &RaftMsg::Snapshot { term, index, index_term, members, data });
+            }
+        });
+    }
+
+    /// Leader: whether the keyspace is up to date enough to serve reads
+    /// from. A majority must have answered an `Append` sent within
+    /// `LEASE_MS`, so no other leader can have been elected since, and an
+    /// entry of this term must have committed, so every write acknowledged
+    /// by an earlier leader has been applied.
+    fn can_read(&self) -> bool {
+        let Role::Leader { peers, since } = &self.role else {
+            return false;
+        };
+        if self.members.is_empty() || self.term_at(self.commit) != Some(self.state.term) {
+            return false;
+        }
+        let now = since.elapsed().as_millis() as u64;
+        let mut acked: Vec<Option<u64>> = self
+            .members
+            .iter()
+            .map(|m| if m == self.me() { Some(now) } else { peers.get(m).and_then(|p| p.acked) })
+            .collect();
+        acked.sort_unstable_by(|a, b| b.cmp(a));
+        acked[self.majority() - 1].is_some_and(|sent| now < sent + LEASE_MS)
+    }
+
+    /// Leader: commits the highest entry of this term that a majority has.
+    fn advance_commit(&mut self) {
+        let Role::Leader { peers, .. } = &self.role else {
+            return;
+        };
+        if self.members.is_empty() {
+            return;
+        }
+        let mut matched: Vec<u64> = self
+            .members
+            .iter()
+            .map(|m| if m == self.me() { self.durable } else { peers.get(m).map_or(0, |p| p.matched) })
+            .collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.majority() - 1];
+        if index > self.commit && self.term_at(index) == Some(self.state.term) {
+            self.commit = index;
+        }
+    }
+
+    /// Applies committed entries, answering the clients that proposed them
+    /// here.
+    pub fn apply(&mut self, keyspace: &mut Keyspace) {
+        while keyspace.version() < self.commit {
+            let index = keyspace.version() + 1;
+            let record = self.record(index);
+            let (term, timestamp, op) = (record.entry.term, record.timestamp, record.entry.op.clone());
+            self.clock = self.clock.max(timestamp);
+            let leaves = matches!(&op, RaftOp::Members(members) if !members.iter().any(|m| m == self.me()));
+            let reply = apply_op(keyspace, op, timestamp);
+            if let Some((proposed, resp)) = self.waiters.remove(&index) {
+                let _ = resp.send(if proposed == term { reply } else { LOST_WRITE.into() });
+            }
+            // A leader that removed itself hands over once that's committed.
+            if leaves && self.is_leader() {
+                self.step_down();
+                self.leader = None;
+            }
+        }
+    }
+
+    /// Handles a membership change request, passing it on to the leader if
+    /// this node isn't it.
+    pub async fn request_change(&mut self, add: bool, node: String, wal_tx: &Sender<WalCommand>) -> String {
+        if self.is_leader() {
+            return self.change(add, node, wal_tx).await;
+        }
+        match self.leader.clone() {
+            Some(leader) => {
+                self.send(&leader, RaftMsg::Change { add, node });
+                "OK\n".into()
+            }
+            None => self.redirect(),
+        }
+    }
+
+    /// Adds or removes one voter. Changes go one at a time, and only once
+    /// the leader has committed an entry of its own term.
+    async fn change(&mut self, add: bool, node: String, wal_tx: &Sender<WalCommand>) -> String {
+        if self.members_index > self.commit || self.term_at(self.commit) != Some(self.state.term) {
+            return "TRYAGAIN A membership change is in progress\n".into();
+        }
+        let mut members = self.members.clone();
+        if add == members.contains(&node) {
+            return "OK\n".into();
+        }
+        if add {
+            members.push(node);
+        } else {
+            members.retain(|m| *m != node);
+        }
+        if members.is_empty() {
+            return "ERR can't remove the last member\n".into();
+        }
+        self.propose(RaftOp::Members(members), None, wal_tx).await;
+        "OK\n".into()
+    }
+
+    /// Checks a snapshot from `from` before the engine installs it. Replies
+    /// right away and returns false if it's stale or not needed.
+    pub fn accept_snapshot(&mut self, from: &str, term: u64, index: u64) -> bool {
+        if term < self.state.term {
+            self.send(from, RaftMsg::AppendReply { term: self.state.term, success: false, index: self.last_index(), sent: 0 });
+            return false;
+        }
+        self.become_follower(term, Some(from));
+        if index <= self.commit {
+            self.send(from, RaftMsg::AppendReply { term: self.state.term, success: true, index, sent: 0 });
+            return false;
+        }
+        true
+    }
+
+    /// The engine installed the snapshot from `from`; the log starts over
+    /// after it. Whatever clients were still waiting on can't be told apart
+    /// from the snapshot any more.
+    pub fn installed(&mut self, from: &str, index: u64, index_term: u64, members: Vec<String>) {
+        self.fail_waiters(|_| true, MAYBE_LOST);
+        self.log.clear();
+        self.durable = index;
+        self.state.snapshot_index = index;
+        self.state.snapshot_term = index_term;
+        self.state.snapshot_members = members;
+        self.save_state();
+        self.commit = index;
+        self.reload_members();
+        self.send(from, RaftMsg::AppendReply { term: self.state.term, success: true, index, sent: 0 });
+    }
+
+    /// Drops the entries a snapshot taken at `index` now covers.
+    pub fn compact(&mut self, index: u64) {
+        if index <= self.state.snapshot_index || index > self.last_index() {
+            return;
+        }
+        let term = self.record(index).entry.term;
+        let upto = (index - self.state.snapshot_index) as usize;
+        if let Some(members) = self.log.range(..upto).rev().find_map(members_of) {
+            self.state.snapshot_members = members.clone();
+        } else if self.state.snapshot_members.is_empty() {
+            self.state.snapshot_members = self.members.clone();
+        }
+        self.log.drain(..upto);
+        self.state.snapshot_index = index;
+        self.state.snapshot_term = term;
+        self.save_state();
+    }
+
+    /// One line for `INFO raft`.
+    pub fn info(&self, applied: u64) -> String {
+        let role = match self.role {
+            Role::Follower => "follower",
+            Role::Candidate { .. } => "candidate",
+            Role::Leader { .. } => "leader",
+        };
+        let mut line = format!(
+            "shard{}:role={},term={},leader={},commit={},applied={},last_index={},members=",
+            self.shard_id,
+            role,
+            self.state.term,
+            self.leader.as_deref().unwrap_or("-"),
+            self.commit,
+            applied,
+            self.last_index()
+        );
+        let _ = writeln!(line, "{}", self.members.join("|"));
+        line
+    }
+
+    fn send(&self, to: &str, msg: RaftMsg) {
+        self.net.send(to, self.shard_id, &msg);
+    }
+
+    fn save_state(&self) {
+        let path = meta_path(self.shard_id);
+        let tmp_path = format!("{}.tmp", path);
+        let result = (|| -> io::Result<()> {
+            let mut file = File::create(&tmp_path)?;
+            file.write_all(&bincode::serialize(&self.state).map_err(io::Error::other)?)?;
+            file.sync_all()?;
+            fs::rename(&tmp_path, &path)
+        })();
+        if let Err(e) = result {
+            error!(shard = self.shard_id, "can't save raft state: {}", e);
+        }
+    }
+}
+
+impl Progress {
+    fn new(last_index: u64) -> Self {
+        Self { next: last_index + 1, matched: 0, acked: None, snapshot_sent: None }
+    }
+}
+
+fn members_of(record: &RaftRecord) -> Option<&Vec<String>> {
+    match &record.entry.op {
+        RaftOp::Members(members) => Some(members),
+        _ => None,
+    }
+}
+
+fn meta_path(shard_id: usize) -> String {
+    format!("raft_{}.meta", shard_id)
+}
+
+fn decode_segment(data: &[u8]) -> Vec<RaftRecord> {
+    let mut records = Vec::new();
+    if let Some(mut slice) = data.strip_prefix(RAFT_MAGIC) {
+        while !slice.is_empty() {
+            match bincode::deserialize_from::<_, RaftRecord>(&mut slice) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+        }
+    }
+    records
+}
+
+/// Applies one committed entry as of its timestamp and returns the reply
+/// for the client that sent it. Every entry bumps the version exactly once,
+/// whether or not it changes anything.
+fn apply_op(keyspace: &mut Keyspace, op: RaftOp, now: u64) -> Bytes {
+    match op {
+        RaftOp::Noop | RaftOp::Members(_) => {
+            keyspace.next_version();
+            Bytes::new()
+        }
+        RaftOp::Set { key, value } => {
+            apply_db(keyspace, ParsedCommand::Set { key, value }, now);
+            "OK\n".into()
+        }
+        RaftOp::SetEx { key, value, ttl } => {
+            apply_db(keyspace, ParsedCommand::SetEx { key, value, ttl }, now);
+            "OK\n".into()
+        }
+        RaftOp::Del { key } => {
+            let removed = !keyspace.expire_if_needed(&key, now) && keyspace.contains_key(&key);
+            apply_db(keyspace, ParsedCommand::Del { key }, now);
+            if removed { "1".into() } else { "0".into() }
+        }
+        RaftOp::Expire { key, ttl } => {
+            keyspace.expire_if_needed(&key, now);
+            let exists = keyspace.contains_key(&key);
+            apply_db(keyspace, ParsedCommand::Expire { key, ttl }, now);
+            if exists { "1".into() } else { "0".into() }
+        }
+        RaftOp::SetIfVer { key, value, version: expected } => {
+            keyspace.expire_if_needed(&key, now);
+            // Version 0 stands for "key must not exist".
+            if keyspace.db().get(&key).map_or(0, |e| e.version) != expected {
+                keyspace.next_version();
+                return "0\n".into();
+            }
+            apply_db(keyspace, ParsedCommand::Set { key, value }, now);
+            format!("{}\n", keyspace.version()).into()
+        }
+        RaftOp::DelIfVer { key, version: expected } => {
+            keyspace.expire_if_needed(&key, now);
+            if keyspace.db().get(&key).map(|e| e.version) != Some(expected) {
+                keyspace.next_version();
+                return "0\n".into();
+            }
+            apply_db(keyspace, ParsedCommand::Del { key }, now);
+            "1\n".into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::engine::snapshot::decode_snapshot;
+
+    const ME: &str = "127.0.0.1:1";
+    const PEER: &str = "127.0.0.1:2";
+
+    fn record(lsn: u64, term: u64, op: RaftOp) -> RaftRecord {
+        RaftRecord { lsn, timestamp: 1, entry: RaftEntry { term, op } }
+    }
+
+    /// A node whose log holds one no-op per entry of `terms`, all on disk.
+    fn node(terms: &[u64], term: u64) -> RaftNode {
+        let members = vec![ME.to_string(), PEER.to_string(), "127.0.0.1:3".to_string()];
+        let log: VecDeque<_> = terms.iter().zip(1..).map(|(&t, lsn)| record(lsn, t, RaftOp::Noop)).collect();
+        let mut node = RaftNode {
+            shard_id: 0,
+            net: Arc::new(RaftNet::new(ME.into(), members)),
+            state: HardState { term, ..Default::default() },
+            durable: log.len() as u64,
+            log,
+            members: Vec::new(),
+            members_index: 0,
+            commit: 0,
+            role: Role::Follower,
+            leader: None,
+            last_heartbeat: Instant::now(),
+            last_contact: Instant::now(),
+            election_deadline: Instant::now(),
+            waiters: HashMap::new(),
+            clock: 0,
+        };
+        node.reload_members();
+        node
+    }
+
+    fn lead(node: &mut RaftNode) {
+        let last_index = node.last_index();
+        let peers = node.members.iter().filter(|m| *m != ME).map(|m| (m.clone(), Progress::new(last_index))).collect();
+        node.role = Role::Leader { peers, since: Instant::now() };
+    }
+
+    fn progress<'a>(node: &'a mut RaftNode, peer: &str) -> &'a mut Progress {
+        let Role::Leader { peers, .. } = &mut node.role else {
+            panic!("not leading");
+        };
+        peers.get_mut(peer).unwrap()
+    }
+
+    fn wait(node: &mut RaftNode, index: u64, term: u64) -> oneshot::Receiver<Bytes> {
+        let (tx, rx) = oneshot::channel();
+        node.waiters.insert(index, (term, tx.into()));
+        rx
+    }
+
+    /// A WAL task that keeps what's written and answers every sync.
+    fn wal() -> Sender<WalCommand> {
+        let (wal_tx, mut wal_rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Some(cmd) = wal_rx.recv().await {
+                if let WalCommand::Sync(done) = cmd {
+                    let _ = done.send(());
+                }
+            }
+        });
+        wal_tx
+    }
+
+    #[tokio::test]
+    async fn append_truncates_conflicting_entries() {
+        let mut node = node(&[1, 1, 1], 2);
+        let second = wait(&mut node, 2, 1);
+        let third = wait(&mut node, 3, 1);
+
+        node.append_entries(PEER, (1, 1), vec![record(2, 2, RaftOp::Noop)], 5, 0, &wal()).await;
+
+        assert_eq!(node.last_index(), 2);
+        assert_eq!(node.term_at(2), Some(2));
+        assert_eq!(node.term_at(3), None);
+        assert_eq!(node.durable, 2);
+        // Only as far as what the leader sent is known to match it.
+        assert_eq!(node.commit, 2);
+        assert_eq!(second.await.unwrap(), LOST_WRITE);
+        assert_eq!(third.await.unwrap(), LOST_WRITE);
+    }
+
+    #[tokio::test]
+    async fn append_keeps_entries_it_already_has() {
+        let mut node = node(&[1, 1, 2], 2);
+        let entries = vec![record(2, 1, RaftOp::Noop)];
+
+        node.append_entries(PEER, (1, 1), entries, 0, 0, &wal()).await;
+
+        assert_eq!(node.last_index(), 3);
+        assert_eq!(node.term_at(3), Some(2));
+    }
+
+    #[tokio::test]
+    async fn append_refuses_a_gap() {
+        let mut node = node(&[1], 1);
+
+        node.append_entries(PEER, (3, 1), vec![record(4, 1, RaftOp::Noop)], 4, 0, &wal()).await;
+
+        assert_eq!(node.last_index(), 1);
+        assert_eq!(node.commit, 0);
+    }
+
+    #[test]
+    fn leader_counts_only_its_synced_entries() {
+        let mut node = node(&[1, 2], 2);
+        lead(&mut node);
+        node.durable = 0;
+        progress(&mut node, PEER).matched = 2;
+
+        node.advance_commit();
+        assert_eq!(node.commit, 0);
+
+        node.durable = 2;
+        node.advance_commit();
+        assert_eq!(node.commit, 2);
+    }
+
+    #[test]
+    fn earlier_terms_commit_with_an_entry_of_the_leaders() {
+        let mut node = node(&[1], 2);
+        lead(&mut node);
+        progress(&mut node, PEER).matched = 1;
+
+        node.advance_commit();
+        assert_eq!(node.commit, 0);
+
+        node.log.push_back(record(2, 2, RaftOp::Noop));
+        node.durable = 2;
+        progress(&mut node, PEER).matched = 2;
+        node.advance_commit();
+        assert_eq!(node.commit, 2);
+    }
+
+    #[tokio::test]
+    async fn sync_makes_the_log_durable() {
+        let mut node = node(&[1], 1);
+        lead(&mut node);
+        node.log.push_back(record(2, 1, RaftOp::Noop));
+        progress(&mut node, PEER).matched = 2;
+
+        node.sync(&wal()).await;
+
+        assert_eq!(node.durable, 2);
+        assert_eq!(node.commit, 2);
+    }
+
+    #[tokio::test]
+    async fn apply_answers_waiters_of_the_same_term() {
+        let mut node = node(&[], 2);
+        node.log.push_back(record(1, 1, RaftOp::Set { key: "a".into(), value: "1".into() }));
+        node.log.push_back(record(2, 2, RaftOp::Set { key: "b".into(), value: "2".into() }));
+        node.commit = 2;
+        let kept = wait(&mut node, 1, 1);
+        let replaced = wait(&mut node, 2, 1);
+        let mut keyspace = Keyspace::default();
+
+        node.apply(&mut keyspace);
+
+        assert_eq!(keyspace.version(), 2);
+        assert!(keyspace.contains_key(b"b"));
+        assert_eq!(kept.await.unwrap(), "OK\n");
+        assert_eq!(replaced.await.unwrap(), LOST_WRITE);
+    }
+
+    #[tokio::test]
+    async fn stepping_down_fails_uncommitted_waiters() {
+        let mut node = node(&[1, 1], 1);
+        lead(&mut node);
+        node.commit = 1;
+        let committed = wait(&mut node, 1, 1);
+        let pending = wait(&mut node, 2, 1);
+
+        node.become_follower(1, Some(PEER));
+
+        assert!(!node.is_leader());
+        assert_eq!(pending.await.unwrap(), MAYBE_LOST);
+        assert!(node.waiters.contains_key(&1));
+        drop(committed);
+    }
+
+    #[test]
+    fn setifver_on_a_key_from_a_legacy_snapshot() {
+        let db = HashMap::from([("a".to_string(), "1".to_string())]);
+        let legacy = bincode::serialize(&(db, HashMap::<String, u64>::new())).unwrap();
+        let mut keyspace = Keyspace::from_snapshot(decode_snapshot(&legacy).unwrap(), 1);
+        let version = keyspace.db()[&b"a"[..]].version;
+        assert_eq!(version, 1);
+
+        // 0 means the key must not exist, which it does.
+        let missing = RaftOp::SetIfVer { key: "a".into(), value: "2".into(), version: 0 };
+        assert_eq!(apply_op(&mut keyspace, missing, 1), "0\n");
+        let current = RaftOp::SetIfVer { key: "a".into(), value: "2".into(), version };
+        // The refused write took version 2.
+        assert_eq!(apply_op(&mut keyspace, current, 1), "3\n");
+        assert_eq!(keyspace.db()[&b"a"[..]].value, "2");
+    }
+
+    #[test]
+    fn reads_need_a_lease() {
+        let mut node = node(&[1], 1);
+        lead(&mut node);
+        node.commit = 1;
+        assert!(!node.can_read());
+
+        progress(&mut node, PEER).acked = Some(0);
+        assert!(node.can_read());
+
+        let Role::Leader { since, .. } = &mut node.role else {
+            unreachable!();
+        };
+        *since -= Duration::from_millis(LEASE_MS);
+        assert!(!node.can_read());
+    }
+
+    #[test]
+    fn reads_wait_for_an_entry_of_the_term() {
+        let mut node = node(&[1], 2);
+        lead(&mut node);
+        node.commit = 1;
+        progress(&mut node, PEER).acked = Some(0);
+        assert!(!node.can_read());
+    }
+}
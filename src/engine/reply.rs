@@ -0,0 +1,134 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use tokio::sync::{Notify, oneshot};
+
+/// Where a shard sends a command's reply.
+pub enum Resp {
+    /// The caller's own channel.
+    One(oneshot::Sender<Bytes>),
+    /// A slot in a batch, answered together with the rest of it.
+    Slot(BatchSlot),
+}
+
+impl Resp {
+    /// Hands the reply over. Like `oneshot::Sender::send`, it comes back as
+    /// an error if nobody is waiting for it.
+    pub fn send(self, reply: Bytes) -> Result<(), Bytes> {
+        match self {
+            Resp::One(tx) => tx.send(reply),
+            Resp::Slot(mut slot) => {
+                slot.reply = Some(reply);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<oneshot::Sender<Bytes>> for Resp {
+    fn from(tx: oneshot::Sender<Bytes>) -> Self {
+        Resp::One(tx)
+    }
+}
+
+/// Replies to a batch of commands sent to a shard in one message. The shard
+/// fills a slot per command, in whatever order it gets to them, and the
+/// batch is answered as a whole once the last one is in.
+#[derive(Default)]
+pub struct BatchReplies {
+    slots: Mutex<Slots>,
+    done: Notify,
+}
+
+#[derive(Default)]
+struct Slots {
+    replies: Vec<Option<Bytes>>,
+    missing: usize,
+}
+
+impl BatchReplies {
+    /// A slot for the next command in the batch, and its index.
+    pub fn slot(self: &Arc<Self>) -> (Resp, usize) {
+        let mut slots = self.slots.lock().unwrap();
+        slots.replies.push(None);
+        slots.missing += 1;
+        let index = slots.replies.len() - 1;
+        (Resp::Slot(BatchSlot { batch: self.clone(), index, reply: None }), index)
+    }
+
+    /// Waits for the whole batch, then takes the reply in slot `index`, or
+    /// `None` if the shard dropped that command unanswered.
+    pub async fn take(&self, index: usize) -> Option<Bytes> {
+        loop {
+            // Created before looking, so the last reply landing in between
+            // isn't missed.
+            let done = self.done.notified();
+            {
+                let mut slots = self.slots.lock().unwrap();
+                if slots.missing == 0 {
+                    return slots.replies[index].take();
+                }
+            }
+            done.await;
+        }
+    }
+}
+
+/// A command's place in a batch. The slot is filled when this drops, empty
+/// if no reply was sent.
+pub struct BatchSlot {
+    batch: Arc<BatchReplies>,
+    index: usize,
+    reply: Option<Bytes>,
+}
+
+impl Drop for BatchSlot {
+    fn drop(&mut self) {
+        let mut slots = self.batch.slots.lock().unwrap();
+        slots.replies[self.index] = self.reply.take();
+        slots.missing -= 1;
+        if slots.missing == 0 {
+            self.batch.done.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn replies_keep_their_order() {
+        let batch = Arc::new(BatchReplies::default());
+        let (first, _) = batch.slot();
+        let (second, _) = batch.slot();
+        let (third, index) = batch.slot();
+        assert_eq!(index, 2);
+
+        third.send("3".into()).unwrap();
+        first.send("1".into()).unwrap();
+        drop(second);
+
+        assert_eq!(batch.take(0).await.as_deref(), Some(&b"1"[..]));
+        assert_eq!(batch.take(1).await, None);
+        assert_eq!(batch.take(2).await.as_deref(), Some(&b"3"[..]));
+    }
+
+    #[tokio::test]
+    async fn take_waits_for_the_whole_batch() {
+        let batch = Arc::new(BatchReplies::default());
+        let (first, _) = batch.slot();
+        let (second, _) = batch.slot();
+        first.send("1".into()).unwrap();
+
+        let waiting = tokio::spawn({
+            let batch = batch.clone();
+            async move { batch.take(0).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        second.send("2".into()).unwrap();
+        assert_eq!(waiting.await.unwrap().as_deref(), Some(&b"1"[..]));
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use bytes::Bytes;
+use mlua::{HookTriggers, Lua, LuaOptions, RegistryKey, StdLib, Table, Value, Variadic};
+use sha1::{Digest, Sha1};
+
+use super::Keyspace;
+use super::apply::now_ms;
+use super::command::WalEntry;
+
+/// How many Lua instructions run between kill / time limit checks.
+const HOOK_INSTRUCTIONS: u32 = 10_000;
+
+const KILLED_MSG: &str = "Script killed by user with SCRIPT KILL";
+const TIMEOUT_MSG: &str = "Script exceeded the time limit";
+
+pub fn sha1_hex(script: &str) -> String {
+    let digest = Sha1::digest(script.as_bytes());
+    let mut hex = String::with_capacity(40);
+    for byte in digest {
+        let _ = write!(hex, "{:02x}", byte);
+    }
+    hex
+}
+
+/// Server wide `sha -> source` cache backing EVALSHA and SCRIPT LOAD/EXISTS/FLUSH.
+#[derive(Default)]
+pub struct ScriptCache {
+    scripts: RwLock<HashMap<String, Arc<str>>>,
+}
+
+impl ScriptCache {
+    pub fn load(&self, script: String) -> (String, Arc<str>) {
+        let sha = sha1_hex(&script);
+        let source = self
+            .scripts
+            .write()
+            .unwrap()
+            .entry(sha.clone())
+            .or_insert_with(|| script.into())
+            .clone();
+        (sha, source)
+    }
+
+    pub fn get(&self, sha: &str) -> Option<Arc<str>> {
+        self.scripts.read().unwrap().get(&sha.to_lowercase()).cloned()
+    }
+
+    pub fn exists(&self, sha: &str) -> bool {
+        self.scripts.read().unwrap().contains_key(&sha.to_lowercase())
+    }
+
+    pub fn flush(&self) {
+        self.scripts.write().unwrap().clear();
+    }
+}
+
+/// Per-shard flags shared between the engine running a script and the
+/// connection serving SCRIPT KILL.
+#[derive(Default)]
+pub struct ScriptState {
+    running: AtomicBool,
+    kill: AtomicBool,
+    started_ms: AtomicU64,
+}
+
+impl ScriptState {
+    /// Asks the running script, if any, to stop. Returns false when the
+    /// shard isn't executing a script.
+    pub fn kill(&self) -> bool {
+        if self.running.load(Ordering::Acquire) {
+            self.kill.store(true, Ordering::Release);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Result of a successful script run: the reply for the client and the
+/// writes to log and apply, in order.
+pub struct ScriptOutcome {
+    pub reply: Bytes,
+    pub effects: Vec<WalEntry>,
+}
+
+/// Keys touched by the script so far. `None` marks a key deleted by the script.
+type Overlay = HashMap<Bytes, Option<(Bytes, Option<u64>)>>;
+
+/// Lua VM owned by a single shard engine.
+pub struct ScriptRunner {
+    lua: Lua,
+    state: Arc<ScriptState>,
+    functions: HashMap<String, RegistryKey>,
+}
+
+impl ScriptRunner {
+    /// `time_limit` is in milliseconds, 0 for none.
+    pub fn new(state: Arc<ScriptState>, time_limit: u64) -> Self {
+        let lua = Lua::new_with(
+            StdLib::TABLE | StdLib::STRING | StdLib::MATH,
+            LuaOptions::default(),
+        )
+        .expect("Failed to create Lua state");
+
+        let hook_state = state.clone();
+        lua.set_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS),
+            move |_, _| {
+                if hook_state.kill.load(Ordering::Acquire) {
+                    return Err(mlua::Error::RuntimeError(KILLED_MSG.into()));
+                }
+                let started = hook_state.started_ms.load(Ordering::Relaxed);
+                if time_limit > 0 && now_ms().saturating_sub(started) > time_limit {
+                    return Err(mlua::Error::RuntimeError(TIMEOUT_MSG.into()));
+                }
+                Ok(())
+            },
+        );
+
+        Self {
+            lua,
+            state,
+            functions: HashMap::new(),
+        }
+    }
+
+    /// Forgets every compiled script and frees what the VM held for them.
+    pub fn flush(&mut self) {
+        self.functions.clear();
+        self.lua.expire_registry_values();
+    }
+
+    /// Runs a script against a read-only view of the shard. Writes are kept
+    /// in an overlay and only handed back as effects if the script finishes,
+    /// so a failed or killed script leaves the shard untouched.
+    pub fn run(
+        &mut self,
+        sha: &str,
+        source: &str,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        keyspace: &Keyspace,
+    ) -> Result<ScriptOutcome, String> {
+        if !self.functions.contains_key(sha) {
+            let func = self
+                .lua
+                .load(source)
+                .set_name(format!("@user_script:{}", sha))
+                .into_function()
+                .map_err(|e| {
+                    let msg = e.to_string();
+                    format!("ERR Error compiling script: {}", msg.lines().next().unwrap_or_default())
+                })?;
+            let key = self.lua.create_registry_value(func).map_err(|e| e.to_string())?;
+            self.functions.insert(sha.to_string(), key);
+        }
+
+        self.state.kill.store(false, Ordering::Release);
+        self.state.started_ms.store(now_ms(), Ordering::Relaxed);
+        self.state.running.store(true, Ordering::Release);
+        let result = self.call(sha, keys, args, keyspace);
+        self.state.running.store(false, Ordering::Release);
+        self.state.kill.store(false, Ordering::Release);
+        result
+    }
+
+    fn call(
+        &self,
+        sha: &str,
+        keys: Vec<Bytes>,
+        args: Vec<Bytes>,
+        keyspace: &Keyspace,
+    ) -> Result<ScriptOutcome, String> {
+        let lua = &self.lua;
+        let mut overlay = Overlay::new();
+        let mut effects = Vec::new();
+
+        let declared = &keys;
+
+        let reply = lua
+            .scope(|scope| {
+                let call = scope.create_function_mut(|lua, argv: Variadic<Value>| {
+                    let argv = argv
+                        .into_iter()
+                        .map(|v| match lua.coerce_string(v)? {
+                            Some(s) => Ok(Bytes::copy_from_slice(s.as_bytes())),
+                            None => Err(mlua::Error::RuntimeError(
+                                "Lua crab.call() arguments must be strings or numbers".into(),
+                            )),
+                        })
+                        .collect::<mlua::Result<Vec<_>>>()?;
+                    dispatch(lua, declared, &argv, keyspace, &mut overlay, &mut effects)
+                })?;
+
+                let crab = lua.create_table()?;
+                crab.set("call", call)?;
+                let env = environment(lua)?;
+                env.raw_set("crab", crab.clone())?;
+                env.raw_set("redis", crab)?;
+                let strings = |items: &[Bytes]| {
+                    items.iter().map(|item| lua.create_string(item)).collect::<mlua::Result<Vec<_>>>()
+                };
+                env.raw_set("KEYS", lua.create_sequence_from(strings(&keys)?)?)?;
+                env.raw_set("ARGV", lua.create_sequence_from(strings(&args)?)?)?;
+
+                let func: mlua::Function = lua.registry_value(&self.functions[sha])?;
+                func.set_environment(env)?;
+                let ret: Value = func.call(())?;
+                render(&ret)
+            })
+            .map_err(|e| {
+                let msg = match e {
+                    mlua::Error::CallbackError { cause, .. } => cause.to_string(),
+                    e => e.to_string(),
+                };
+                // Replies are single lines, so the Lua traceback is dropped.
+                format!("ERR {}", msg.lines().next().unwrap_or_default())
+            })?;
+
+        Ok(ScriptOutcome { reply: reply.into(), effects })
+    }
+}
+
+/// Globals for one run: the standard library with its tables copied, so
+/// nothing a script assigns, even to `string.format` or `_G`, is seen by the
+/// next one.
+fn environment(lua: &Lua) -> mlua::Result<Table<'_>> {
+    let env = lua.create_table()?;
+    for pair in lua.globals().pairs::<Value, Value>() {
+        let (name, value) = pair?;
+        let value = match value {
+            _ if matches!(&name, Value::String(name) if name == "_G") => continue,
+            Value::Table(lib) => {
+                let copy = lua.create_table()?;
+                for entry in lib.pairs::<Value, Value>() {
+                    let (k, v) = entry?;
+                    copy.raw_set(k, v)?;
+                }
+                Value::Table(copy)
+            }
+            value => value,
+        };
+        env.raw_set(name, value)?;
+    }
+    env.raw_set("_G", env.clone())?;
+    Ok(env)
+}
+
+/// Refuses values a request line couldn't have carried. Replies, `MIGRATE`
+/// and CDC all write values on one line, so a newline would split them.
+fn line_safe(value: &[u8]) -> mlua::Result<()> {
+    if value.iter().any(|&b| b == b'\n' || b == b'\r') {
+        return Err(mlua::Error::RuntimeError("value must not contain CR or LF".into()));
+    }
+    Ok(())
+}
+
+fn dispatch<'lua>(
+    lua: &'lua Lua,
+    declared: &[Bytes],
+    argv: &[Bytes],
+    keyspace: &Keyspace,
+    overlay: &mut Overlay,
+    effects: &mut Vec<WalEntry>,
+) -> mlua::Result<Value<'lua>> {
+    let err = |msg: &str| mlua::Error::RuntimeError(msg.to_string());
+    let (name, rest) = argv.split_first().ok_or_else(|| err("Please specify at least one argument for crab.call()"))?;
+    if let Some(key) = rest.first()
+        && !declared.contains(key)
+    {
+        return Err(err("Script attempted to access a key not declared in KEYS"));
+    }
+
+    let now = now_ms();
+    let current = |overlay: &Overlay, key: &[u8]| -> Option<(Bytes, Option<u64>)> {
+        match overlay.get(key) {
+            Some(item) => item.clone().filter(|(_, expiry)| expiry.is_none_or(|exp| exp > now)),
+            None => keyspace.peek(key, now).map(|(e, expiry)| (e.value.clone(), expiry)),
+        }
+    };
+
+    let integer = |n: &[u8]| {
+        std::str::from_utf8(n)
+            .ok()
+            .and_then(|n| n.parse::<u64>().ok())
+            .ok_or_else(|| err("value is not an integer"))
+    };
+    match (name.to_ascii_uppercase().as_slice(), rest) {
+        (b"GET", [key]) => Ok(match current(overlay, key) {
+            Some((value, _)) => Value::String(lua.create_string(&value)?),
+            None => Value::Boolean(false),
+        }),
+        (b"SET", [key, value]) => {
+            line_safe(value)?;
+            overlay.insert(key.clone(), Some((value.clone(), None)));
+            effects.push(WalEntry::Set { key: key.clone(), value: value.clone() });
+            Ok(Value::String(lua.create_string("OK")?))
+        }
+        (b"SETEX", [key, value, ttl]) => {
+            line_safe(value)?;
+            let ttl = integer(ttl)?;
+            overlay.insert(key.clone(), Some((value.clone(), Some(now + ttl * 1000))));
+            effects.push(WalEntry::SetEx { key: key.clone(), value: value.clone(), ttl });
+            Ok(Value::String(lua.create_string("OK")?))
+        }
+        (b"DEL", [key]) => {
+            let existed = current(overlay, key).is_some();
+            overlay.insert(key.clone(), None);
+            effects.push(WalEntry::Del { key: key.clone() });
+            Ok(Value::Integer(existed as i64))
+        }
+        (b"EXPIRE", [key, ttl]) => {
+            let ttl = integer(ttl)?;
+            match current(overlay, key) {
+                Some((value, _)) => {
+                    overlay.insert(key.clone(), Some((value, Some(now + ttl * 1000))));
+                    effects.push(WalEntry::Expire { key: key.clone(), ttl });
+                    Ok(Value::Integer(1))
+                }
+                None => Ok(Value::Integer(0)),
+            }
+        }
+        (b"TTL", [key]) => Ok(Value::Integer(match current(overlay, key) {
+            None => -2,
+            Some((_, None)) => -1,
+            Some((_, Some(exp))) => ((exp - now) / 1000) as i64,
+        })),
+        (b"EX", [key]) => Ok(Value::Integer(current(overlay, key).is_some() as i64)),
+        _ => Err(err("Unknown command or wrong number of arguments called from script")),
+    }
+}
+
+/// Converts the script's return value into a reply line, following the
+/// Redis conventions for booleans, numbers and `{err=...}` / `{ok=...}` tables.
+fn render(value: &Value) -> mlua::Result<Vec<u8>> {
+    Ok(match value {
+        Value::Nil | Value::Boolean(false) => b"nil\n".to_vec(),
+        Value::Boolean(true) => b"1\n".to_vec(),
+        Value::Integer(i) => format!("{}\n", i).into_bytes(),
+        Value::Number(n) => format!("{}\n", *n as i64).into_bytes(),
+        Value::String(s) => [s.as_bytes(), b"\n"].concat(),
+        Value::Table(t) => {
+            if let Ok(msg) = t.get::<_, String>("err") {
+                format!("ERR {}\n", msg).into_bytes()
+            } else if let Ok(msg) = t.get::<_, String>("ok") {
+                format!("{}\n", msg).into_bytes()
+            } else {
+                let items = t
+                    .clone()
+                    .sequence_values::<Value>()
+                    .map(|v| v.and_then(|v| render(&v)).map(|s| s.trim_ascii_end().to_vec()))
+                    .collect::<mlua::Result<Vec<_>>>()?;
+                [items.join(&b' ').as_slice(), b"\n"].concat()
+            }
+        }
+        _ => b"nil\n".to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(
+        runner: &mut ScriptRunner,
+        source: &str,
+        keys: &[&str],
+        keyspace: &Keyspace,
+    ) -> Result<ScriptOutcome, String> {
+        let keys = keys.iter().map(|k| Bytes::copy_from_slice(k.as_bytes())).collect();
+        runner.run(&sha1_hex(source), source, keys, Vec::new(), keyspace)
+    }
+
+    fn reply(outcome: Result<ScriptOutcome, String>) -> String {
+        String::from_utf8(outcome.unwrap().reply.to_vec()).unwrap()
+    }
+
+    fn describe(entry: &WalEntry) -> String {
+        let text = |b: &Bytes| String::from_utf8_lossy(b).into_owned();
+        match entry {
+            WalEntry::Set { key, value } => format!("SET {} {}", text(key), text(value)),
+            WalEntry::SetEx { key, value, ttl } => format!("SETEX {} {} {}", text(key), text(value), ttl),
+            WalEntry::Del { key } => format!("DEL {}", text(key)),
+            WalEntry::Expire { key, ttl } => format!("EXPIRE {} {}", text(key), ttl),
+            WalEntry::Restore { key, .. } => format!("RESTORE {}", text(key)),
+        }
+    }
+
+    #[test]
+    fn reads_see_the_scripts_own_writes() {
+        let mut keyspace = Keyspace::default();
+        keyspace.insert(Bytes::from_static(b"k"), Bytes::from_static(b"old"), 1, now_ms());
+        let mut runner = ScriptRunner::new(Arc::default(), 0);
+        let source = "local before = crab.call('GET', KEYS[1]) \
+            crab.call('SET', KEYS[1], 'new') \
+            local after = crab.call('GET', KEYS[1]) \
+            crab.call('DEL', KEYS[1]) \
+            return {before, after, crab.call('EX', KEYS[1]), crab.call('TTL', KEYS[1])}";
+        assert_eq!(reply(run(&mut runner, source, &["k"], &keyspace)), "old new 0 -2\n");
+        // Only the effects change the shard, once the engine applies them.
+        assert_eq!(&keyspace.peek(b"k", now_ms()).unwrap().0.value[..], b"old");
+    }
+
+    #[test]
+    fn effects_are_the_writes_in_order() {
+        let keyspace = Keyspace::default();
+        let mut runner = ScriptRunner::new(Arc::default(), 0);
+        let source = "crab.call('SET', KEYS[1], '1') \
+            crab.call('SETEX', KEYS[2], '2', 30) \
+            crab.call('DEL', KEYS[1]) \
+            crab.call('EXPIRE', KEYS[2], 60) \
+            crab.call('EXPIRE', KEYS[1], 5) \
+            return 'done'";
+        let outcome = run(&mut runner, source, &["a", "b"], &keyspace).unwrap();
+        let effects: Vec<String> = outcome.effects.iter().map(describe).collect();
+        // Expiring the deleted key changes nothing, so it isn't logged.
+        assert_eq!(effects, ["SET a 1", "SETEX b 2 30", "DEL a", "EXPIRE b 60"]);
+    }
+
+    #[test]
+    fn values_with_cr_or_lf_are_refused() {
+        let keyspace = Keyspace::default();
+        let mut runner = ScriptRunner::new(Arc::default(), 0);
+        for source in ["return crab.call('SET', KEYS[1], 'a\\nb')", "return crab.call('SETEX', KEYS[1], 'a\\rb', 5)"] {
+            let error = run(&mut runner, source, &["k"], &keyspace).err().unwrap();
+            assert!(error.contains("value must not contain CR or LF"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn globals_do_not_leak_between_runs() {
+        let keyspace = Keyspace::default();
+        let mut runner = ScriptRunner::new(Arc::default(), 0);
+        let source = "x = 1 _G.y = 2 rawset(_G, 'z', 3) string.extra = 4 return 'ok'";
+        assert_eq!(reply(run(&mut runner, source, &[], &keyspace)), "ok\n");
+        let source = "return tostring(x) .. tostring(y) .. tostring(z) .. tostring(string.extra) .. string.rep('!', 2)";
+        assert_eq!(reply(run(&mut runner, source, &[], &keyspace)), "nilnilnilnil!!\n");
+    }
+
+    #[test]
+    fn scripts_over_the_time_limit_are_aborted() {
+        let keyspace = Keyspace::default();
+        let state = Arc::new(ScriptState::default());
+        let mut runner = ScriptRunner::new(state.clone(), 50);
+        let error = run(&mut runner, "crab.call('SET', KEYS[1], 'v') while true do end", &["k"], &keyspace)
+            .err()
+            .unwrap();
+        assert!(error.contains(TIMEOUT_MSG), "{}", error);
+        assert!(!state.kill());
+        // The VM is still usable afterwards.
+        assert_eq!(reply(run(&mut runner, "return 1", &[], &keyspace)), "1\n");
+    }
+}
@@ -1,19 +1,204 @@
 use std::collections::HashMap;
-use std::fs::{File, rename};
-use std::io::{BufWriter, Write};
+use std::fs::{self, File, rename};
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
+use std::time::Instant;
 use bincode;
+use bytes::Bytes;
+use indexmap::IndexMap;
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+use tracing::info;
 
-pub fn save_snapshot(shard_id: usize, db: &HashMap<String, String>, ttl_db: &HashMap<String, u64>) {
+use super::Entry;
+use crate::metrics::ShardMetrics;
+
+// Snapshots start with a magic so we can tell the formats apart. CKV2 is a
+// `(version, count)` header followed by `count` `(key, entry, expiry)`
+// records; CKV1 was a single bincode `(db, ttl_db, version)`; anything else
+// is the legacy `(db, ttl_db)`.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"CKV2";
+const SNAPSHOT_MAGIC_V1: &[u8; 4] = b"CKV1";
+
+// Snapshots from before keys had versions load as if one write at this
+// version made all their keys. It can't be 0, which SETIFVER takes to mean
+// "no such key", and WAL segments of that era are numbered on from it, so
+// it has to come out the same on every load.
+const LEGACY_VERSION: u64 = 1;
+
+// Chunks buffered between the engine and the writer thread.
+const CHUNK_QUEUE: usize = 4;
+
+#[derive(Default)]
+pub struct SnapshotState {
+    pub db: IndexMap<Bytes, Entry>,
+    pub ttl_db: IndexMap<Bytes, u64>,
+    pub version: u64,
+}
+
+/// A batch of records built with `encode_record`, and how many it holds.
+pub type SnapshotChunk = (u64, Vec<u8>);
+
+/// Starts writing a snapshot of `count` keys at `version` on a blocking
+/// thread. The engine streams the records in as chunks and drops the sender
+/// once all of them are sent; the handle resolves when the file is synced
+/// and has replaced the previous snapshot.
+pub fn spawn_writer(
+    shard_id: usize,
+    version: u64,
+    count: u64,
+    metrics: Arc<ShardMetrics>,
+) -> (mpsc::Sender<SnapshotChunk>, JoinHandle<io::Result<()>>) {
+    let (chunk_tx, mut chunk_rx) = mpsc::channel::<SnapshotChunk>(CHUNK_QUEUE);
+    let handle = task::spawn_blocking(move || {
+        let started = Instant::now();
+        let tmp_path = format!("snapshot_{}.bin.tmp", shard_id);
+        let final_path = snapshot_path(shard_id);
+
+        let file = File::create(&tmp_path)?;
+        let mut writer = BufWriter::with_capacity(64 * 1024, file);
+
+        writer.write_all(SNAPSHOT_MAGIC)?;
+        bincode::serialize_into(&mut writer, &(version, count)).map_err(io::Error::other)?;
+
+        let mut written = 0;
+        while let Some((records, chunk)) = chunk_rx.blocking_recv() {
+            writer.write_all(&chunk)?;
+            written += records;
+        }
+        // The sender also goes away if the engine gives up half way; don't
+        // let a partial snapshot replace a good one.
+        if written != count {
+            return Err(io::Error::other(format!("got {} of {} keys", written, count)));
+        }
+
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        let size = file.metadata()?.len();
+
+        rename(tmp_path, final_path)?;
+        let took = started.elapsed();
+        metrics.snapshot(took, size);
+        info!(shard = shard_id, version, keys = count, bytes = size, took_ms = took.as_millis() as u64, "snapshot written");
+        Ok(())
+    });
+    (chunk_tx, handle)
+}
+
+pub fn encode_record(out: &mut Vec<u8>, key: &[u8], entry: &Entry, expiry: Option<u64>) {
+    bincode::serialize_into(out, &(key, entry, expiry)).unwrap();
+}
+
+pub fn snapshot_path(shard_id: usize) -> String {
+    format!("snapshot_{}.bin", shard_id)
+}
+
+/// Replaces the snapshot with `data`, a snapshot file received from a
+/// primary. Must not run while a snapshot is being written.
+pub fn install_snapshot(shard_id: usize, data: &[u8]) -> io::Result<()> {
     let tmp_path = format!("snapshot_{}.bin.tmp", shard_id);
-    let final_path = format!("snapshot_{}.bin", shard_id);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    rename(tmp_path, snapshot_path(shard_id))
+}
+
+pub fn load_snapshot(shard_id: usize) -> Option<SnapshotState> {
+    if let Ok(data) = fs::read(snapshot_path(shard_id))
+        && let Some(state) = decode_snapshot(&data)
+    {
+        return Some(state);
+    }
+
+    // Older builds wrote JSON snapshots, either with or without the TTL map.
+    let data = fs::read_to_string(format!("snapshot_{}.json", shard_id)).ok()?;
+    if let Ok((db, ttl_db)) =
+        serde_json::from_str::<(HashMap<String, String>, HashMap<String, u64>)>(&data)
+    {
+        Some(SnapshotState { db: unversioned(db), ttl_db: keyed(ttl_db), version: LEGACY_VERSION })
+    } else if let Ok(db) = serde_json::from_str::<HashMap<String, String>>(&data) {
+        Some(SnapshotState { db: unversioned(db), version: LEGACY_VERSION, ..Default::default() })
+    } else {
+        None
+    }
+}
+
+/// Decodes the contents of a `snapshot_{id}.bin` file.
+pub fn decode_snapshot(data: &[u8]) -> Option<SnapshotState> {
+    if let Some(payload) = data.strip_prefix(SNAPSHOT_MAGIC) {
+        decode_records(payload)
+    } else if let Some(payload) = data.strip_prefix(SNAPSHOT_MAGIC_V1) {
+        let (db, ttl_db, version) = bincode::deserialize(payload).ok()?;
+        Some(SnapshotState { db, ttl_db, version })
+    } else {
+        let (db, ttl_db) =
+            bincode::deserialize::<(HashMap<String, String>, HashMap<String, u64>)>(data).ok()?;
+        Some(SnapshotState { db: unversioned(db), ttl_db: keyed(ttl_db), version: LEGACY_VERSION })
+    }
+}
+
+fn decode_records(mut payload: &[u8]) -> Option<SnapshotState> {
+    let (version, count): (u64, u64) = bincode::deserialize_from(&mut payload).ok()?;
+    let mut state = SnapshotState {
+        db: IndexMap::with_capacity(count as usize),
+        version,
+        ..Default::default()
+    };
+    for _ in 0..count {
+        let (key, entry, expiry): (Bytes, Entry, Option<u64>) =
+            bincode::deserialize_from(&mut payload).ok()?;
+        if let Some(expiry) = expiry {
+            state.ttl_db.insert(key.clone(), expiry);
+        }
+        state.db.insert(key, entry);
+    }
+    Some(state)
+}
+
+fn unversioned(db: HashMap<String, String>) -> IndexMap<Bytes, Entry> {
+    db.into_iter().map(|(k, v)| (k.into(), Entry::new(v.into(), LEGACY_VERSION))).collect()
+}
+
+fn keyed(ttl_db: HashMap<String, u64>) -> IndexMap<Bytes, u64> {
+    ttl_db.into_iter().map(|(k, expiry)| (k.into(), expiry)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let file = File::create(&tmp_path).unwrap();
-    let mut writer = BufWriter::with_capacity(64 * 1024, file);
+    #[test]
+    fn records_round_trip() {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        bincode::serialize_into(&mut data, &(7u64, 2u64)).unwrap();
+        encode_record(&mut data, b"a", &Entry::new("1".into(), 3), None);
+        encode_record(&mut data, b"b\n\0", &Entry::new("x y".into(), 7), Some(42));
 
-    bincode::serialize_into(&mut writer, &(db, ttl_db)).unwrap();
+        let state = decode_snapshot(&data).unwrap();
+        assert_eq!(state.version, 7);
+        assert_eq!(state.db[&b"a"[..]].version, 3);
+        assert_eq!(state.db[&b"b\n\0"[..]].value, "x y");
+        assert_eq!(state.ttl_db.get(&b"b\n\0"[..]), Some(&42));
+        assert_eq!(state.ttl_db.len(), 1);
+    }
 
+    #[test]
+    fn legacy_keys_get_a_version() {
+        let db = HashMap::from([("a".to_string(), "1".to_string())]);
+        let ttl_db = HashMap::from([("a".to_string(), 42u64)]);
+        let data = bincode::serialize(&(db, ttl_db)).unwrap();
 
-    writer.flush().unwrap();
+        let state = decode_snapshot(&data).unwrap();
+        assert_eq!(state.version, LEGACY_VERSION);
+        assert_eq!(state.db[&b"a"[..]].version, LEGACY_VERSION);
+        assert_eq!(state.ttl_db.get(&b"a"[..]), Some(&42));
+    }
 
-    rename(tmp_path, final_path).unwrap();
+    #[test]
+    fn truncated_snapshots_are_refused() {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        bincode::serialize_into(&mut data, &(1u64, 2u64)).unwrap();
+        encode_record(&mut data, b"a", &Entry::new("1".into(), 1), None);
+        assert!(decode_snapshot(&data).is_none());
+    }
 }
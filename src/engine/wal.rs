@@ -1,266 +1,884 @@
-use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::io;
 use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::fs::OpenOptions;
-use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
-use tokio::sync::mpsc::{Receiver, Sender};
+use bytes::Bytes;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+#[cfg(not(feature = "io-uring"))]
+use tokio::io::BufWriter;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio::{task, time};
+use tracing::{debug, error, info, trace, warn};
 
-use super::{Command, WalCommand, apply_db, save_snapshot};
+use super::backlog::{ReplFrame, Replicas};
+use super::cdc;
+use super::command::{WalEntry, WalRecord};
+use super::raft::{RAFT_TICK, RaftMsg, RaftNode};
+use super::script::{ScriptRunner, ScriptState};
+use super::snapshot::{
+    SnapshotChunk, decode_snapshot, encode_record, install_snapshot, load_snapshot, snapshot_path, spawn_writer,
+};
+use super::{Command, Keyspace, Queued, Resp, Stray, WalCommand, apply_db};
+use crate::cluster::key_slot;
+use crate::config::{EvictionConfig, ShardConfig};
 use crate::engine::apply::now_ms;
-use crate::engine::command::WalEntry;
-use crate::engine::ParsedCommand;
+use crate::metrics::ShardMetrics;
+use crate::metrics::latency::Stage;
+use crate::raft::RaftNet;
+use crate::shard_engine::router::shard_for;
 
-pub fn start_wal_task(shard_id: usize, mut wal_rx: Receiver<WalCommand>) {
-    let filename = format!("wal_{}.log", shard_id);
-    task::spawn(async move {
-        let wal = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&filename)
-            .await
-            .expect("Failed to open WAL");
-
-        let mut writer = BufWriter::with_capacity(64 * 1024, wal);
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+const ACTIVE_EXPIRE_BACKLOG_DELAY: Duration = Duration::from_millis(1);
+const ACTIVE_EXPIRE_BUDGET: Duration = Duration::from_millis(2);
+const ACTIVE_EXPIRE_BATCH: usize = 64;
+
+// How often the snapshot triggers are checked, and how many keys go into
+// each chunk handed to the snapshot writer between commands.
+const SNAPSHOT_CHECK_PERIOD: Duration = Duration::from_secs(1);
+const SNAPSHOT_CHUNK_KEYS: usize = 1024;
+
+// Segment files start with this; older ones hold bare `WalEntry`s.
+pub const WAL_MAGIC: &[u8; 4] = b"CKW1";
+
+pub(super) const OOM_ERROR: &str = "OOM command not allowed when used memory > 'maxmemory'.\n";
+
+/// Writes a shard's log to segment files starting with `magic`: the WAL, or
+/// in Raft mode the Raft log. With io_uring it runs on an I/O worker, whose
+/// ring its appends go through.
+pub fn start_wal_task(
+    shard_id: usize,
+    magic: &'static [u8; 4],
+    mut wal_rx: Receiver<WalCommand>,
+    metrics: Arc<ShardMetrics>,
+) {
+    let wal = move || async move {
+        // Opened by the first Rotate, which the engine sends once it has
+        // replayed the existing segments.
+        let mut writer: Option<Segment> = None;
         let mut buffer = Vec::with_capacity(128 * 1024);
         let mut sync_interval = time::interval(Duration::from_millis(5));
+        // Live feeds of CDC consumers; one that can't keep up is dropped.
+        let mut subscribers: Vec<Sender<Vec<u8>>> = Vec::new();
+        // Set once a write or sync fails. The segment may have lost records
+        // by then, so no later sync can vouch for what came before.
+        let mut failed = false;
 
         loop {
             tokio::select! {
                 _ = sync_interval.tick() => {
-                    if !buffer.is_empty() {
-                        let _ = writer.write_all(&buffer).await;
-                        buffer.clear();
-                    }
+                    failed |= write_buffer(&mut writer, &mut buffer, &metrics).await.is_err();
                 }
 
                 entry = wal_rx.recv() => {
                     match entry {
                         Some(WalCommand::Write(s)) => {
                             buffer.extend_from_slice(&s);
+                            metrics.wal_pending(buffer.len());
+                            subscribers.retain(|sub| sub.try_send(s.clone()).is_ok());
 
                             if buffer.len() >= 128 * 1024 {
-                                let _ = writer.write_all(&buffer).await;
-                                let _ = writer.flush().await;
-                                buffer.clear();
+                                failed |= write_buffer(&mut writer, &mut buffer, &metrics).await.is_err();
                             }
                         }
-                        Some(WalCommand::Truncate) => {
-                            // 1. Flush BufWriter internal buffer to file
-                            writer.flush().await.unwrap();
-
-                            // 2. Write our extra WAL batch buffer directly to file
-                            if !buffer.is_empty() {
-                                let file = writer.get_mut();
-                                file.write_all(&buffer).await.unwrap();
-                                file.flush().await.unwrap();
-                                buffer.clear();
+                        Some(WalCommand::Sync(done)) => {
+                            failed |= write_buffer(&mut writer, &mut buffer, &metrics).await.is_err();
+                            // Dropping `done` unanswered tells the caller nothing
+                            // was made durable.
+                            if !failed && let Some(writer) = &mut writer {
+                                match sync_segment(writer).await {
+                                    Ok(()) => drop(done.send(())),
+                                    Err(e) => {
+                                        error!("WAL sync failed: {}", e);
+                                        failed = true;
+                                    }
+                                }
                             }
+                        }
+                        Some(WalCommand::Rotate { last_lsn }) => {
+                            failed |= write_buffer(&mut writer, &mut buffer, &metrics).await.is_err();
+                            drop(writer.take());
 
-                            // 3. Truncate file to zero
-                            let file = writer.get_mut();
-                            file.set_len(0).await.unwrap();
-                            file.seek(std::io::SeekFrom::Start(0)).await.unwrap();
-
-                            // 4. Final flush to commit metadata
-                            file.flush().await.unwrap();
+                            // A segment without records can simply be reused.
+                            let current = segment_path(shard_id);
+                            if let Ok(meta) = fs::metadata(&current).await
+                                && meta.len() > magic.len() as u64
+                            {
+                                fs::rename(&current, closed_segment_path(shard_id, last_lsn))
+                                    .await
+                                    .expect("Failed to close WAL segment");
+                            }
 
+                            debug!(shard = shard_id, last_lsn, "WAL segment rotated");
+                            let mut file = File::create(&current).await.expect("Failed to open WAL");
+                            file.write_all(magic).await.expect("Failed to write WAL");
+                            writer = Some(segment(file).await);
+                            metrics.wal_size(segments_size(shard_id).await);
+                        }
+                        Some(WalCommand::Purge { upto_lsn }) => {
+                            let mut purged = 0;
+                            for (last_lsn, path) in closed_segments(shard_id).await {
+                                if last_lsn <= upto_lsn && fs::remove_file(path).await.is_ok() {
+                                    purged += 1;
+                                }
+                            }
+                            debug!(shard = shard_id, upto_lsn, purged, "WAL segments purged");
+                            metrics.wal_size(segments_size(shard_id).await);
+                        }
+                        Some(WalCommand::Subscribe { from, history_end, out }) => {
+                            // The feed reads everything up to here from disk.
+                            failed |= write_buffer(&mut writer, &mut buffer, &metrics).await.is_err();
+                            let (live_tx, live_rx) = mpsc::channel(cdc::LIVE_CAPACITY);
+                            subscribers.push(live_tx);
+                            debug!(shard = shard_id, ?from, history_end, "CDC consumer subscribed");
+                            cdc::spawn_feed(shard_id, from, history_end, live_rx, out);
                         }
                         None => break,
                     }
                 }
             }
         }
-    });
+    };
+    #[cfg(not(feature = "io-uring"))]
+    task::spawn(wal());
+    #[cfg(feature = "io-uring")]
+    {
+        let workers = crate::uring::workers();
+        workers[shard_id % workers.len()].spawn(wal);
+    }
 }
 
-pub fn start_engine(shard_id: usize, mut cmd_rx: Receiver<Command>, wal_tx: Sender<WalCommand>) {
-    task::spawn(async move {
-        let mut db: HashMap<String, String> = HashMap::new();
-        let mut ttl_db: HashMap<String, u64> = HashMap::new();
-        let mut expiry_heap = std::collections::BinaryHeap::new();
+/// The open segment, behind a buffer of its own. With io_uring, appends go
+/// straight to the ring, which `write_buffer` batches enough already.
+#[cfg(not(feature = "io-uring"))]
+type Segment = BufWriter<File>;
+#[cfg(feature = "io-uring")]
+type Segment = crate::uring::fs::File;
 
-        let mut snapshot_interval = time::interval(Duration::from_secs(10));
-        let mut cleanup_interval = time::interval(Duration::from_millis(100));
+#[cfg(not(feature = "io-uring"))]
+async fn segment(file: File) -> Segment {
+    BufWriter::with_capacity(64 * 1024, file)
+}
+
+#[cfg(feature = "io-uring")]
+async fn segment(file: File) -> Segment {
+    Segment::from_std(file.into_std().await).expect("Failed to open WAL")
+}
 
+#[cfg(not(feature = "io-uring"))]
+async fn sync_segment(writer: &mut Segment) -> io::Result<()> {
+    writer.get_ref().sync_data().await
+}
 
-        let snapshot_file = format!("snapshot_{}.json", shard_id);
+#[cfg(feature = "io-uring")]
+async fn sync_segment(writer: &mut Segment) -> io::Result<()> {
+    writer.sync_data().await
+}
 
-        if let Ok(snapshot_data) = tokio::fs::read_to_string(&snapshot_file).await {
-            if let Ok((loaded_db, loaded_ttl)) = serde_json::from_str::<(
-                HashMap<String, String>,
-                HashMap<String, u64>,
-            )>(&snapshot_data)
-            {
-                db = loaded_db;
-                ttl_db = loaded_ttl;
-            } else if let Ok(loaded_db) =
-                serde_json::from_str::<HashMap<String, String>>(&snapshot_data)
-            {
+/// Appends the buffered records to the segment and empties the buffer,
+/// whether or not they made it.
+async fn write_buffer(writer: &mut Option<Segment>, buffer: &mut Vec<u8>, metrics: &ShardMetrics) -> io::Result<()> {
+    let mut result = Ok(());
+    if let Some(writer) = writer
+        && !buffer.is_empty()
+    {
+        let started = Instant::now();
+        result = match writer.write_all(buffer).await {
+            Ok(()) => writer.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            error!("WAL write failed: {}", e);
+        }
+        metrics.wal_write(buffer.len(), started.elapsed());
+        metrics.wal_pending(0);
+        buffer.clear();
+    }
+    result
+}
 
-                db = loaded_db;
-            }
+/// The segment currently being appended to.
+pub(super) fn segment_path(shard_id: usize) -> String {
+    format!("wal_{}.log", shard_id)
+}
+
+/// A closed segment, named after the LSN of its last record.
+fn closed_segment_path(shard_id: usize, last_lsn: u64) -> String {
+    format!("wal_{}.{:020}.log", shard_id, last_lsn)
+}
+
+/// Bytes on disk in all of a shard's segments.
+async fn segments_size(shard_id: usize) -> u64 {
+    let mut paths: Vec<PathBuf> = closed_segments(shard_id).await.into_iter().map(|(_, path)| path).collect();
+    paths.push(segment_path(shard_id).into());
+    let mut size = 0;
+    for path in paths {
+        if let Ok(meta) = fs::metadata(&path).await {
+            size += meta.len();
+        }
+    }
+    size
+}
+
+/// Closed segments of a shard, oldest first, with their last LSN.
+pub(super) async fn closed_segments(shard_id: usize) -> Vec<(u64, PathBuf)> {
+    let prefix = format!("wal_{}.", shard_id);
+    let mut segments = Vec::new();
+    let Ok(mut dir) = fs::read_dir(".").await else {
+        return segments;
+    };
+    while let Ok(Some(dirent)) = dir.next_entry().await {
+        let name = dirent.file_name();
+        let Some(lsn) = name
+            .to_str()
+            .and_then(|n| n.strip_prefix(&prefix))
+            .and_then(|n| n.strip_suffix(".log"))
+            .and_then(|n| n.parse().ok())
+        else {
+            continue;
+        };
+        segments.push((lsn, dirent.path()));
+    }
+    segments.sort();
+    segments
+}
 
-            for (k, v) in &ttl_db {
-                expiry_heap.push(Reverse((*v, k.clone())));
+/// Replays every WAL segment on top of the loaded snapshot, skipping
+/// records it already contains. Returns how many records were applied.
+async fn replay_wal(shard_id: usize, keyspace: &mut Keyspace) -> usize {
+    let mut replayed = 0;
+    let mut segments: Vec<(Option<u64>, PathBuf)> = closed_segments(shard_id)
+        .await
+        .into_iter()
+        .map(|(lsn, path)| (Some(lsn), path))
+        .collect();
+    segments.push((None, segment_path(shard_id).into()));
+
+    for (last_lsn, path) in segments {
+        let Ok(data) = fs::read(&path).await else {
+            continue;
+        };
+        let version = keyspace.version();
+        let records = decode_segment(&data, |count| {
+            last_lsn.map_or(version, |lsn| lsn.saturating_sub(count as u64))
+        });
+        for record in records {
+            if record.lsn > keyspace.version() {
+                apply_db(keyspace, record.entry.into(), record.timestamp);
+                replayed += 1;
             }
         }
+    }
+    replayed
+}
 
-        let wal_file = format!("wal_{}.log", shard_id);
-        if let Ok(wal_content) = tokio::fs::read(&wal_file).await {
-            let mut slice = wal_content.as_slice();
-            
-            while !slice.is_empty() {
-                match bincode::deserialize_from::<_,WalEntry>(&mut slice) {
-                    Ok(entry) => {
-                        match entry {
-                            WalEntry::Set { key, value } => {
-                                apply_db(&mut db, &mut ttl_db, &mut expiry_heap, ParsedCommand::Set { key, value });
-                            },
-                            WalEntry::SetEx { key, value, ttl } => {
-                                apply_db(&mut db, &mut ttl_db, &mut expiry_heap, ParsedCommand::SetEx { key, value, ttl });
-                            },
-                            WalEntry::Del { key } => {
-                                apply_db(&mut db, &mut ttl_db, &mut expiry_heap, ParsedCommand::Del { key });
-                            },
-                            WalEntry::Expire { key, ttl } => {
-                                apply_db(&mut db, &mut ttl_db, &mut expiry_heap, ParsedCommand::Expire { key, ttl });
-                            },
-                        }
-                    }
-                    Err(_) => break,
-                }
+/// Decodes the records of a segment, stopping at the first torn one.
+/// Segments written before records carried an LSN hold bare entries; those
+/// are numbered on from what `legacy_base` returns for their count.
+pub(super) fn decode_segment(data: &[u8], legacy_base: impl FnOnce(usize) -> u64) -> Vec<WalRecord> {
+    let mut records = Vec::new();
+    if let Some(mut slice) = data.strip_prefix(WAL_MAGIC) {
+        while !slice.is_empty() {
+            match bincode::deserialize_from::<_, WalRecord>(&mut slice) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
             }
         }
+        return records;
+    }
+
+    let mut slice = data;
+    let mut entries = Vec::new();
+    while !slice.is_empty() {
+        match bincode::deserialize_from::<_, WalEntry>(&mut slice) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+    let base = legacy_base(entries.len());
+    let now = now_ms();
+    records.extend(entries.into_iter().zip(base + 1..).map(|(entry, lsn)| WalRecord {
+        lsn,
+        timestamp: now,
+        entry,
+    }));
+    records
+}
+
+pub fn start_engine(
+    shard_id: usize,
+    mut cmd_rx: Receiver<Queued>,
+    wal_tx: Sender<WalCommand>,
+    script_state: Arc<ScriptState>,
+    config: ShardConfig,
+    raft_net: Option<Arc<RaftNet>>,
+    metrics: Arc<ShardMetrics>,
+) {
+    task::spawn(async move {
+        let mut keyspace = Keyspace::default();
+        let mut scripts = ScriptRunner::new(script_state, config.lua_time_limit);
+
+        let cleanup_timer = time::sleep(ACTIVE_EXPIRE_PERIOD);
+        tokio::pin!(cleanup_timer);
+
+        let recovery = Instant::now();
+        if let Some(snapshot) = task::spawn_blocking(move || load_snapshot(shard_id)).await.unwrap() {
+            keyspace = Keyspace::from_snapshot(snapshot, now_ms());
+        }
+        let mut saved_version = keyspace.version();
+        let snapshot_keys = keyspace.db().len();
+
+        // In Raft mode the log replaces the WAL, and its entries only get
+        // applied once a leader says they're committed.
+        let mut replayed = 0;
+        let mut raft = match raft_net {
+            Some(net) => Some(RaftNode::load(shard_id, net, keyspace.version()).await),
+            None => {
+                replayed = replay_wal(shard_id, &mut keyspace).await;
+                None
+            }
+        };
+        info!(
+            shard = shard_id,
+            snapshot_keys,
+            wal_records = replayed,
+            keys = keyspace.db().len(),
+            version = keyspace.version(),
+            took_ms = recovery.elapsed().as_millis() as u64,
+            "shard recovered"
+        );
+        let last_lsn = raft.as_ref().map_or(keyspace.version(), RaftNode::last_index);
+        let _ = wal_tx.send(WalCommand::Rotate { last_lsn }).await;
+        let mut raft_tick = time::interval(RAFT_TICK);
+
+        // A snapshot in progress: `snapshot_chunks` feeds its writer until
+        // every key is sent, then `snapshot_done` reports how it went. SAVE
+        // callers and followers needing a full sync wait in `save_waiters` and
+        // `sync_waiters`, or in the `pending_` lists for the next snapshot if
+        // the running one can't serve them.
+        let mut snapshot_chunks: Option<Sender<SnapshotChunk>> = None;
+        let mut snapshot_done: Option<JoinHandle<io::Result<()>>> = None;
+        let mut snapshot_version = 0;
+        let mut save_waiters: Vec<Resp> = Vec::new();
+        let mut pending_saves: Vec<Resp> = Vec::new();
+        let mut sync_waiters: Vec<Sender<ReplFrame>> = Vec::new();
+        let mut pending_syncs: Vec<Sender<ReplFrame>> = Vec::new();
+        let mut replicas = Replicas::default();
+        let mut last_save = now_ms() / 1000;
+        let mut last_snapshot = Instant::now();
+        let mut snapshot_check = time::interval(SNAPSHOT_CHECK_PERIOD);
 
         let mut encoded = Vec::with_capacity(128);
 
         loop {
+            metrics.engine(&keyspace, last_save, snapshot_done.is_some());
             tokio::select! {
-                _ = cleanup_interval.tick() => {
-                    let now = now_ms();
-                    let mut expired_count = 0;
-                    while expired_count < 200 {
-                        match expiry_heap.peek() {
-                            Some(Reverse((exp, _))) if *exp <= now => {
-                                let Reverse((exp2, key)) = expiry_heap.pop().unwrap();
-                                if ttl_db.get(&key) == Some(&exp2) {
-                                    db.remove(&key);
-                                    ttl_db.remove(&key);
-                                }
-                                expired_count += 1;
-                            }
-                            _ => break,
-                        }
-                    }
+                _ = &mut cleanup_timer => {
+                    // Expire in small batches until the budget runs out. With a
+                    // backlog left, come back right away instead of waiting for
+                    // the next period, so bursts of expiring keys drain quickly.
+                    let started = Instant::now();
+                    let clock = raft.as_ref().map_or_else(now_ms, RaftNode::clock);
+                    while keyspace.remove_expired(clock, ACTIVE_EXPIRE_BATCH) == ACTIVE_EXPIRE_BATCH
+                        && started.elapsed() < ACTIVE_EXPIRE_BUDGET
+                    {}
+                    let next = if keyspace.has_expired() {
+                        ACTIVE_EXPIRE_BACKLOG_DELAY
+                    } else {
+                        ACTIVE_EXPIRE_PERIOD
+                    };
+                    cleanup_timer.as_mut().reset(Instant::now() + next);
                 }
 
-                _ = snapshot_interval.tick() => {
-                    let db_snapshot = db.clone();
-                    let ttl_snapshot = ttl_db.clone();
-                    let wal_tx_clone = wal_tx.clone();
-
-                    task::spawn_blocking(move || {
-                        let combined_state = (db_snapshot, ttl_snapshot);
-                        save_snapshot(shard_id,&combined_state.0, &combined_state.1);
-                    }).await.unwrap();
+                _ = raft_tick.tick(), if raft.is_some() => {
+                    let raft = raft.as_mut().unwrap();
+                    raft.tick(&wal_tx).await;
+                    raft.sync(&wal_tx).await;
+                    raft.apply(&mut keyspace);
+                }
 
+                _ = snapshot_check.tick() => {
+                    let writes = keyspace.version() - saved_version;
+                    let due = (writes > 0 && last_snapshot.elapsed() >= config.snapshot.interval)
+                        || (config.snapshot.writes > 0 && writes >= config.snapshot.writes);
+                    if due && snapshot_done.is_none() {
+                        let (version, chunks, done) = begin_snapshot(shard_id, &mut keyspace, &wal_tx, raft.as_ref(), &metrics).await;
+                        (snapshot_version, snapshot_chunks, snapshot_done) = (version, Some(chunks), Some(done));
+                    }
+                }
 
-                    let _ = wal_tx_clone.send(WalCommand::Truncate).await;
+                // Only build a chunk once the writer has room for it, so a slow
+                // disk never holds up commands.
+                permit = async { snapshot_chunks.clone().unwrap().reserve_owned().await }, if snapshot_chunks.is_some() => {
+                    let Ok(permit) = permit else {
+                        // The writer failed; `snapshot_done` has the error.
+                        keyspace.abort_snapshot();
+                        snapshot_chunks = None;
+                        continue;
+                    };
+                    let mut chunk = Vec::with_capacity(64 * 1024);
+                    let mut records = 0;
+                    let finished = keyspace.snapshot_chunk(SNAPSHOT_CHUNK_KEYS, |key, entry, expiry| {
+                        encode_record(&mut chunk, key, entry, expiry);
+                        records += 1;
+                    });
+                    permit.send((records, chunk));
+                    if finished {
+                        snapshot_chunks = None;
+                    }
                 }
 
-                Some(cmd) = cmd_rx.recv() => {
-                    let now = now_ms();
-                    match cmd {
-                        Command::Set { key, value, resp } => {
-
-                            let entry = WalEntry::Set { key: key.clone(), value: value.clone() };
-                            // let mut encoded = Vec::with_capacity(64);
-                            encoded.clear();
-                            bincode::serialize_into(&mut encoded, &entry).unwrap();
-                            // let log = format!("SET {} {}\n", key, value);
-                            let _ = wal_tx.send(WalCommand::Write(mem::take(&mut encoded))).await;
-                            db.insert(key, value);
-                            let _ = resp.send("OK\n".to_string());
-                        }
-                        Command::SetEx { key, value, ttl, resp } => {
-                            let entry = WalEntry::SetEx { key: key.clone(), value: value.clone(), ttl };
-                            // let mut encoded = Vec::with_capacity(64);
-                            encoded.clear();
-                            bincode::serialize_into(&mut encoded, &entry).unwrap();
-                            // let log = format!("SETEX {} {} {}\n", key, value, ttl);
-                            let expiry = now + ttl * 1000;
-                            let _ = wal_tx.send(WalCommand::Write(mem::take(&mut encoded))).await;
-                            db.insert(key.clone(), value);
-                            ttl_db.insert(key.clone(), expiry);
-                            expiry_heap.push(Reverse((expiry, key)));
-                            let _ = resp.send("OK\n".to_string());
-                        }
-                        Command::Get { key, resp } => {
-                            if let Some(&expiry) = ttl_db.get(&key) {
-                                if expiry <= now {
-                                    db.remove(&key);
-                                    ttl_db.remove(&key);
-                                }
+                result = async { snapshot_done.as_mut().unwrap().await }, if snapshot_done.is_some() && snapshot_chunks.is_none() => {
+                    snapshot_done = None;
+                    last_snapshot = Instant::now();
+                    let result = result.unwrap();
+                    let reply: Bytes = match &result {
+                        Ok(()) => {
+                            saved_version = snapshot_version;
+                            last_save = now_ms() / 1000;
+                            if let Some(raft) = &mut raft {
+                                raft.compact(snapshot_version);
                             }
-                            let value = db.get(&key).cloned().unwrap_or_else(|| "nil\n".into());
-                            let _ = resp.send(value);
+                            let _ = wal_tx.send(WalCommand::Purge { upto_lsn: snapshot_version }).await;
+                            "OK\n".into()
                         }
-                        Command::Expire { key, ttl, resp } => {
-                            // let log = format!("EXPIRE {} {}\n", key, ttl);
-                            let entry = WalEntry::Expire { key: key.clone(), ttl };
-                            // let mut encoded = Vec::with_capacity(64);
-                            encoded.clear();
-                            bincode::serialize_into(&mut encoded, &entry).unwrap();
-                            if db.contains_key(&key) {
-                                let expiry = now + ttl * 1000;
-                                let _ = wal_tx.send(WalCommand::Write(mem::take(&mut encoded))).await;
-                                ttl_db.insert(key.clone(), expiry);
-                                expiry_heap.push(Reverse((expiry, key)));
-                                let _ = resp.send("1".into());
-                            } else {
-                                let _ = resp.send("0".into());
-                            }
+                        Err(e) => {
+                            error!(shard = shard_id, "snapshot failed: {}", e);
+                            metrics.snapshot_failed();
+                            format!("ERR snapshot failed: {}\n", e).into()
+                        }
+                    };
+                    for waiter in save_waiters.drain(..) {
+                        let _ = waiter.send(reply.clone());
+                    }
+                    // Followers get the file we just wrote; a failed snapshot
+                    // drops them and they try again.
+                    for stream in sync_waiters.drain(..) {
+                        if result.is_ok()
+                            && let Ok(snapshot) = std::fs::File::open(snapshot_path(shard_id))
+                            && !replicas.attach(stream, snapshot_version, snapshot)
+                        {
+                            warn!(shard = shard_id, version = snapshot_version, "backlog lost records past the snapshot; follower must sync again");
                         }
-                        Command::Del { key, resp } => {
-                            // let log = format!("DEL {}\n", key);
-                            let entry = WalEntry::Del { key: key.clone() };
-                            // let mut encoded = Vec::with_capacity(64);
-                            encoded.clear();
-                            bincode::serialize_into(&mut encoded, &entry).unwrap();
-                            let _ = wal_tx.send(WalCommand::Write(mem::take(&mut encoded))).await;
-                            let removed = db.remove(&key).is_some();
-                            ttl_db.remove(&key);
-                            let _ = resp.send(if removed { "1".into() } else { "0".into() });
+                    }
+                    replicas.release();
+
+                    if !pending_saves.is_empty() || !pending_syncs.is_empty() {
+                        let (version, chunks, done) = begin_snapshot(shard_id, &mut keyspace, &wal_tx, raft.as_ref(), &metrics).await;
+                        (snapshot_version, snapshot_chunks, snapshot_done) = (version, Some(chunks), Some(done));
+                        save_waiters.append(&mut pending_saves);
+                        if !pending_syncs.is_empty() {
+                            replicas.hold(version);
                         }
-                        Command::Ex { key, resp } => {
-                            let exists = db.contains_key(&key);
-                            let _ = resp.send(if exists { "1".into() } else { "0".into() });
+                        sync_waiters.append(&mut pending_syncs);
+                    }
+                }
+
+                Some(Queued { cmds, at }) = cmd_rx.recv() => {
+                    for (cmd, span) in cmds {
+                        let now = now_ms();
+                        let name = cmd.name();
+                        let waited = at.elapsed();
+                        metrics.latency.record(name, Stage::Queue, waited);
+                        trace!(parent: &span, shard = shard_id, queue_us = waited.as_micros() as u64, "picked up by shard");
+                        // Recorded when this command is done, whichever way it ends.
+                        let _timer = metrics.engine_timer(name, &span);
+
+                        let cmd = match &mut raft {
+                            Some(raft) => {
+                                let full = config.eviction.maxmemory > 0
+                                    && keyspace.used_memory() > config.eviction.maxmemory;
+                                let Some(cmd) = raft.handle(cmd, full, &wal_tx).await else {
+                                    continue;
+                                };
+                                // Writes queued ahead of this one in the batch come first.
+                                raft.sync(&wal_tx).await;
+                                raft.apply(&mut keyspace);
+                                cmd
+                            }
+                            None => cmd,
+                        };
+
+                        if cmd.may_grow_memory()
+                            && !make_room(&mut keyspace, &config.eviction, &wal_tx, &mut encoded, &mut replicas, &metrics).await
+                        {
+                            cmd.reject(OOM_ERROR);
+                            continue;
                         }
-                        Command::Ttl { key, resp } => {
-                            if !db.contains_key(&key) {
-                                let _ = resp.send("-2".into());
-                            } else if let Some(&expiry) = ttl_db.get(&key) {
-                                if expiry <= now {
-                                    db.remove(&key);
-                                    ttl_db.remove(&key);
-                                    let _ = resp.send("-2".into());
+
+                        let version = keyspace.version();
+                        match cmd {
+                            Command::Set { key, value, resp } => {
+                                commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, WalEntry::Set { key, value }, now).await;
+                                let _ = resp.send("OK\n".into());
+                            }
+                            Command::SetEx { key, value, ttl, resp } => {
+                                commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, WalEntry::SetEx { key, value, ttl }, now).await;
+                                let _ = resp.send("OK\n".into());
+                            }
+                            Command::Get { key, resp } => {
+                                let value = keyspace.get(&key, now).map(|e| e.value.clone()).unwrap_or_else(|| "nil\n".into());
+                                let _ = resp.send(value);
+                            }
+                            Command::Expire { key, ttl, resp } => {
+                                if keyspace.contains_key(&key) {
+                                    commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, WalEntry::Expire { key, ttl }, now).await;
+                                    let _ = resp.send("1".into());
                                 } else {
+                                    let _ = resp.send("0".into());
+                                }
+                            }
+                            Command::Del { key, resp } => {
+                                let removed = keyspace.contains_key(&key);
+                                commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, WalEntry::Del { key }, now).await;
+                                let _ = resp.send(if removed { "1".into() } else { "0".into() });
+                            }
+                            Command::Ex { key, resp } => {
+                                let exists = keyspace.contains_key(&key);
+                                let _ = resp.send(if exists { "1".into() } else { "0".into() });
+                            }
+                            Command::Ttl { key, resp } => {
+                                if keyspace.expire_if_needed(&key, now) || !keyspace.contains_key(&key) {
+                                    let _ = resp.send("-2".into());
+                                } else if let Some(expiry) = keyspace.expiry(&key) {
                                     let ttl = (expiry - now) / 1000;
-                                    let _ = resp.send(ttl.to_string());
+                                    let _ = resp.send(ttl.to_string().into());
+                                } else {
+                                    let _ = resp.send("-1".into());
+                                }
+                            }
+                            Command::Ping { resp } => {
+                                let _ = resp.send("PONG\n".into());
+                            }
+                            Command::GetVer { key, resp } => {
+                                let reply = match keyspace.get(&key, now) {
+                                    Some(e) => [&e.value[..], format!(" {}\n", e.version).as_bytes()].concat().into(),
+                                    None => "nil\n".into(),
+                                };
+                                let _ = resp.send(reply);
+                            }
+                            Command::SetIfVer { key, value, version: expected, resp } => {
+                                keyspace.expire_if_needed(&key, now);
+                                // Version 0 stands for "key must not exist".
+                                let current = keyspace.db().get(&key).map_or(0, |e| e.version);
+                                if current != expected {
+                                    let _ = resp.send("0\n".into());
+                                    continue;
+                                }
+                                commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, WalEntry::Set { key, value }, now).await;
+                                let _ = resp.send(format!("{}\n", keyspace.version()).into());
+                            }
+                            Command::DelIfVer { key, version: expected, resp } => {
+                                keyspace.expire_if_needed(&key, now);
+                                if keyspace.db().get(&key).map(|e| e.version) != Some(expected) {
+                                    let _ = resp.send("0\n".into());
+                                    continue;
+                                }
+                                commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, WalEntry::Del { key }, now).await;
+                                let _ = resp.send("1\n".into());
+                            }
+                            Command::Eval { sha, source, keys, args, resp } => {
+                                // Scripts can run for seconds; hand this worker's queue to
+                                // another thread so SCRIPT KILL and other shards stay responsive.
+                                // A shard with a core of its own just blocks it.
+                                let run = || scripts.run(&sha, &source, keys, args, &keyspace);
+                                let result = match Handle::current().runtime_flavor() {
+                                    RuntimeFlavor::MultiThread => task::block_in_place(run),
+                                    _ => run(),
+                                };
+                                match result {
+                                    Ok(outcome) => {
+                                        for entry in outcome.effects {
+                                            commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, entry, now).await;
+                                        }
+                                        let _ = resp.send(outcome.reply);
+                                    }
+                                    Err(e) => {
+                                        let _ = resp.send(format!("{}\n", e).into());
+                                    }
+                                }
+                            }
+                            Command::ScriptFlush { resp } => {
+                                scripts.flush();
+                                let _ = resp.send("OK\n".into());
+                            }
+                            Command::Save { background, resp } => {
+                                if snapshot_done.is_none() {
+                                    let (version, chunks, done) = begin_snapshot(shard_id, &mut keyspace, &wal_tx, raft.as_ref(), &metrics).await;
+                                    (snapshot_version, snapshot_chunks, snapshot_done) = (version, Some(chunks), Some(done));
+                                    if background {
+                                        let _ = resp.send("Background saving started\n".into());
+                                    } else {
+                                        save_waiters.push(resp);
+                                    }
+                                } else if background {
+                                    let _ = resp.send("ERR Background save already in progress\n".into());
+                                } else {
+                                    // The running snapshot began before this call, so
+                                    // it might miss writes the caller expects saved.
+                                    pending_saves.push(resp);
                                 }
-                            } else {
-                                let _ = resp.send("-1".into());
+                            }
+                            Command::LastSave { resp } => {
+                                let _ = resp.send(format!("{}\n", last_save).into());
+                            }
+                            Command::Psync { lsn, stream } => {
+                                let version = keyspace.version();
+                                let Err(stream) = replicas.resume(stream, lsn, config.repl_backlog, version) else {
+                                    continue;
+                                };
+                                // Full sync: the next snapshot, then the backlog from
+                                // its version on.
+                                if snapshot_done.is_none() {
+                                    let (version, chunks, done) = begin_snapshot(shard_id, &mut keyspace, &wal_tx, raft.as_ref(), &metrics).await;
+                                    (snapshot_version, snapshot_chunks, snapshot_done) = (version, Some(chunks), Some(done));
+                                    replicas.hold(version);
+                                    sync_waiters.push(stream);
+                                } else if replicas.covers(snapshot_version) {
+                                    replicas.hold(snapshot_version);
+                                    sync_waiters.push(stream);
+                                } else {
+                                    pending_syncs.push(stream);
+                                }
+                            }
+                            Command::ApplyRecords { records, resp } => {
+                                let mut error = None;
+                                for encoded_record in records {
+                                    let Ok(record) = bincode::deserialize::<WalRecord>(&encoded_record) else {
+                                        error = Some("ERR malformed WAL record\n".to_string());
+                                        break;
+                                    };
+                                    if record.lsn <= keyspace.version() {
+                                        continue;
+                                    }
+                                    if record.lsn != keyspace.version() + 1 {
+                                        error = Some(format!("ERR expected LSN {}, got {}\n", keyspace.version() + 1, record.lsn));
+                                        break;
+                                    }
+                                    replicas.feed(&encoded_record);
+                                    let _ = wal_tx.send(WalCommand::Write(encoded_record)).await;
+                                    apply_db(&mut keyspace, record.entry.into(), record.timestamp);
+                                }
+                                let _ = resp.send(error.unwrap_or_else(|| format!("{}\n", keyspace.version())).into());
+                            }
+                            Command::LoadSnapshot { snapshot, resp } => {
+                                let last_lsn = keyspace.version();
+                                let loaded = replace_data(shard_id, &mut keyspace, snapshot, last_lsn, &wal_tx, &mut snapshot_chunks, &mut snapshot_done).await;
+                                if let Err(e) = loaded {
+                                    let _ = resp.send(e.into());
+                                    continue;
+                                }
+                                saved_version = keyspace.version();
+                                last_save = now / 1000;
+                                last_snapshot = Instant::now();
+                                // Our own followers have to start over too.
+                                replicas = Replicas::default();
+                                sync_waiters.clear();
+                                pending_syncs.clear();
+                                for waiter in save_waiters.drain(..).chain(pending_saves.drain(..)) {
+                                    let _ = waiter.send("OK\n".into());
+                                }
+                                let _ = resp.send("OK\n".into());
+                            }
+                            Command::CdcSubscribe { from, out } => {
+                                let _ = wal_tx.send(WalCommand::Subscribe { from, history_end: keyspace.version(), out }).await;
+                            }
+                            Command::ReplOffset { resp } => {
+                                let _ = resp.send(format!("{}\n", keyspace.version()).into());
+                            }
+                            Command::Raft { from, msg: RaftMsg::Snapshot { term, index, index_term, members, data } } => {
+                                let Some(node) = &mut raft else {
+                                    continue;
+                                };
+                                if !node.accept_snapshot(&from, term, index) {
+                                    continue;
+                                }
+                                let last_lsn = node.last_index();
+                                let loaded = replace_data(shard_id, &mut keyspace, data, last_lsn, &wal_tx, &mut snapshot_chunks, &mut snapshot_done).await;
+                                if let Err(e) = loaded {
+                                    error!(shard = shard_id, "can't install the leader's snapshot: {}", e.trim());
+                                    continue;
+                                }
+                                saved_version = keyspace.version();
+                                last_save = now / 1000;
+                                last_snapshot = Instant::now();
+                                for waiter in save_waiters.drain(..).chain(pending_saves.drain(..)) {
+                                    let _ = waiter.send("OK\n".into());
+                                }
+                                node.installed(&from, index, index_term, members);
+                            }
+                            Command::Raft { from, msg } => {
+                                if let Some(raft) = &mut raft {
+                                    raft.step(&from, msg, &wal_tx).await;
+                                    raft.sync(&wal_tx).await;
+                                    raft.apply(&mut keyspace);
+                                }
+                            }
+                            Command::RaftChange { add, node, resp } => {
+                                let reply = match &mut raft {
+                                    Some(raft) => raft.request_change(add, node, &wal_tx).await,
+                                    None => "ERR raft mode is off\n".into(),
+                                };
+                                let _ = resp.send(reply.into());
+                            }
+                            Command::RaftInfo { resp } => {
+                                let _ = resp.send(raft.as_ref().map_or_else(String::new, |r| r.info(keyspace.version())).into());
+                            }
+                            Command::Restore { key, value, ttl_ms, replace, resp } => {
+                                keyspace.expire_if_needed(&key, now);
+                                if !replace && keyspace.contains_key(&key) {
+                                    let _ = resp.send("BUSYKEY Target key name already exists.\n".into());
+                                    continue;
+                                }
+                                commit(&mut keyspace, &wal_tx, &mut encoded, &mut replicas, WalEntry::Restore { key, value, ttl_ms }, now).await;
+                                let _ = resp.send("OK\n".into());
+                            }
+                            Command::Dump { key, resp } => {
+                                let reply = match keyspace.peek(&key, now) {
+                                    Some((entry, expiry)) => {
+                                        let ttl_ms = expiry.map_or(0, |exp| exp - now);
+                                        [format!("{} {} ", ttl_ms, entry.version).as_bytes(), &entry.value].concat().into()
+                                    }
+                                    None => "nil\n".into(),
+                                };
+                                let _ = resp.send(reply);
+                            }
+                            Command::KeysInSlot { slot, count, resp } => {
+                                let mut reply = Vec::new();
+                                for key in keyspace.db().keys().filter(|k| key_slot(k) == slot && keyspace.peek(k, now).is_some()).take(count) {
+                                    reply.extend_from_slice(key);
+                                    reply.push(b'\n');
+                                }
+                                let _ = resp.send(reply.into());
+                            }
+                            Command::CountKeysInSlot { slot, resp } => {
+                                let count = keyspace.db().keys().filter(|k| key_slot(k) == slot && keyspace.peek(k, now).is_some()).count();
+                                let _ = resp.send(count.to_string().into());
+                            }
+                            Command::Strays { shards, out } => {
+                                let strays = keyspace
+                                    .db()
+                                    .keys()
+                                    .filter(|k| shard_for(k, shards) != shard_id)
+                                    .filter_map(|k| {
+                                        let (entry, expiry) = keyspace.peek(k, now)?;
+                                        let ttl_ms = expiry.map_or(0, |exp| (exp - now).max(1));
+                                        Some(Stray { key: k.clone(), value: entry.value.clone(), ttl_ms, version: entry.version })
+                                    })
+                                    .collect();
+                                let _ = out.send(strays);
                             }
                         }
-                        Command::Ping { resp } => {
-                            let _ = resp.send("PONG\n".to_string());
+                        if keyspace.version() > version {
+                            trace!(parent: &span, shard = shard_id, lsn = keyspace.version(), "written to WAL");
                         }
-                        
+                    }
+                    // The whole batch's proposals go to disk with one sync.
+                    if let Some(raft) = &mut raft {
+                        raft.sync(&wal_tx).await;
+                        raft.apply(&mut keyspace);
                     }
                 }
             }
         }
     });
 }
+
+/// Logs `entry` to the WAL, sends it to followers and applies it to the
+/// keyspace.
+async fn commit(
+    keyspace: &mut Keyspace,
+    wal_tx: &Sender<WalCommand>,
+    encoded: &mut Vec<u8>,
+    replicas: &mut Replicas,
+    entry: WalEntry,
+    now: u64,
+) {
+    let record = WalRecord {
+        lsn: keyspace.version() + 1,
+        timestamp: now,
+        entry,
+    };
+    encoded.clear();
+    bincode::serialize_into(&mut *encoded, &record).unwrap();
+    replicas.feed(encoded);
+    let _ = wal_tx.send(WalCommand::Write(mem::take(encoded))).await;
+    apply_db(keyspace, record.entry.into(), now);
+}
+
+/// Starts a snapshot of the keyspace as it is now. The WAL moves on to a new
+/// segment so the old ones can be purged once the snapshot is on disk. A
+/// Raft log may run ahead of what's applied, so its segment is named after
+/// its last entry instead.
+async fn begin_snapshot(
+    shard_id: usize,
+    keyspace: &mut Keyspace,
+    wal_tx: &Sender<WalCommand>,
+    raft: Option<&RaftNode>,
+    metrics: &Arc<ShardMetrics>,
+) -> (u64, Sender<SnapshotChunk>, JoinHandle<io::Result<()>>) {
+    let (version, count) = keyspace.begin_snapshot();
+    let last_lsn = raft.map_or(version, RaftNode::last_index);
+    let _ = wal_tx.send(WalCommand::Rotate { last_lsn }).await;
+    let (chunks, done) = spawn_writer(shard_id, version, count, metrics.clone());
+    (version, chunks, done)
+}
+
+/// Replaces the shard's data with a snapshot file received from a primary or
+/// Raft leader. Any snapshot of our own is abandoned first, and the log is
+/// dropped since none of it applies to the new data.
+async fn replace_data(
+    shard_id: usize,
+    keyspace: &mut Keyspace,
+    snapshot: Vec<u8>,
+    last_lsn: u64,
+    wal_tx: &Sender<WalCommand>,
+    snapshot_chunks: &mut Option<Sender<SnapshotChunk>>,
+    snapshot_done: &mut Option<JoinHandle<io::Result<()>>>,
+) -> Result<(), String> {
+    let Some(state) = decode_snapshot(&snapshot) else {
+        return Err("ERR malformed snapshot\n".into());
+    };
+    if let Some(done) = snapshot_done.take() {
+        keyspace.abort_snapshot();
+        *snapshot_chunks = None;
+        let _ = done.await;
+    }
+    let _ = wal_tx.send(WalCommand::Rotate { last_lsn }).await;
+    let _ = wal_tx.send(WalCommand::Purge { upto_lsn: u64::MAX }).await;
+    task::spawn_blocking(move || install_snapshot(shard_id, &snapshot))
+        .await
+        .unwrap()
+        .map_err(|e| format!("ERR can't write snapshot: {}\n", e))?;
+    *keyspace = Keyspace::from_snapshot(state, now_ms());
+    Ok(())
+}
+
+/// Evicts keys until the shard is back under its share of `maxmemory`.
+/// Evictions are logged as deletes so replay ends up with the same keys.
+/// Returns false if the limit can't be met and the write must be refused.
+async fn make_room(
+    keyspace: &mut Keyspace,
+    eviction: &EvictionConfig,
+    wal_tx: &Sender<WalCommand>,
+    encoded: &mut Vec<u8>,
+    replicas: &mut Replicas,
+    metrics: &ShardMetrics,
+) -> bool {
+    if eviction.maxmemory == 0 {
+        return true;
+    }
+    while keyspace.used_memory() > eviction.maxmemory {
+        let now = now_ms();
+        let Some(key) = keyspace.pick_victim(eviction.policy, eviction.samples, now) else {
+            return false;
+        };
+        commit(keyspace, wal_tx, encoded, replicas, WalEntry::Del { key }, now).await;
+        metrics.evicted();
+    }
+    true
+}
@@ -1,21 +1,199 @@
+use std::io::{self, IsTerminal};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use tokio::net::TcpListener;
+use tokio::runtime::{self, Runtime};
+use tracing::{error, info};
+use tracing_subscriber::EnvFilter;
 
+use crate::acl::Acl;
+use crate::cluster::Cluster;
+use crate::config::Config;
+use crate::raft::RaftNet;
+use crate::replication::Replication;
 use crate::shard_engine::router::ShardRouter;
 
+mod acl;
+mod cdc;
+mod cluster;
+mod config;
 mod engine;
+mod metrics;
+mod raft;
+mod replication;
 mod server;
 mod shard_engine;
+#[cfg(feature = "io-uring")]
+mod uring;
 
 const NUM_SHARDS: usize = 16;
+// I/O workers when `io-cores` doesn't say.
+const IO_WORKERS: usize = 6;
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 6)]
-async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("Server listening on port 3000");
-    let shards = shard_engine::engine::spawn_shards(NUM_SHARDS);
-    let router = Arc::new(ShardRouter::new(shards));
+fn main() {
+    let config = Config::from_args().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+    init_logging(&config);
+    io_runtime(&config).block_on(serve(config));
+}
+
+/// Puts keys from before `{hash tags}` picked the shard where they now
+/// belong, before any client can look for them there. A replica gets its
+/// primary's moves. A Raft group's nodes would each move keys on their
+/// own, so a Raft node holding any refuses to start instead.
+async fn move_strays(router: &ShardRouter, config: &Config) {
+    if config.raft_address().is_some() {
+        for shard_id in 0..NUM_SHARDS {
+            let strays = router.strays(shard_id).await.len();
+            if strays > 0 {
+                error!(shard = shard_id, strays, "keys on the wrong shard for their hash tag; load them into a fresh group");
+                std::process::exit(1);
+            }
+        }
+    } else if config.replicaof.is_none() {
+        let moved = router.move_strays().await;
+        if moved > 0 {
+            info!(moved, "moved keys to the shard their hash tag picks");
+        }
+    }
+}
+
+/// The I/O workers' runtime, with one worker pinned to each of `io-cores`
+/// if set. Blocking threads share those cores too.
+fn io_runtime(config: &Config) -> Runtime {
+    let mut builder = runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    match config.io_cores.clone() {
+        Some(cores) => {
+            let next = AtomicUsize::new(0);
+            builder.worker_threads(cores.len()).on_thread_start(move || {
+                let core = cores[next.fetch_add(1, Ordering::Relaxed) % cores.len()];
+                shard_engine::cores::pin(core);
+            });
+        }
+        None => {
+            builder.worker_threads(IO_WORKERS);
+        }
+    }
+    builder.build().unwrap()
+}
+
+async fn serve(config: Config) {
+    let listeners = match config.port {
+        0 => Vec::new(),
+        port => server::bind(port, config.acceptors).unwrap_or_else(|e| {
+            error!("can't listen on port {}: {}", port, e);
+            std::process::exit(1);
+        }),
+    };
+    let tls_listeners = match config.tls_port {
+        Some(port) => {
+            let acceptor = server::tls::acceptor(&config).unwrap_or_else(|e| {
+                error!("can't set up TLS: {}", e);
+                std::process::exit(1);
+            });
+            let listeners = server::bind(port, config.acceptors).unwrap_or_else(|e| {
+                error!("can't listen on TLS port {}: {}", port, e);
+                std::process::exit(1);
+            });
+            Some((listeners, acceptor))
+        }
+        None => None,
+    };
+    let unix_listener = config.unixsocket.as_ref().map(|path| {
+        server::bind_unix(path, config.unixsocketperm).unwrap_or_else(|e| {
+            error!("can't listen on {}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    info!(
+        port = config.port,
+        tls_port = config.tls_port,
+        acceptors = config.acceptors,
+        unixsocket = config.unixsocket,
+        shards = NUM_SHARDS,
+        shard_cores = ?config.shard_cores,
+        io_cores = ?config.io_cores,
+        version = env!("CARGO_PKG_VERSION"),
+        "server listening"
+    );
+    // Before the shards, whose WAL tasks run on the workers.
+    #[cfg(feature = "io-uring")]
+    uring::start(IO_WORKERS, config.io_cores.as_deref()).unwrap_or_else(|e| {
+        error!("can't set up io_uring: {}", e);
+        std::process::exit(1);
+    });
+    let raft = config
+        .raft_address()
+        .map(|me| Arc::new(RaftNet::new(me, config.raft_peers.clone())));
+    let shards = shard_engine::engine::spawn_shards(NUM_SHARDS, &config, raft.clone());
+    let cluster = config.cluster_address().map(|me| {
+        Arc::new(Cluster::load(me).unwrap_or_else(|e| {
+            error!("can't load the cluster config: {}", e);
+            std::process::exit(1);
+        }))
+    });
+    if let Some(cluster) = &cluster {
+        cluster::gossip::spawn(cluster.clone());
+    }
+    let acl = Acl::load(&config).unwrap_or_else(|e| {
+        error!("can't load the ACL users: {}", e);
+        std::process::exit(1);
+    });
+    acl::set_link_auth(&config);
+    let router = Arc::new(ShardRouter::new(shards, config.clone(), Replication::new(config.port), raft, cluster, acl));
+    move_strays(&router, &config).await;
+    if config.replicaof.is_some() {
+        replication::replicaof(&router, config.replicaof.clone());
+    }
+
+    if let Some(path) = &config.cdc_socket {
+        let cdc_listener = server::bind_unix(path, None).unwrap_or_else(|e| {
+            error!("can't listen on {}: {}", path, e);
+            std::process::exit(1);
+        });
+        tokio::spawn(cdc::run_unix(cdc_listener, router.clone()));
+    }
+
+    if let Some(port) = config.metrics_port {
+        let metrics_listener = TcpListener::bind(("0.0.0.0", port)).await.unwrap_or_else(|e| {
+            error!("can't listen on metrics port {}: {}", port, e);
+            std::process::exit(1);
+        });
+        tokio::spawn(metrics::serve(metrics_listener, router.clone()));
+    }
+
+    if let (Some(unix_listener), Some(path)) = (unix_listener, &config.unixsocket) {
+        tokio::spawn(server::run_unix(unix_listener, path.clone(), router.clone()));
+    }
+    if let Some((tls_listeners, acceptor)) = tls_listeners {
+        for listener in tls_listeners {
+            tokio::spawn(server::run(listener, Some(acceptor.clone()), router.clone()));
+        }
+    }
+    #[cfg(feature = "io-uring")]
+    if !listeners.is_empty() {
+        server::run_uring(listeners.into_iter().map(|l| l.into_std().unwrap()).collect(), router.clone());
+    }
+    #[cfg(not(feature = "io-uring"))]
+    for listener in listeners {
+        tokio::spawn(server::run(listener, None, router.clone()));
+    }
+    std::future::pending().await
+}
 
-    server::run(listener, router.clone()).await;
+/// Logs go to stdout, filtered by `RUST_LOG` or else `log-level`.
+fn init_logging(config: &Config) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.log_level));
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stdout().is_terminal());
+    if config.log_json {
+        logger.json().init();
+    } else {
+        logger.init();
+    }
 }
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use hdrhistogram::Histogram;
+
+// Microseconds, from 1 us to a minute, to 2 significant digits.
+const HIGHEST_US: u64 = 60_000_000;
+const SIGFIGS: u8 = 2;
+
+/// Where a command spends its time, in the order it goes through them.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Turning the request line into a command.
+    Parse,
+    /// Waiting in the shard's `cmd_tx` queue.
+    Queue,
+    /// Running in the shard's event loop.
+    Engine,
+    /// The connection waiting for the shard's reply: queue plus engine.
+    Reply,
+}
+
+impl Stage {
+    fn name(self) -> &'static str {
+        match self {
+            Stage::Parse => "parse",
+            Stage::Queue => "queue",
+            Stage::Engine => "engine",
+            Stage::Reply => "reply",
+        }
+    }
+}
+
+/// Latency histograms by command and stage.
+#[derive(Default)]
+pub struct Latency {
+    histograms: Mutex<BTreeMap<(&'static str, Stage), Histogram<u64>>>,
+}
+
+impl Latency {
+    pub fn record(&self, command: &'static str, stage: Stage, took: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms
+            .entry((command, stage))
+            .or_insert_with(|| Histogram::new_with_bounds(1, HIGHEST_US, SIGFIGS).unwrap());
+        histogram.saturating_record(took.as_micros() as u64);
+    }
+
+    /// Adds our histograms to `into`.
+    pub fn merge_into(&self, into: &mut BTreeMap<(&'static str, Stage), Histogram<u64>>) {
+        for (key, histogram) in self.histograms.lock().unwrap().iter() {
+            match into.get_mut(key) {
+                Some(merged) => {
+                    let _ = merged.add(histogram);
+                }
+                None => {
+                    into.insert(*key, histogram.clone());
+                }
+            }
+        }
+    }
+
+    pub fn reset(&self) {
+        self.histograms.lock().unwrap().clear();
+    }
+}
+
+/// `LATENCY HISTOGRAM`: one line per command and stage, in microseconds,
+/// for `commands` or all of them if empty.
+pub fn report(histograms: &BTreeMap<(&'static str, Stage), Histogram<u64>>, commands: &[String]) -> String {
+    let mut out = String::new();
+    for ((command, stage), histogram) in histograms {
+        if !commands.is_empty() && !commands.iter().any(|c| c.eq_ignore_ascii_case(command)) {
+            continue;
+        }
+        let _ = writeln!(
+            out,
+            "{} {} calls={} p50={} p99={} p999={} max={}",
+            command,
+            stage.name(),
+            histogram.len(),
+            histogram.value_at_quantile(0.5),
+            histogram.value_at_quantile(0.99),
+            histogram.value_at_quantile(0.999),
+            histogram.max()
+        );
+    }
+    out.push('\n');
+    out
+}
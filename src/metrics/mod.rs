@@ -0,0 +1,372 @@
+pub mod latency;
+pub mod slowlog;
+
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{Span, trace, warn};
+
+use crate::engine::{Command, Keyspace};
+use crate::shard_engine::router::ShardRouter;
+use latency::{Latency, Stage};
+
+/// Label values of `rustkv_commands_total`, indexed by `command_index`.
+const COMMANDS: [&str; 14] = [
+    "set", "setex", "get", "del", "ex", "expire", "ttl", "ping", "getver", "setifver", "delifver", "eval", "restore",
+    "dump",
+];
+// Upper bounds of the WAL write latency buckets, in seconds.
+const WAL_WRITE_BUCKETS: [f64; 10] = [0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25];
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// Picks one of the plain counters or gauges out of a shard's metrics.
+type Field = fn(&ShardMetrics) -> &AtomicU64;
+
+/// Counters and gauges of one shard, bumped by its engine and WAL task and
+/// by the router as commands go in.
+#[derive(Default)]
+pub struct ShardMetrics {
+    commands: [AtomicU64; COMMANDS.len()],
+    keys: AtomicU64,
+    expiring_keys: AtomicU64,
+    memory: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+    wal_bytes: AtomicU64,
+    wal_writes: Histogram,
+    snapshots: AtomicU64,
+    last_snapshot_us: AtomicU64,
+    last_snapshot_bytes: AtomicU64,
+    last_snapshot_failed: AtomicBool,
+    snapshotting: AtomicBool,
+    last_save: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    wal_size: AtomicU64,
+    wal_pending: AtomicU64,
+    /// The queue and engine stages of commands sent to this shard.
+    pub latency: Latency,
+}
+
+/// A reading of a shard's metrics, for `INFO`.
+pub struct ShardStats {
+    pub keys: u64,
+    pub expiring_keys: u64,
+    pub memory: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub expired: u64,
+    pub evicted: u64,
+    /// Unix time in seconds of the last successful snapshot, or of startup.
+    pub last_save: u64,
+    pub snapshotting: bool,
+    pub last_snapshot_ok: bool,
+    pub last_snapshot_secs: f64,
+    /// Bytes in the WAL segments on disk, and waiting to be written to them.
+    pub wal_size: u64,
+    pub wal_pending: u64,
+}
+
+impl ShardMetrics {
+    pub fn command(&self, cmd: &Command) {
+        if let Some(idx) = command_index(cmd) {
+            self.commands[idx].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Copies what only the engine can read: the keyspace's sizes and
+    /// counters, and where its snapshots are at.
+    pub fn engine(&self, keyspace: &Keyspace, last_save: u64, snapshotting: bool) {
+        self.keys.store(keyspace.db().len() as u64, Ordering::Relaxed);
+        self.expiring_keys.store(keyspace.expiring_keys() as u64, Ordering::Relaxed);
+        self.memory.store(keyspace.used_memory() as u64, Ordering::Relaxed);
+        self.expired.store(keyspace.expired_keys(), Ordering::Relaxed);
+        let (hits, misses) = keyspace.lookups();
+        self.hits.store(hits, Ordering::Relaxed);
+        self.misses.store(misses, Ordering::Relaxed);
+        self.last_save.store(last_save, Ordering::Relaxed);
+        self.snapshotting.store(snapshotting, Ordering::Relaxed);
+    }
+
+    pub fn evicted(&self) {
+        self.evicted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn wal_write(&self, bytes: usize, took: Duration) {
+        self.wal_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.wal_size.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.wal_writes.observe(took);
+    }
+
+    pub fn snapshot(&self, took: Duration, bytes: u64) {
+        self.snapshots.fetch_add(1, Ordering::Relaxed);
+        self.last_snapshot_us.store(took.as_micros() as u64, Ordering::Relaxed);
+        self.last_snapshot_bytes.store(bytes, Ordering::Relaxed);
+        self.last_snapshot_failed.store(false, Ordering::Relaxed);
+    }
+
+    pub fn snapshot_failed(&self) {
+        self.last_snapshot_failed.store(true, Ordering::Relaxed);
+    }
+
+    /// Bytes in the WAL segments, after they were rotated or purged.
+    pub fn wal_size(&self, bytes: u64) {
+        self.wal_size.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Bytes buffered for the next WAL write.
+    pub fn wal_pending(&self, bytes: usize) {
+        self.wal_pending.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Times the engine stage of a command until dropped.
+    pub fn engine_timer<'a>(&'a self, command: &'static str, span: &'a Span) -> EngineTimer<'a> {
+        EngineTimer { metrics: self, command, span, started: Instant::now() }
+    }
+
+    pub fn stats(&self) -> ShardStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ShardStats {
+            keys: load(&self.keys),
+            expiring_keys: load(&self.expiring_keys),
+            memory: load(&self.memory),
+            hits: load(&self.hits),
+            misses: load(&self.misses),
+            expired: load(&self.expired),
+            evicted: load(&self.evicted),
+            last_save: load(&self.last_save),
+            snapshotting: self.snapshotting.load(Ordering::Relaxed),
+            last_snapshot_ok: !self.last_snapshot_failed.load(Ordering::Relaxed),
+            last_snapshot_secs: load(&self.last_snapshot_us) as f64 / 1e6,
+            wal_size: load(&self.wal_size),
+            wal_pending: load(&self.wal_pending),
+        }
+    }
+}
+
+pub struct EngineTimer<'a> {
+    metrics: &'a ShardMetrics,
+    command: &'static str,
+    span: &'a Span,
+    started: Instant,
+}
+
+impl Drop for EngineTimer<'_> {
+    fn drop(&mut self) {
+        let took = self.started.elapsed();
+        self.metrics.latency.record(self.command, Stage::Engine, took);
+        trace!(parent: self.span, engine_us = took.as_micros() as u64, "handled by shard");
+    }
+}
+
+fn command_index(cmd: &Command) -> Option<usize> {
+    Some(match cmd {
+        Command::Set { .. } => 0,
+        Command::SetEx { .. } => 1,
+        Command::Get { .. } => 2,
+        Command::Del { .. } => 3,
+        Command::Ex { .. } => 4,
+        Command::Expire { .. } => 5,
+        Command::Ttl { .. } => 6,
+        Command::Ping { .. } => 7,
+        Command::GetVer { .. } => 8,
+        Command::SetIfVer { .. } => 9,
+        Command::DelIfVer { .. } => 10,
+        Command::Eval { .. } => 11,
+        Command::Restore { .. } => 12,
+        Command::Dump { .. } => 13,
+        _ => return None,
+    })
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the rest only count in `count`.
+    buckets: [AtomicU64; WAL_WRITE_BUCKETS.len()],
+    sum_ns: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, took: Duration) {
+        let secs = took.as_secs_f64();
+        if let Some(idx) = WAL_WRITE_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_ns.fetch_add(took.as_nanos() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Server-wide counters.
+pub struct Metrics {
+    started: Instant,
+    connected_clients: AtomicU64,
+    connections: AtomicU64,
+    commands: AtomicU64,
+    /// The stages of commands seen from the connection: parse and reply.
+    pub latency: Latency,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            connected_clients: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            commands: AtomicU64::new(0),
+            latency: Latency::default(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn connected_clients(&self) -> u64 {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Counts a command read from a client, whatever it was.
+    pub fn command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn commands(&self) -> u64 {
+        self.commands.load(Ordering::Relaxed)
+    }
+
+    /// Counts a client connection for as long as the guard lives.
+    pub fn client_connected(self: &Arc<Self>) -> ClientGuard {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(self.clone())
+    }
+}
+
+pub struct ClientGuard(Arc<Metrics>);
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.0.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Everything in Prometheus' text format.
+pub fn render(router: &ShardRouter) -> String {
+    let mut out = String::new();
+    let shards: Vec<(usize, &ShardMetrics)> = (0..router.shard_count()).map(|id| (id, router.shard_metrics(id))).collect();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    header(&mut out, "rustkv_commands_total", "counter", "Commands routed to a shard, by command.");
+    for (id, shard) in &shards {
+        for (name, count) in COMMANDS.iter().zip(&shard.commands) {
+            let _ = writeln!(out, "rustkv_commands_total{{shard=\"{}\",command=\"{}\"}} {}", id, name, load(count));
+        }
+    }
+
+    let per_shard: [(&str, &str, &str, Field); 8] = [
+        ("rustkv_keys", "gauge", "Keys held.", |s| &s.keys),
+        ("rustkv_expiring_keys", "gauge", "Keys with a TTL.", |s| &s.expiring_keys),
+        ("rustkv_memory_bytes", "gauge", "Estimated memory used by keys and values.", |s| &s.memory),
+        ("rustkv_expired_keys_total", "counter", "Keys removed because their TTL passed.", |s| &s.expired),
+        ("rustkv_evicted_keys_total", "counter", "Keys evicted to stay under maxmemory.", |s| &s.evicted),
+        ("rustkv_wal_bytes_total", "counter", "Bytes written to the WAL.", |s| &s.wal_bytes),
+        ("rustkv_snapshots_total", "counter", "Snapshots written.", |s| &s.snapshots),
+        ("rustkv_last_snapshot_bytes", "gauge", "Size of the last snapshot.", |s| &s.last_snapshot_bytes),
+    ];
+    for (name, kind, help, field) in per_shard {
+        header(&mut out, name, kind, help);
+        for (id, shard) in &shards {
+            let _ = writeln!(out, "{}{{shard=\"{}\"}} {}", name, id, load(field(shard)));
+        }
+    }
+
+    header(&mut out, "rustkv_last_snapshot_duration_seconds", "gauge", "How long the last snapshot took.");
+    for (id, shard) in &shards {
+        let secs = load(&shard.last_snapshot_us) as f64 / 1e6;
+        let _ = writeln!(out, "rustkv_last_snapshot_duration_seconds{{shard=\"{}\"}} {}", id, secs);
+    }
+
+    header(&mut out, "rustkv_queue_depth", "gauge", "Messages (commands or batches) waiting in the shard's queue.");
+    for id in 0..router.shard_count() {
+        let _ = writeln!(out, "rustkv_queue_depth{{shard=\"{}\"}} {}", id, router.queue_depth(id));
+    }
+
+    // The WAL is handed to the OS, not fsynced, so this is the time to
+    // write and flush a batch of records.
+    header(&mut out, "rustkv_wal_write_seconds", "histogram", "Time to write a batch of records to the WAL.");
+    for (id, shard) in &shards {
+        let histogram = &shard.wal_writes;
+        let mut cumulative = 0;
+        for (bound, count) in WAL_WRITE_BUCKETS.iter().zip(&histogram.buckets) {
+            cumulative += load(count);
+            let _ = writeln!(out, "rustkv_wal_write_seconds_bucket{{shard=\"{}\",le=\"{}\"}} {}", id, bound, cumulative);
+        }
+        let count = load(&histogram.count);
+        let sum = load(&histogram.sum_ns) as f64 / 1e9;
+        let _ = writeln!(out, "rustkv_wal_write_seconds_bucket{{shard=\"{}\",le=\"+Inf\"}} {}", id, count);
+        let _ = writeln!(out, "rustkv_wal_write_seconds_sum{{shard=\"{}\"}} {}", id, sum);
+        let _ = writeln!(out, "rustkv_wal_write_seconds_count{{shard=\"{}\"}} {}", id, count);
+    }
+
+    let metrics = router.metrics();
+    header(&mut out, "rustkv_connected_clients", "gauge", "Open client connections.");
+    let _ = writeln!(out, "rustkv_connected_clients {}", load(&metrics.connected_clients));
+    header(&mut out, "rustkv_connections_total", "counter", "Client connections accepted.");
+    let _ = writeln!(out, "rustkv_connections_total {}", load(&metrics.connections));
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Answers `GET /metrics` over plain HTTP/1.1, one request per connection.
+pub async fn serve(listener: TcpListener, router: Arc<ShardRouter>) {
+    loop {
+        let (socket, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("metrics accept failed: {}", e);
+                continue;
+            }
+        };
+        tokio::spawn(answer(socket, router.clone()));
+    }
+}
+
+async fn answer(mut socket: TcpStream, router: Arc<ShardRouter>) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match socket.read(&mut buf).await {
+            Ok(n) if n > 0 && request.len() + n <= MAX_REQUEST => request.extend_from_slice(&buf[..n]),
+            _ => return,
+        }
+    }
+    let request_line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|&b| b == b' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => ("200 OK", render(&router)),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let _ = socket.write_all(response.as_bytes()).await;
+}
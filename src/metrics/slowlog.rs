@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::engine::apply::now_ms;
+use crate::server::Peer;
+
+// Longest command line kept per entry, like Redis trims long arguments.
+const MAX_LINE: usize = 256;
+
+struct Entry {
+    id: u64,
+    timestamp: u64,
+    duration_us: u64,
+    client: Peer,
+    line: String,
+}
+
+/// The most recent commands that took longer than `slower_than`.
+pub struct SlowLog {
+    /// `None` turns the log off.
+    slower_than: Option<Duration>,
+    max_len: usize,
+    entries: Mutex<(u64, VecDeque<Entry>)>,
+}
+
+impl SlowLog {
+    pub fn new(slower_than: Option<Duration>, max_len: usize) -> Self {
+        Self { slower_than, max_len, entries: Mutex::new((0, VecDeque::new())) }
+    }
+
+    /// Logs the command if it took long enough.
+    pub fn record(&self, client: &Peer, line: &str, took: Duration) {
+        if self.slower_than.is_none_or(|limit| took < limit) || self.max_len == 0 {
+            return;
+        }
+        let line = match line.char_indices().nth(MAX_LINE) {
+            Some((cut, _)) => format!("{}... ({} more bytes)", &line[..cut], line.len() - cut),
+            None => line.to_string(),
+        };
+        let mut guard = self.entries.lock().unwrap();
+        let (next_id, entries) = &mut *guard;
+        entries.push_front(Entry {
+            id: *next_id,
+            timestamp: now_ms() / 1000,
+            duration_us: took.as_micros() as u64,
+            client: client.clone(),
+            line,
+        });
+        entries.truncate(self.max_len);
+        *next_id += 1;
+    }
+
+    /// `SLOWLOG GET`: the newest `count` entries, one per line as
+    /// `<id> <unix-time> <microseconds> <client> <command>`, then a blank line.
+    pub fn get(&self, count: usize) -> String {
+        let guard = self.entries.lock().unwrap();
+        let mut out = String::new();
+        for entry in guard.1.iter().take(count) {
+            let _ = writeln!(
+                out,
+                "{} {} {} {} {}",
+                entry.id, entry.timestamp, entry.duration_us, entry.client, entry.line
+            );
+        }
+        out.push('\n');
+        out
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().1.len()
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().1.clear();
+    }
+}
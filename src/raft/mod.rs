@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Cursor};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::time;
+use tracing::warn;
+
+use crate::acl;
+use crate::engine::Command;
+use crate::engine::raft::RaftMsg;
+use crate::server::Stream;
+use crate::shard_engine::router::ShardRouter;
+
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+// Messages queued for a peer before new ones are dropped. Raft retries
+// whatever gets lost.
+const LINK_CAPACITY: usize = 10_000;
+
+/// This node's links to the other members of the shards' Raft groups. Each
+/// peer gets one connection, shared by all shards, that only carries
+/// messages towards it: `RAFT LINK`, then frames of a big-endian u32 length
+/// and a bincode `(shard, from, RaftMsg)`.
+pub struct RaftNet {
+    /// Our `host:port`, which is also how clients get redirected to us.
+    me: String,
+    /// Voters of a group with nothing in its log yet.
+    bootstrap: Vec<String>,
+    links: Mutex<HashMap<String, Sender<Vec<u8>>>>,
+}
+
+impl RaftNet {
+    pub fn new(me: String, bootstrap: Vec<String>) -> Self {
+        Self { me, bootstrap, links: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn me(&self) -> &str {
+        &self.me
+    }
+
+    pub fn bootstrap(&self) -> &[String] {
+        &self.bootstrap
+    }
+
+    /// Queues `msg` for `to`, dropping it if the link is backed up.
+    pub fn send(&self, to: &str, shard: usize, msg: &RaftMsg) {
+        let frame = bincode::serialize(&(shard, &self.me, msg)).unwrap();
+        let mut links = self.links.lock().unwrap();
+        let link = links.entry(to.to_string()).or_insert_with(|| {
+            let (link_tx, link_rx) = mpsc::channel(LINK_CAPACITY);
+            tokio::spawn(run_link(to.to_string(), link_rx));
+            link_tx
+        });
+        let _ = link.try_send(frame);
+    }
+}
+
+/// Keeps a connection to `addr` open and writes out the queued frames.
+/// Whatever piles up while it's down is stale by the time it's back.
+async fn run_link(addr: String, mut link_rx: Receiver<Vec<u8>>) {
+    while write_frames(&addr, &mut link_rx).await.is_err() {
+        while link_rx.try_recv().is_ok() {}
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Returns once the queue is closed, or with the error that broke the link.
+async fn write_frames(addr: &str, link_rx: &mut Receiver<Vec<u8>>) -> io::Result<()> {
+    let socket = acl::connect(addr).await?;
+    socket.set_nodelay(true)?;
+    let mut out = BufWriter::with_capacity(64 * 1024, socket);
+    out.write_all(b"RAFT LINK\n").await?;
+    loop {
+        let Some(frame) = link_rx.recv().await else {
+            return Ok(());
+        };
+        out.write_u32(frame.len() as u32).await?;
+        out.write_all(&frame).await?;
+        if link_rx.is_empty() {
+            out.flush().await?;
+        }
+    }
+}
+
+/// Reads the messages a peer sends over a connection that opened with
+/// `RAFT LINK` and hands them to their shards. `received` is what was read
+/// past that line already.
+pub async fn serve_peer(socket: impl Stream, received: Vec<u8>, router: Arc<ShardRouter>) {
+    let mut reader = BufReader::with_capacity(64 * 1024, Cursor::new(received).chain(socket));
+    loop {
+        let Ok(len) = reader.read_u32().await else {
+            return;
+        };
+        let mut frame = vec![0; len as usize];
+        if reader.read_exact(&mut frame).await.is_err() {
+            return;
+        }
+        let Ok((shard, from, msg)) = bincode::deserialize::<(usize, String, RaftMsg)>(&frame) else {
+            warn!("dropping malformed raft message");
+            continue;
+        };
+        if shard < router.shard_count() {
+            router.send_to(shard, Command::Raft { from, msg }).await;
+        }
+    }
+}
+
+/// `RAFT ADD|REMOVE <host:port>` on every shard's group. Shards led from
+/// elsewhere pass the change on to their leader, so `OK` means every group
+/// took it up, not that it's committed yet.
+pub async fn change(router: &ShardRouter, add: bool, node: String) -> String {
+    if router.raft().is_none() {
+        return "ERR raft mode is off\n".into();
+    }
+    router
+        .broadcast(|resp| Command::RaftChange { add, node: node.clone(), resp })
+        .await
+        .into_iter()
+        .find(|reply| !reply.starts_with("OK"))
+        .unwrap_or_else(|| "OK\n".into())
+}
+
+/// The raft section of `INFO`.
+pub async fn info(router: &ShardRouter) -> String {
+    let mut info = String::from("# Raft\n");
+    let Some(net) = router.raft() else {
+        info.push_str("raft_enabled:0\n");
+        return info;
+    };
+    let shards = router.broadcast(|resp| Command::RaftInfo { resp }).await;
+    let led = shards.iter().filter(|s| s.contains("role=leader")).count();
+    let _ = writeln!(info, "raft_enabled:1");
+    let _ = writeln!(info, "raft_address:{}", net.me());
+    let _ = writeln!(info, "raft_shards_led:{}", led);
+    for shard in shards {
+        info.push_str(&shard);
+    }
+    info
+}
@@ -0,0 +1,233 @@
+pub mod primary;
+pub mod replica;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::task::JoinHandle;
+
+use crate::engine::Command;
+use crate::shard_engine::router::ShardRouter;
+
+/// How a follower's link to one shard of its primary is doing.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Connecting,
+    Syncing,
+    Up,
+}
+
+/// Server-wide replication status: who we follow, if anyone, and which
+/// followers are streaming from us.
+pub struct Replication {
+    /// Port we listen on, announced to our primary.
+    port: u16,
+    /// Set while following a primary; client writes are refused.
+    read_only: AtomicBool,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Names this node's history of writes. A follower may only resume from
+    /// a primary with the replid it synced from.
+    replid: String,
+    primary: Option<Primary>,
+    /// Our followers by `ip:port`.
+    followers: HashMap<String, Follower>,
+}
+
+struct Primary {
+    host: String,
+    port: u16,
+    links: Vec<LinkState>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct Follower {
+    /// Last LSN each shard's link acknowledged, `None` while not connected.
+    acks: Vec<Option<u64>>,
+}
+
+impl Replication {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            read_only: AtomicBool::new(false),
+            state: Mutex::new(State {
+                replid: new_replid(),
+                primary: None,
+                followers: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn replid(&self) -> String {
+        self.state.lock().unwrap().replid.clone()
+    }
+
+    /// Gives this node a new replid once its data stops following the old
+    /// history, so its own followers can't resume from the wrong one.
+    pub(crate) fn new_history(&self) {
+        self.state.lock().unwrap().replid = new_replid();
+    }
+
+    pub(crate) fn set_link(&self, shard: usize, link: LinkState) {
+        if let Some(primary) = &mut self.state.lock().unwrap().primary {
+            primary.links[shard] = link;
+        }
+    }
+
+    /// Records how far a follower's link to `shard` got, or with `None` that
+    /// the link went away.
+    pub(crate) fn set_follower_lsn(&self, name: &str, shard: usize, shard_count: usize, lsn: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let follower = state.followers.entry(name.to_string()).or_insert_with(|| Follower {
+            acks: vec![None; shard_count],
+        });
+        follower.acks[shard] = lsn;
+        if follower.acks.iter().all(Option::is_none) {
+            state.followers.remove(name);
+        }
+    }
+}
+
+/// Starts following `primary`, or stops following with `None`, which turns
+/// this node into a primary with a history of its own.
+pub fn replicaof(router: &Arc<ShardRouter>, primary: Option<(String, u16)>) -> String {
+    let replication = router.replication();
+    let mut state = replication.state.lock().unwrap();
+    if let (Some(current), Some((host, port))) = (&state.primary, &primary)
+        && current.host == *host
+        && current.port == *port
+    {
+        return "OK Already connected to specified master\n".into();
+    }
+
+    if let Some(old) = state.primary.take() {
+        for task in old.tasks {
+            task.abort();
+        }
+    }
+    match primary {
+        Some((host, port)) => {
+            replication.read_only.store(true, Ordering::Relaxed);
+            state.primary = Some(Primary {
+                tasks: replica::spawn(router.clone(), host.clone(), port),
+                host,
+                port,
+                links: vec![LinkState::Connecting; router.shard_count()],
+            });
+        }
+        None => {
+            replication.read_only.store(false, Ordering::Relaxed);
+            state.replid = new_replid();
+        }
+    }
+    "OK\n".into()
+}
+
+/// `ROLE`: `master <offset> [<ip> <port> <offset>]...` on a primary,
+/// `slave <host> <port> <state> <offset>` on a follower.
+pub async fn role(router: &ShardRouter) -> String {
+    let offset = offset(router).await;
+    let state = router.replication().state.lock().unwrap();
+    match &state.primary {
+        Some(primary) => format!(
+            "slave {} {} {} {}\n",
+            primary.host,
+            primary.port,
+            link_name(&primary.links),
+            offset
+        ),
+        None => {
+            let mut reply = format!("master {}", offset);
+            for (name, follower) in &state.followers {
+                let (ip, port) = name.rsplit_once(':').unwrap();
+                let _ = write!(reply, " {} {} {}", ip, port, follower.offset());
+            }
+            reply.push('\n');
+            reply
+        }
+    }
+}
+
+/// The replication section of `INFO`.
+pub async fn info(router: &ShardRouter) -> String {
+    let offset = offset(router).await;
+    let state = router.replication().state.lock().unwrap();
+    let mut info = String::from("# Replication\n");
+    match &state.primary {
+        Some(primary) => {
+            let up = primary.links.iter().all(|&l| l == LinkState::Up);
+            let syncing = primary.links.contains(&LinkState::Syncing);
+            let _ = writeln!(info, "role:slave");
+            let _ = writeln!(info, "master_host:{}", primary.host);
+            let _ = writeln!(info, "master_port:{}", primary.port);
+            let _ = writeln!(info, "master_link_status:{}", if up { "up" } else { "down" });
+            let _ = writeln!(info, "master_sync_in_progress:{}", syncing as u8);
+            let _ = writeln!(info, "slave_repl_offset:{}", offset);
+            let _ = writeln!(info, "slave_read_only:1");
+        }
+        None => {
+            let _ = writeln!(info, "role:master");
+            let _ = writeln!(info, "connected_slaves:{}", state.followers.len());
+            for (i, (name, follower)) in state.followers.iter().enumerate() {
+                let (ip, port) = name.rsplit_once(':').unwrap();
+                let online = follower.acks.iter().all(Option::is_some);
+                let _ = writeln!(
+                    info,
+                    "slave{}:ip={},port={},state={},offset={}",
+                    i,
+                    ip,
+                    port,
+                    if online { "online" } else { "sync" },
+                    follower.offset()
+                );
+            }
+        }
+    }
+    let _ = writeln!(info, "master_replid:{}", state.replid);
+    let _ = writeln!(info, "master_repl_offset:{}", offset);
+    info
+}
+
+impl Follower {
+    fn offset(&self) -> u64 {
+        self.acks.iter().flatten().sum()
+    }
+}
+
+/// The replication offset: the sum of the shards' LSNs. It only grows, and a
+/// follower that is caught up has the same one as its primary.
+async fn offset(router: &ShardRouter) -> u64 {
+    router
+        .broadcast(|resp| Command::ReplOffset { resp })
+        .await
+        .iter()
+        .filter_map(|r| r.trim().parse::<u64>().ok())
+        .sum()
+}
+
+fn link_name(links: &[LinkState]) -> &'static str {
+    if links.contains(&LinkState::Connecting) {
+        "connect"
+    } else if links.contains(&LinkState::Syncing) {
+        "sync"
+    } else {
+        "connected"
+    }
+}
+
+fn new_replid() -> String {
+    (0..40).map(|_| char::from_digit(fastrand::u32(..16), 16).unwrap()).collect()
+}
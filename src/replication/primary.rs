@@ -0,0 +1,117 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::sync::mpsc;
+use tokio::time;
+use tracing::warn;
+
+use crate::engine::Command;
+use crate::engine::backlog::ReplFrame;
+use crate::server::{Peer, Stream};
+use crate::shard_engine::router::ShardRouter;
+
+// Frames a follower may fall behind by before the shard drops it.
+const STREAM_CAPACITY: usize = 100_000;
+pub const HEARTBEAT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Serves one shard to a follower that sent `PSYNC shard replid lsn port`.
+///
+/// The reply is `FULLRESYNC <replid> <version> <len>` followed by the
+/// snapshot file, or `CONTINUE <replid>`. Then come the shard's WAL records,
+/// each prefixed by its length as a big-endian u32; a zero length is a
+/// heartbeat. Empty lines keep the link alive while a snapshot is being
+/// taken. The follower sends `ACK <lsn>` lines back.
+pub async fn serve_replica(
+    socket: impl Stream,
+    addr: Peer,
+    router: Arc<ShardRouter>,
+    shard: usize,
+    replid: String,
+    lsn: u64,
+    port: u16,
+) {
+    let replication = router.replication();
+    let name = format!("{}:{}", addr.ip(), port);
+    let result = stream_shard(socket, &router, shard, replid, lsn, &name).await;
+    replication.set_follower_lsn(&name, shard, router.shard_count(), None);
+    if let Err(e) = result {
+        warn!(follower = %name, shard, "replication link closed: {}", e);
+    }
+}
+
+async fn stream_shard(
+    mut socket: impl Stream,
+    router: &ShardRouter,
+    shard: usize,
+    replid: String,
+    lsn: u64,
+    name: &str,
+) -> io::Result<()> {
+    let replication = router.replication();
+    if shard >= router.shard_count() {
+        socket.write_all(b"ERR no such shard\n").await?;
+        return Ok(());
+    }
+
+    let (stream_tx, mut stream_rx) = mpsc::channel(STREAM_CAPACITY);
+    let resume_from = (replid == replication.replid()).then_some(lsn);
+    router.send_to(shard, Command::Psync { lsn: resume_from, stream: stream_tx }).await;
+
+    let (read_half, write_half) = tokio::io::split(socket);
+    let mut acks = BufReader::new(read_half).lines();
+    let mut out = BufWriter::with_capacity(64 * 1024, write_half);
+    let mut heartbeat = time::interval(HEARTBEAT_PERIOD);
+    let mut started = false;
+
+    loop {
+        tokio::select! {
+            frame = stream_rx.recv() => {
+                // The shard hangs up on followers that fall too far behind.
+                let Some(frame) = frame else {
+                    return Err(io::Error::other("follower fell behind"));
+                };
+                match frame {
+                    ReplFrame::FullSync { version, snapshot } => {
+                        let mut snapshot = tokio::fs::File::from_std(snapshot);
+                        let len = snapshot.metadata().await?.len();
+                        let header = format!("FULLRESYNC {} {} {}\n", replication.replid(), version, len);
+                        out.write_all(header.as_bytes()).await?;
+                        tokio::io::copy(&mut (&mut snapshot).take(len), &mut out).await?;
+                        started = true;
+                    }
+                    ReplFrame::Continue => {
+                        out.write_all(format!("CONTINUE {}\n", replication.replid()).as_bytes()).await?;
+                        started = true;
+                    }
+                    ReplFrame::Record(record) => {
+                        out.write_u32(record.len() as u32).await?;
+                        out.write_all(&record).await?;
+                    }
+                }
+                if stream_rx.is_empty() {
+                    out.flush().await?;
+                }
+            }
+
+            _ = heartbeat.tick() => {
+                if started {
+                    out.write_u32(0).await?;
+                } else {
+                    out.write_all(b"\n").await?;
+                }
+                out.flush().await?;
+            }
+
+            line = acks.next_line() => {
+                let Some(line) = line? else {
+                    return Ok(());
+                };
+                if let Some(lsn) = line.strip_prefix("ACK ").and_then(|l| l.trim().parse().ok()) {
+                    replication.set_follower_lsn(name, shard, router.shard_count(), Some(lsn));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant, timeout};
+use tracing::warn;
+
+use super::LinkState;
+use super::primary::HEARTBEAT_PERIOD;
+use crate::acl;
+use crate::engine::{Command, Resp};
+use crate::shard_engine::router::ShardRouter;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+// No heartbeat for this long and the primary is considered gone.
+const REPL_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_BATCH: usize = 1024;
+
+/// Where a shard left off: the primary's replid and the last LSN applied.
+type Resume = Option<(String, u64)>;
+
+/// Starts one task per shard that keeps it in sync with the same shard on
+/// the primary.
+pub fn spawn(router: Arc<ShardRouter>, host: String, port: u16) -> Vec<JoinHandle<()>> {
+    (0..router.shard_count())
+        .map(|shard| tokio::spawn(follow_shard(router.clone(), host.clone(), port, shard)))
+        .collect()
+}
+
+async fn follow_shard(router: Arc<ShardRouter>, host: String, port: u16, shard: usize) {
+    let mut resume: Resume = None;
+    loop {
+        router.replication().set_link(shard, LinkState::Connecting);
+        if let Err(e) = sync_shard(&router, &host, port, shard, &mut resume).await {
+            warn!(shard, primary = %format!("{}:{}", host, port), "lost link to primary: {}", e);
+        }
+        time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_shard(
+    router: &ShardRouter,
+    host: &str,
+    port: u16,
+    shard: usize,
+    resume: &mut Resume,
+) -> io::Result<()> {
+    let replication = router.replication();
+    let socket = timeout(REPL_TIMEOUT, acl::connect((host, port))).await??;
+    let (read_half, mut write_half) = socket.into_split();
+    let mut reader = BufReader::with_capacity(64 * 1024, read_half);
+
+    let (replid, lsn) = resume.clone().unwrap_or_else(|| ("?".into(), 0));
+    let psync = format!("PSYNC {} {} {} {}\n", shard, replid, lsn, replication.port());
+    write_half.write_all(psync.as_bytes()).await?;
+
+    // Skip the empty lines the primary sends while it takes a snapshot.
+    let mut header = String::new();
+    while header.trim().is_empty() {
+        header.clear();
+        if timeout(REPL_TIMEOUT, reader.read_line(&mut header)).await?? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+
+    let mut lsn = match header.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["FULLRESYNC", replid, version, len] => {
+            replication.set_link(shard, LinkState::Syncing);
+            let (version, len) = (parse(version)?, parse(len)?);
+            let mut snapshot = vec![0; len as usize];
+            for chunk in snapshot.chunks_mut(64 * 1024) {
+                timeout(REPL_TIMEOUT, reader.read_exact(chunk)).await??;
+            }
+            call(router, shard, |resp| Command::LoadSnapshot { snapshot, resp }).await?;
+            // Our data changed under whoever follows us.
+            replication.new_history();
+            *resume = Some((replid.to_string(), version));
+            version
+        }
+        ["CONTINUE", replid] => {
+            *resume = Some((replid.to_string(), lsn));
+            lsn
+        }
+        _ => return Err(io::Error::other(header.trim().to_string())),
+    };
+    replication.set_link(shard, LinkState::Up);
+
+    let mut last_ack = Instant::now();
+    loop {
+        let batch = timeout(REPL_TIMEOUT, read_batch(&mut reader)).await??;
+        let heartbeat = batch.is_empty();
+        if !heartbeat {
+            let reply = call(router, shard, |resp| Command::ApplyRecords { records: batch, resp }).await?;
+            lsn = parse(reply.trim())?;
+            if let Some((_, resume_lsn)) = resume {
+                *resume_lsn = lsn;
+            }
+        }
+        if heartbeat || last_ack.elapsed() >= HEARTBEAT_PERIOD {
+            write_half.write_all(format!("ACK {}\n", lsn).as_bytes()).await?;
+            last_ack = Instant::now();
+        }
+    }
+}
+
+/// Reads the next frames up to a heartbeat, taking as many as are already
+/// buffered so they reach the shard in one go. Empty means a heartbeat.
+async fn read_batch(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<Vec<Vec<u8>>> {
+    let mut batch = Vec::new();
+    loop {
+        let len = reader.read_u32().await? as usize;
+        if len == 0 {
+            return Ok(batch);
+        }
+        let mut record = vec![0; len];
+        reader.read_exact(&mut record).await?;
+        batch.push(record);
+        if batch.len() >= MAX_BATCH || !frame_buffered(reader.buffer()) {
+            return Ok(batch);
+        }
+    }
+}
+
+fn frame_buffered(buf: &[u8]) -> bool {
+    buf.len() >= 4 && buf.len() >= 4 + u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize
+}
+
+/// Runs a command on one of our shards. Errors it replies with end the link.
+async fn call(
+    router: &ShardRouter,
+    shard: usize,
+    make: impl FnOnce(Resp) -> Command,
+) -> io::Result<String> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    router.send_to(shard, make(resp_tx.into())).await;
+    match resp_rx.await.map(|reply| String::from_utf8_lossy(&reply).into_owned()) {
+        Ok(reply) if !reply.starts_with("ERR") => Ok(reply),
+        Ok(reply) => Err(io::Error::other(reply.trim().to_string())),
+        Err(_) => Err(io::Error::other("shard went away")),
+    }
+}
+
+fn parse(value: &str) -> io::Result<u64> {
+    value
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("bad number '{}'", value)))
+}
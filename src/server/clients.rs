@@ -0,0 +1,259 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use tokio::time;
+
+use crate::engine::command::{ClientCommand, ClientFilter};
+use crate::server::Peer;
+use crate::shard_engine::router::ShardRouter;
+
+/// Every open client connection, for `CLIENT LIST` and `CLIENT KILL`.
+pub struct Clients {
+    next_id: AtomicU64,
+    clients: Mutex<BTreeMap<u64, Arc<Client>>>,
+    pause: Mutex<Option<Pause>>,
+    unpaused: Notify,
+}
+
+/// Set by `CLIENT PAUSE`.
+#[derive(Clone, Copy)]
+struct Pause {
+    until: Instant,
+    /// `WRITE` mode: reads carry on.
+    writes_only: bool,
+}
+
+pub struct Client {
+    pub id: u64,
+    pub addr: Peer,
+    created: Instant,
+    state: Mutex<ClientState>,
+    killed: Notify,
+}
+
+struct ClientState {
+    name: String,
+    /// Who the client logged in as, `None` until it does.
+    user: Option<Arc<str>>,
+    last_command: &'static str,
+    last_active: Instant,
+    /// Bytes read but not yet parsed into commands.
+    query_buf: usize,
+    /// Reply bytes not yet flushed to the socket.
+    output_buf: usize,
+}
+
+impl Default for Clients {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            clients: Mutex::new(BTreeMap::new()),
+            pause: Mutex::new(None),
+            unpaused: Notify::new(),
+        }
+    }
+}
+
+impl Clients {
+    /// Adds a connection, which stays listed until the returned handle drops.
+    pub fn register(self: &Arc<Self>, addr: Peer) -> Registered {
+        let now = Instant::now();
+        let client = Arc::new(Client {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            created: now,
+            state: Mutex::new(ClientState {
+                name: String::new(),
+                user: None,
+                last_command: "NULL",
+                last_active: now,
+                query_buf: 0,
+                output_buf: 0,
+            }),
+            killed: Notify::new(),
+        });
+        self.clients.lock().unwrap().insert(client.id, client.clone());
+        Registered { clients: self.clone(), client }
+    }
+
+    /// `CLIENT LIST`: one line per client, oldest first, then a blank line.
+    pub fn list(&self) -> String {
+        let clients: Vec<Arc<Client>> = self.clients.lock().unwrap().values().cloned().collect();
+        let mut out = String::new();
+        for client in clients {
+            out.push_str(&client.describe());
+        }
+        out.push('\n');
+        out
+    }
+
+    /// Closes every client matching `filter`, returning how many there were.
+    pub fn kill(&self, filter: &ClientFilter) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for client in clients.values() {
+            let matches = match filter {
+                ClientFilter::Id(id) => client.id == *id,
+                ClientFilter::Addr(addr) => client.addr.to_string() == *addr,
+                ClientFilter::User(user) => client.user().as_deref() == Some(user.as_str()),
+            };
+            if matches {
+                client.killed.notify_one();
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    /// Holds commands back for `timeout`, or only writes if `writes_only`.
+    pub fn pause(&self, timeout: Duration, writes_only: bool) {
+        *self.pause.lock().unwrap() = Some(Pause { until: Instant::now() + timeout, writes_only });
+    }
+
+    pub fn unpause(&self) {
+        *self.pause.lock().unwrap() = None;
+        self.unpaused.notify_waiters();
+    }
+
+    /// Whether a `CLIENT PAUSE` holds back commands of this kind right now.
+    pub fn paused(&self, write: bool) -> bool {
+        matches!(*self.pause.lock().unwrap(), Some(pause) if (write || !pause.writes_only) && pause.until > Instant::now())
+    }
+
+    /// Returns once a command of this kind may run, right away unless a
+    /// `CLIENT PAUSE` covers it.
+    pub async fn wait_unpaused(&self, write: bool) {
+        loop {
+            // Created before looking, so an UNPAUSE in between isn't missed.
+            let unpaused = self.unpaused.notified();
+            let until = match *self.pause.lock().unwrap() {
+                Some(pause) if write || !pause.writes_only => pause.until,
+                _ => return,
+            };
+            if until <= Instant::now() {
+                return;
+            }
+            // The pause may have been extended meanwhile, so look again.
+            tokio::select! {
+                _ = time::sleep_until(until.into()) => {}
+                _ = unpaused => {}
+            }
+        }
+    }
+}
+
+impl Client {
+    pub fn name(&self) -> String {
+        self.state.lock().unwrap().name.clone()
+    }
+
+    pub fn set_name(&self, name: String) {
+        self.state.lock().unwrap().name = name;
+    }
+
+    pub fn user(&self) -> Option<Arc<str>> {
+        self.state.lock().unwrap().user.clone()
+    }
+
+    pub fn set_user(&self, user: Option<&str>) {
+        self.state.lock().unwrap().user = user.map(Arc::from);
+    }
+
+    /// Notes the command the client just sent.
+    pub fn command(&self, name: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        state.last_command = name;
+        state.last_active = Instant::now();
+    }
+
+    pub fn buffers(&self, query_buf: usize, output_buf: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.query_buf = query_buf;
+        state.output_buf = output_buf;
+    }
+
+    /// Resolves once `CLIENT KILL` picked this client.
+    pub async fn killed(&self) {
+        self.killed.notified().await
+    }
+
+    /// `CLIENT INFO`: the client's `CLIENT LIST` line. There is only one
+    /// database, so `db` is always 0.
+    pub fn describe(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut line = String::new();
+        let _ = writeln!(
+            line,
+            "id={} addr={} name={} age={} idle={} db=0 qbuf={} obuf={} cmd={} user={}",
+            self.id,
+            self.addr,
+            state.name,
+            self.created.elapsed().as_secs(),
+            state.last_active.elapsed().as_secs(),
+            state.query_buf,
+            state.output_buf,
+            state.last_command,
+            state.user.as_deref().unwrap_or("")
+        );
+        line
+    }
+}
+
+/// A connection's entry in `Clients`, removed on drop.
+pub struct Registered {
+    clients: Arc<Clients>,
+    client: Arc<Client>,
+}
+
+impl Deref for Registered {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.clients.clients.lock().unwrap().remove(&self.client.id);
+    }
+}
+
+/// Runs a `CLIENT` subcommand for `client`.
+pub fn execute(router: &ShardRouter, client: &Client, cmd: ClientCommand) -> String {
+    let clients = router.clients();
+    match cmd {
+        ClientCommand::List => clients.list(),
+        ClientCommand::Info => client.describe(),
+        ClientCommand::Id => format!("{}\n", client.id),
+        ClientCommand::SetName { name } if name.contains(|c: char| c.is_whitespace() || c.is_control()) => {
+            "ERR Client names cannot contain spaces, newlines or special characters.\n".into()
+        }
+        ClientCommand::SetName { name } => {
+            client.set_name(name);
+            "OK\n".into()
+        }
+        ClientCommand::GetName => match client.name() {
+            name if name.is_empty() => "nil\n".into(),
+            name => format!("{}\n", name),
+        },
+        ClientCommand::Kill { filter, legacy: true } => match clients.kill(&filter) {
+            0 => "ERR No such client\n".into(),
+            _ => "OK\n".into(),
+        },
+        ClientCommand::Kill { filter, legacy: false } => format!("{}\n", clients.kill(&filter)),
+        ClientCommand::Pause { timeout_ms, writes_only } => {
+            clients.pause(Duration::from_millis(timeout_ms), writes_only);
+            "OK\n".into()
+        }
+        ClientCommand::Unpause => {
+            clients.unpause();
+            "OK\n".into()
+        }
+    }
+}
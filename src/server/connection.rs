@@ -1,70 +1,489 @@
 use crate::{
-    engine::{Command, ParsedCommand, parse_command},
+    acl, cdc,
+    cluster::{self, migrate},
+    config::OutputBufferLimit,
+    engine::{Command, ParsedCommand, Resp, command::AclCommand, parse_command, reply::BatchReplies},
+    metrics::{
+        ClientGuard,
+        latency::{self, Stage},
+    },
+    raft::{self, serve_peer},
+    replication::{self, primary::serve_replica},
+    server::{
+        Peer, Stream,
+        clients::{self, Client},
+        info,
+    },
     shard_engine::router::ShardRouter,
 };
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use bytes::{Bytes, BytesMut};
+use futures_util::{
+    FutureExt, StreamExt,
+    future::{self, Either},
+    stream::FuturesOrdered,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
-    sync::oneshot,
+    time,
 };
+use tracing::{Instrument, Span, debug, debug_span, warn};
+
+// Numbers each request's span, so its logs can be followed into the shard.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
-pub async fn handle_connection(socket: TcpStream, _addr: SocketAddr, router: Arc<ShardRouter>) {
+/// Serves one client. `_connected` keeps it counted against `maxclients`
+/// until it goes away; `cert_user` is the user its TLS client certificate
+/// names, if any.
+pub async fn handle_connection(
+    socket: impl Stream,
+    addr: Peer,
+    router: Arc<ShardRouter>,
+    _connected: ClientGuard,
+    cert_user: Option<String>,
+) {
+    let limits = router.config().client_limits();
+    let client = router.clients().register(addr.clone());
+    client.set_user(router.acl().login(cert_user.as_deref()).as_deref());
+    debug!("client connected");
     let mut stream = BufWriter::with_capacity(8 * 1024, socket);
-    let mut buf = Vec::with_capacity(8 * 1024);
-    let mut temp = [0u8; 8 * 1024]; 
+    let mut buf = BytesMut::with_capacity(8 * 1024);
+    // How much of `buf` is known to hold no newline.
+    let mut scanned = 0;
+    // Set by ASKING for the command that follows it.
+    let mut asking = false;
+    // Replies still to come, in the order their commands arrived. A read's
+    // commands go out once it's parsed, one batch per shard, so a pipeline
+    // keeps several shards busy at once.
+    let mut in_flight = FuturesOrdered::new();
+    let mut batches = Batches::new(router.shard_count());
 
     loop {
-        let n = match stream.get_mut().read(&mut temp).await {
-            Ok(0) => return, 
-            Ok(n) => n,
-            Err(_) => return, 
+        // Lines taken off the front still share the buffer's memory, so this
+        // only reuses it in place once they're all dropped.
+        buf.reserve(8 * 1024);
+        let read = tokio::select! {
+            read = stream.get_mut().read_buf(&mut buf), if in_flight.len() < limits.max_inflight => read,
+            Some(reply) = in_flight.next() => {
+                if !write_reply(&mut stream, reply, &limits.output).await
+                    || !send_ready(&mut stream, &client, &buf, &mut in_flight, &limits.output).await
+                {
+                    return;
+                }
+                continue;
+            }
+            _ = client.killed() => {
+                debug!("client killed");
+                return;
+            }
+            _ = time::sleep(limits.idle_timeout.unwrap_or_default()), if limits.idle_timeout.is_some() => {
+                debug!("client idle for too long");
+                return;
+            }
         };
+        match read {
+            Ok(0) => {
+                debug!("client disconnected");
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!("client connection failed: {}", e);
+                return;
+            }
+        }
+
+        // Only what came in since the last search can hold the next newline.
+        while let Some(found) = buf[scanned..].iter().position(|&b| b == b'\n') {
+            let idx = scanned + found;
+            scanned = 0;
+            // Past the bound, wait for the oldest replies first.
+            if in_flight.len() >= limits.max_inflight {
+                batches.send(&router).await;
+            }
+            while in_flight.len() >= limits.max_inflight {
+                let Some(reply) = in_flight.next().await else { break };
+                if !write_reply(&mut stream, reply, &limits.output).await {
+                    return;
+                }
+            }
+            // The line leaves the buffer without a copy; the keys and values
+            // parsed from it are slices of the same memory.
+            let line = buf.split_to(idx + 1).freeze();
+            let request = line.slice_ref(line.trim_ascii());
+            let started = Instant::now();
 
-        buf.extend_from_slice(&temp[..n]);
-
-        let mut commands_processed = 0;
-
-        while let Some(idx) = buf.iter().position(|&b| b == b'\n') {
-            let line_slice = &buf[..idx];
-            
-            if let Ok(input_str) = std::str::from_utf8(line_slice) {
-                if let Some(parsed) = parse_command(input_str.trim()) {
-                    let (resp_tx, resp_rx) = oneshot::channel();
-                    
-                    let cmd = match parsed {
-                        ParsedCommand::Set { key, value } => Command::Set { key, value, resp: resp_tx },
-                        ParsedCommand::SetEx { key, value, ttl } => Command::SetEx { key, value, ttl, resp: resp_tx },
-                        ParsedCommand::Get { key } => Command::Get { key, resp: resp_tx },
-                        ParsedCommand::Del { key } => Command::Del { key, resp: resp_tx },
-                        ParsedCommand::Expire { key, ttl } => Command::Expire { key, ttl, resp: resp_tx },
-                        ParsedCommand::Ttl { key } => Command::Ttl { key, resp: resp_tx },
-                        ParsedCommand::Ex { key } => Command::Ex { key, resp: resp_tx },
-                        ParsedCommand::Ping {} => Command::Ping { resp: resp_tx },
-
-                    };
-
-                    router.route(cmd).await;
-                    
-                    match resp_rx.await {
-                        Ok(response) => {
-                            if stream.write_all(response.as_bytes()).await.is_err() {
-                                return; 
-                            }
-                            commands_processed += 1;
+            if let Some(parsed) = parse_command(&request) {
+                router.metrics().command();
+                router.metrics().latency.record(parsed.name(), Stage::Parse, started.elapsed());
+                client.command(parsed.name());
+                // Nothing runs without the ACL's say-so, links to other
+                // nodes included.
+                if let Some(denied) = router.acl().check(&client, &parsed) {
+                    in_flight.push_back(Either::Left(future::ready(Some(denied.into()))));
+                    continue;
+                }
+                // Handoffs below take the connection over, once every
+                // earlier command has had its reply.
+                if matches!(
+                    parsed,
+                    ParsedCommand::Psync { .. } | ParsedCommand::CdcSubscribe { .. } | ParsedCommand::RaftLink
+                ) {
+                    batches.send(&router).await;
+                    while let Some(reply) = in_flight.next().await {
+                        if !write_reply(&mut stream, reply, &limits.output).await {
+                            return;
                         }
-                        Err(_) => return,
+                    }
+                    if stream.flush().await.is_err() {
+                        return;
                     }
                 }
+                // A follower asking for a shard's stream; the connection is
+                // theirs from here on.
+                if let ParsedCommand::Psync { shard, replid, lsn, port } = parsed {
+                    serve_replica(stream.into_inner(), addr, router, shard, replid, lsn, port).await;
+                    return;
+                }
+                // A CDC consumer; the connection only carries its stream now.
+                if let ParsedCommand::CdcSubscribe { positions } = parsed {
+                    cdc::serve(stream.into_inner(), router, positions).await;
+                    return;
+                }
+                // A peer's Raft link; its messages may already be in `buf`.
+                if let ParsedCommand::RaftLink = parsed {
+                    serve_peer(stream.into_inner(), buf.to_vec(), router).await;
+                    return;
+                }
+
+                // Passwords stay out of the slowlog.
+                let logged = match parsed {
+                    ParsedCommand::Auth { .. } | ParsedCommand::Acl(AclCommand::SetUser { .. }) => {
+                        parsed.name().to_string()
+                    }
+                    _ => String::from_utf8_lossy(&request).into_owned(),
+                };
+                let id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+                let span = debug_span!("request", id, cmd = parsed.name());
+                let reply = match parsed {
+                    ParsedCommand::Asking => {
+                        asking = true;
+                        Reply::Now("OK\n".to_string())
+                    }
+                    parsed => {
+                        dispatch(&router, &client, &mut batches, parsed, std::mem::take(&mut asking))
+                            .instrument(span.clone())
+                            .await
+                    }
+                };
+                let done = finish(router.clone(), addr.clone(), reply, logged, started).instrument(span);
+                in_flight.push_back(Either::Right(done));
             }
-            
-            buf.drain(..=idx);
         }
+        scanned = buf.len();
+        batches.send(&router).await;
 
-        if commands_processed > 0 {
-            if stream.flush().await.is_err() {
-                return;
+        // Whatever is left is the start of a line still on its way.
+        if buf.len() > limits.max_request {
+            warn!(bytes = buf.len(), "closing client: request too large");
+            let _ = stream.write_all(b"ERR max request size exceeded\n").await;
+            let _ = stream.flush().await;
+            return;
+        }
+
+        if !send_ready(&mut stream, &client, &buf, &mut in_flight, &limits.output).await {
+            return;
+        }
+    }
+}
+
+/// Writes the replies at the front of `in_flight` that are already there,
+/// then flushes. False if the client should be closed.
+async fn send_ready(
+    stream: &mut BufWriter<impl Stream>,
+    client: &Client,
+    buf: &[u8],
+    in_flight: &mut FuturesOrdered<impl Future<Output = Option<Bytes>>>,
+    limit: &OutputBufferLimit,
+) -> bool {
+    while let Some(Some(reply)) = in_flight.next().now_or_never() {
+        if !write_reply(stream, reply, limit).await {
+            return false;
+        }
+    }
+    // Replies sit in `stream` for as long as the client is slow to read them.
+    client.buffers(buf.len(), stream.buffer().len());
+    if !stream.buffer().is_empty() && stream.flush().await.is_err() {
+        return false;
+    }
+    client.buffers(buf.len(), 0);
+    true
+}
+
+/// Queues a reply for the client. False if the client should be closed
+/// instead: there is no reply, the connection failed, or `limit` was overrun.
+async fn write_reply(stream: &mut BufWriter<impl Stream>, reply: Option<Bytes>, limit: &OutputBufferLimit) -> bool {
+    let Some(reply) = reply else {
+        return false;
+    };
+    let pending = stream.buffer().len() + reply.len();
+    if limit.hard > 0 && pending > limit.hard {
+        warn!(bytes = pending, "closing client: output buffer over its hard limit");
+        return false;
+    }
+    if limit.soft > 0 && limit.soft_seconds > 0 && pending > limit.soft {
+        // Over the soft limit until it's all handed to the socket.
+        let send = async {
+            stream.write_all(&reply).await?;
+            stream.flush().await
+        };
+        return match time::timeout(Duration::from_secs(limit.soft_seconds), send).await {
+            Ok(sent) => sent.is_ok(),
+            Err(_) => {
+                warn!(bytes = pending, "closing client: output buffer over its soft limit for too long");
+                false
             }
+        };
+    }
+    stream.write_all(&reply).await.is_ok()
+}
+
+/// Starts one parsed command. Commands on the keyspace join their shard's
+/// batch and are answered once it has been sent and run; the rest run right
+/// away, after the batches so far are sent.
+async fn dispatch(
+    router: &Arc<ShardRouter>,
+    client: &Client,
+    batches: &mut Batches,
+    parsed: ParsedCommand,
+    asking: bool,
+) -> Reply {
+    let write = parsed.is_write();
+    if write && router.replication().read_only() {
+        return Reply::Now("READONLY You can't write against a read only replica.\n".into());
+    }
+    if let Some(redirect) = cluster::redirect(router, &parsed, asking, batches.send(router)).await {
+        return Reply::Now(redirect);
+    }
+    let Some(key) = parsed.shard_key() else {
+        batches.send(router).await;
+        return execute(router, client, parsed).await;
+    };
+    let shard_id = router.shard_of(key);
+    // CLIENT PAUSE holds back commands on the keyspace only, so the
+    // operator can still look around and unpause.
+    if router.clients().paused(write) {
+        batches.send(router).await;
+        router.clients().wait_unpaused(write).await;
+    }
+
+    let name = parsed.name();
+    let (resp, batch, index) = batches.slot(shard_id);
+    let cmd = match parsed {
+        ParsedCommand::Set { key, value } => Command::Set { key, value, resp },
+        ParsedCommand::SetEx { key, value, ttl } => Command::SetEx { key, value, ttl, resp },
+        ParsedCommand::Get { key } => Command::Get { key, resp },
+        ParsedCommand::Del { key } => Command::Del { key, resp },
+        ParsedCommand::Expire { key, ttl } => Command::Expire { key, ttl, resp },
+        ParsedCommand::Ttl { key } => Command::Ttl { key, resp },
+        ParsedCommand::Ex { key } => Command::Ex { key, resp },
+        ParsedCommand::Ping => Command::Ping { resp },
+        ParsedCommand::GetVer { key } => Command::GetVer { key, resp },
+        ParsedCommand::SetIfVer { key, value, version } => Command::SetIfVer { key, value, version, resp },
+        ParsedCommand::DelIfVer { key, version } => Command::DelIfVer { key, version, resp },
+        ParsedCommand::Eval { script, keys, args } => {
+            let (sha, source) = router.scripts().load(script);
+            Command::Eval { sha, source, keys, args, resp }
+        }
+        ParsedCommand::EvalSha { sha, keys, args } => match router.scripts().get(&sha) {
+            Some(source) => Command::Eval { sha: sha.to_lowercase(), source, keys, args, resp },
+            None => return Reply::Now("NOSCRIPT No matching script. Please use EVAL.\n".into()),
+        },
+        ParsedCommand::Restore { key, value, ttl_ms, replace } => Command::Restore { key, value, ttl_ms, replace, resp },
+        // Every other command runs on no shard, so `execute` took it.
+        _ => return Reply::Gone,
+    };
+    batches.push(shard_id, cmd);
+    Reply::Shard { name, queued: Instant::now(), batch, index }
+}
+
+/// Runs a command that isn't on the keyspace and returns its reply.
+async fn execute(router: &Arc<ShardRouter>, client: &Client, parsed: ParsedCommand) -> Reply {
+    match parsed {
+        ParsedCommand::ScriptLoad { script } => Reply::Now(format!("{}\n", router.scripts().load(script).0)),
+        ParsedCommand::ScriptExists { shas } => {
+            let found: Vec<&str> = shas
+                .iter()
+                .map(|sha| if router.scripts().exists(sha) { "1" } else { "0" })
+                .collect();
+            Reply::Now(format!("{}\n", found.join(" ")))
+        }
+        ParsedCommand::ScriptFlush => {
+            router.scripts().flush();
+            let replies = router.broadcast(|resp| Command::ScriptFlush { resp }).await;
+            Reply::Now(first_error(replies, "OK\n"))
+        }
+        ParsedCommand::ScriptKill => {
+            Reply::Now(if router.kill_script() {
+                "OK\n".into()
+            } else {
+                "NOTBUSY No scripts in execution right now.\n".into()
+            })
         }
+        ParsedCommand::Save => {
+            let replies = router.broadcast(|resp| Command::Save { background: false, resp }).await;
+            Reply::Now(first_error(replies, "OK\n"))
+        }
+        ParsedCommand::BgSave => {
+            let replies = router.broadcast(|resp| Command::Save { background: true, resp }).await;
+            Reply::Now(first_error(replies, "Background saving started\n"))
+        }
+        ParsedCommand::LastSave => {
+            // Everything is on disk at least as of the shard that saved longest ago.
+            let replies = router.broadcast(|resp| Command::LastSave { resp }).await;
+            match replies.iter().filter_map(|r| r.trim().parse::<u64>().ok()).min() {
+                Some(oldest) => Reply::Now(format!("{}\n", oldest)),
+                None => Reply::Gone,
+            }
+        }
+        // Handled by `handle_connection`.
+        ParsedCommand::Psync { .. }
+        | ParsedCommand::RaftLink
+        | ParsedCommand::Asking
+        | ParsedCommand::CdcSubscribe { .. } => Reply::Gone,
+        // Run on a shard by `dispatch`.
+        ParsedCommand::Set { .. }
+        | ParsedCommand::SetEx { .. }
+        | ParsedCommand::Get { .. }
+        | ParsedCommand::Del { .. }
+        | ParsedCommand::Expire { .. }
+        | ParsedCommand::Ttl { .. }
+        | ParsedCommand::Ex { .. }
+        | ParsedCommand::Ping
+        | ParsedCommand::GetVer { .. }
+        | ParsedCommand::SetIfVer { .. }
+        | ParsedCommand::DelIfVer { .. }
+        | ParsedCommand::Eval { .. }
+        | ParsedCommand::EvalSha { .. }
+        | ParsedCommand::Restore { .. } => Reply::Gone,
+        ParsedCommand::ReplicaOf { .. } if router.raft().is_some() => {
+            Reply::Now("ERR REPLICAOF is not allowed in raft mode\n".into())
+        }
+        ParsedCommand::ReplicaOf { .. } if router.cluster().is_some() => {
+            Reply::Now("ERR REPLICAOF is not allowed in cluster mode\n".into())
+        }
+        ParsedCommand::ReplicaOf { primary } => Reply::Now(replication::replicaof(router, primary)),
+        ParsedCommand::RaftChange { add, node } => Reply::Now(raft::change(router, add, node).await),
+        ParsedCommand::Cluster(cmd) => Reply::Now(cluster::execute(router, cmd).await),
+        ParsedCommand::Client(cmd) => Reply::Now(clients::execute(router, client, cmd)),
+        ParsedCommand::Auth { user, password } => Reply::Now(acl::auth(router, client, user, password)),
+        ParsedCommand::Acl(cmd) => Reply::Now(acl::execute(router, client, cmd)),
+        ParsedCommand::Migrate { addr, keys, copy, replace, timeout_ms } => {
+            Reply::Now(migrate::migrate(router, addr, keys, copy, replace, timeout_ms).await)
+        }
+        ParsedCommand::Role => Reply::Now(replication::role(router).await),
+        ParsedCommand::Info { section } => Reply::Now(info::info(router, section.as_deref()).await),
+        ParsedCommand::LatencyHistogram { commands } => {
+            let mut histograms = BTreeMap::new();
+            router.metrics().latency.merge_into(&mut histograms);
+            for shard_id in 0..router.shard_count() {
+                router.shard_metrics(shard_id).latency.merge_into(&mut histograms);
+            }
+            Reply::Now(latency::report(&histograms, &commands))
+        }
+        ParsedCommand::LatencyReset => {
+            router.metrics().latency.reset();
+            for shard_id in 0..router.shard_count() {
+                router.shard_metrics(shard_id).latency.reset();
+            }
+            Reply::Now("OK\n".into())
+        }
+        ParsedCommand::SlowlogGet { count } => Reply::Now(router.slowlog().get(count)),
+        ParsedCommand::SlowlogLen => Reply::Now(format!("{}\n", router.slowlog().len())),
+        ParsedCommand::SlowlogReset => {
+            router.slowlog().reset();
+            Reply::Now("OK\n".into())
+        }
+    }
+}
+
+/// Keyspace commands held back until the read they came in has been
+/// parsed, then sent to each shard in one message.
+struct Batches {
+    shards: Vec<Option<Batch>>,
+}
+
+/// A shard's commands, each with its request's span, and their replies.
+type Batch = (Vec<(Command, Span)>, Arc<BatchReplies>);
+
+impl Batches {
+    fn new(shard_count: usize) -> Self {
+        Self { shards: (0..shard_count).map(|_| None).collect() }
+    }
+
+    /// A reply slot for the next command pushed for `shard_id`.
+    fn slot(&mut self, shard_id: usize) -> (Resp, Arc<BatchReplies>, usize) {
+        let (_, batch) = self.shards[shard_id].get_or_insert_with(Default::default);
+        let (resp, index) = batch.slot();
+        (resp, batch.clone(), index)
     }
-}
\ No newline at end of file
+
+    /// Adds `cmd` under the current span, which is its request's.
+    fn push(&mut self, shard_id: usize, cmd: Command) {
+        if let Some((cmds, _)) = &mut self.shards[shard_id] {
+            cmds.push((cmd, Span::current()));
+        }
+    }
+
+    /// Sends every shard its batch. Whatever runs on a shard next, or waits
+    /// on a reply, has to come after this.
+    async fn send(&mut self, router: &ShardRouter) {
+        for (shard_id, batch) in self.shards.iter_mut().enumerate() {
+            if let Some((cmds, _)) = batch.take() {
+                router.route_batch(shard_id, cmds).await;
+            }
+        }
+    }
+}
+
+/// What `dispatch` made of a command.
+enum Reply {
+    Now(String),
+    /// In slot `index` of a batch for a shard, once that's done.
+    Shard { name: &'static str, queued: Instant, batch: Arc<BatchReplies>, index: usize },
+    /// No reply is coming: the shard went away.
+    Gone,
+}
+
+/// Waits for a dispatched command's reply and logs the command as done.
+/// `None` if the shard went away before answering.
+async fn finish(router: Arc<ShardRouter>, addr: Peer, reply: Reply, logged: String, started: Instant) -> Option<Bytes> {
+    let reply = match reply {
+        Reply::Now(reply) => Some(reply.into()),
+        Reply::Shard { name, queued, batch, index } => {
+            let reply = batch.take(index).await;
+            router.metrics().latency.record(name, Stage::Reply, queued.elapsed());
+            reply
+        }
+        Reply::Gone => None,
+    };
+    let took = started.elapsed();
+    debug!(took_us = took.as_micros() as u64, "request done");
+    router.slowlog().record(&addr, &logged, took);
+    reply
+}
+
+/// Folds per-shard replies into one: the first error, or `ok` if none failed.
+fn first_error(replies: Vec<String>, ok: &str) -> String {
+    replies
+        .into_iter()
+        .find(|reply| reply.starts_with("ERR"))
+        .unwrap_or_else(|| ok.to_string())
+}
@@ -0,0 +1,146 @@
+use std::fmt::Write as _;
+
+use crate::cluster;
+use crate::metrics::ShardStats;
+use crate::raft;
+use crate::replication;
+use crate::shard_engine::router::ShardRouter;
+
+/// Sections of a bare `INFO`, in order. `all` / `everything` / `default` ask
+/// for the same.
+const SECTIONS: [&str; 9] = [
+    "server",
+    "clients",
+    "memory",
+    "persistence",
+    "stats",
+    "replication",
+    "raft",
+    "cluster",
+    "keyspace",
+];
+
+/// `INFO [section]`. Sections are separated by blank lines, and so is the end
+/// of the reply. An unknown section gets just the blank line.
+pub async fn info(router: &ShardRouter, section: Option<&str>) -> String {
+    let shards: Vec<ShardStats> = (0..router.shard_count()).map(|id| router.shard_metrics(id).stats()).collect();
+    let mut info = String::new();
+    for name in SECTIONS {
+        if section.is_some_and(|s| s != name && !matches!(s, "all" | "default" | "everything")) {
+            continue;
+        }
+        if !info.is_empty() {
+            info.push('\n');
+        }
+        match name {
+            "server" => server(router, &mut info),
+            "clients" => clients(router, &mut info),
+            "memory" => memory(router, &shards, &mut info),
+            "persistence" => persistence(router, &shards, &mut info),
+            "stats" => stats(router, &shards, &mut info),
+            "replication" => info.push_str(&replication::info(router).await),
+            "raft" => info.push_str(&raft::info(router).await),
+            "cluster" => info.push_str(&cluster::info_section(router)),
+            _ => keyspace(&shards, &mut info),
+        }
+    }
+    info.push('\n');
+    info
+}
+
+fn server(router: &ShardRouter, info: &mut String) {
+    let config = router.config();
+    let uptime = router.metrics().uptime().as_secs();
+    let mode = if router.raft().is_some() {
+        "raft"
+    } else if router.cluster().is_some() {
+        "cluster"
+    } else {
+        "standalone"
+    };
+    let _ = writeln!(info, "# Server");
+    let _ = writeln!(info, "rustkv_version:{}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(info, "server_mode:{}", mode);
+    let _ = writeln!(info, "process_id:{}", std::process::id());
+    let _ = writeln!(info, "tcp_port:{}", config.port);
+    let _ = writeln!(info, "tls_port:{}", config.tls_port.unwrap_or(0));
+    let _ = writeln!(info, "uptime_in_seconds:{}", uptime);
+    let _ = writeln!(info, "uptime_in_days:{}", uptime / 86400);
+    let _ = writeln!(info, "shards:{}", router.shard_count());
+    let _ = writeln!(info, "config_file:{}", config.config_file.as_deref().unwrap_or(""));
+}
+
+fn clients(router: &ShardRouter, info: &mut String) {
+    let _ = writeln!(info, "# Clients");
+    let _ = writeln!(info, "connected_clients:{}", router.metrics().connected_clients());
+    let _ = writeln!(info, "maxclients:{}", router.config().maxclients);
+    // No command blocks waiting for data yet.
+    let _ = writeln!(info, "blocked_clients:0");
+}
+
+fn memory(router: &ShardRouter, shards: &[ShardStats], info: &mut String) {
+    let config = router.config();
+    let used: u64 = shards.iter().map(|s| s.memory).sum();
+    let _ = writeln!(info, "# Memory");
+    let _ = writeln!(info, "used_memory:{}", used);
+    let _ = writeln!(info, "used_memory_human:{}", human(used));
+    let _ = writeln!(info, "maxmemory:{}", config.maxmemory);
+    let _ = writeln!(info, "maxmemory_human:{}", human(config.maxmemory as u64));
+    let _ = writeln!(info, "maxmemory_policy:{}", config.maxmemory_policy.name());
+}
+
+fn persistence(router: &ShardRouter, shards: &[ShardStats], info: &mut String) {
+    let config = router.config();
+    // The server as a whole is only as saved as its least recent shard.
+    let last_save = shards.iter().map(|s| s.last_save).min().unwrap_or(0);
+    let ok = shards.iter().all(|s| s.last_snapshot_ok);
+    let _ = writeln!(info, "# Persistence");
+    let _ = writeln!(info, "snapshot_in_progress:{}", shards.iter().any(|s| s.snapshotting) as u8);
+    let _ = writeln!(info, "snapshot_last_save_time:{}", last_save);
+    let _ = writeln!(info, "snapshot_last_status:{}", if ok { "ok" } else { "err" });
+    let _ = writeln!(info, "snapshot_interval:{}", config.snapshot_interval);
+    let _ = writeln!(info, "snapshot_writes:{}", config.snapshot_writes);
+    let _ = writeln!(info, "wal_size:{}", shards.iter().map(|s| s.wal_size).sum::<u64>());
+    let _ = writeln!(info, "wal_pending:{}", shards.iter().map(|s| s.wal_pending).sum::<u64>());
+    for (id, shard) in shards.iter().enumerate() {
+        let _ = writeln!(
+            info,
+            "shard{}:last_save_time={},last_status={},last_duration_sec={:.3},wal_size={},wal_pending={}",
+            id,
+            shard.last_save,
+            if shard.last_snapshot_ok { "ok" } else { "err" },
+            shard.last_snapshot_secs,
+            shard.wal_size,
+            shard.wal_pending
+        );
+    }
+}
+
+fn stats(router: &ShardRouter, shards: &[ShardStats], info: &mut String) {
+    let metrics = router.metrics();
+    let _ = writeln!(info, "# Stats");
+    let _ = writeln!(info, "total_connections_received:{}", metrics.connections());
+    let _ = writeln!(info, "total_commands_processed:{}", metrics.commands());
+    let _ = writeln!(info, "keyspace_hits:{}", shards.iter().map(|s| s.hits).sum::<u64>());
+    let _ = writeln!(info, "keyspace_misses:{}", shards.iter().map(|s| s.misses).sum::<u64>());
+    let _ = writeln!(info, "expired_keys:{}", shards.iter().map(|s| s.expired).sum::<u64>());
+    let _ = writeln!(info, "evicted_keys:{}", shards.iter().map(|s| s.evicted).sum::<u64>());
+}
+
+/// Shards that hold keys, like Redis lists its non-empty databases.
+fn keyspace(shards: &[ShardStats], info: &mut String) {
+    let _ = writeln!(info, "# Keyspace");
+    for (id, shard) in shards.iter().enumerate().filter(|(_, s)| s.keys > 0) {
+        let _ = writeln!(info, "shard{}:keys={},expires={}", id, shard.keys, shard.expiring_keys);
+    }
+}
+
+fn human(bytes: u64) -> String {
+    const UNITS: [(&str, u64); 3] = [("G", 1 << 30), ("M", 1 << 20), ("K", 1 << 10)];
+    for (unit, size) in UNITS {
+        if bytes >= size {
+            return format!("{:.2}{}", bytes as f64 / size as f64, unit);
+        }
+    }
+    format!("{}B", bytes)
+}
@@ -1,14 +1,236 @@
+pub mod clients;
 pub mod connection;
+pub mod info;
+pub mod tls;
 
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::AsFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::metrics::ClientGuard;
 use crate::shard_engine::router::ShardRouter;
+#[cfg(feature = "io-uring")]
+use crate::uring;
 use connection::handle_connection;
-use tokio::net::TcpListener;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tracing::{Instrument, debug, error, info_span, warn};
 
-pub async fn run(listener: TcpListener, router: Arc<ShardRouter>) {
+const MAX_CLIENTS_REACHED: &[u8] = b"ERR max number of clients reached\n";
+
+// Connections waiting to be accepted, per listening socket.
+const BACKLOG: i32 = 1024;
+
+// Time a client gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Anything a client connection can run over: plain TCP, TLS or a Unix
+/// socket. An io_uring socket isn't `Send`, so its connections stay on the
+/// worker that accepted them.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + 'static> Stream for S {}
+
+/// Where a client connected from.
+#[derive(Clone, Debug)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    /// Unix socket clients are anonymous, so they go by the socket's path.
+    Unix(Arc<str>),
+}
+
+impl Peer {
+    /// The peer's IP, or loopback for a Unix socket client, which is on
+    /// this host.
+    pub fn ip(&self) -> String {
+        match self {
+            Peer::Tcp(addr) => addr.ip().to_string(),
+            Peer::Unix(_) => "127.0.0.1".into(),
+        }
+    }
+}
+
+/// `ip:port`, or `path:0` for a Unix socket client like Redis shows it.
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr),
+            Peer::Unix(path) => write!(f, "{}:0", path),
+        }
+    }
+}
+
+/// Accepts clients on `listener`, over TLS if `tls` is given.
+pub async fn run(listener: TcpListener, tls: Option<TlsAcceptor>, router: Arc<ShardRouter>) {
     loop {
-        let (socket, addr) = listener.accept().await.unwrap();
-        tokio::spawn(handle_connection(socket, addr, router.clone()));
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let Some(connected) = admit(&router) else {
+            warn!(%addr, "max number of clients reached");
+            let _ = socket.try_write(MAX_CLIENTS_REACHED);
+            continue;
+        };
+        if let Err(e) = keepalive(&socket, router.config().tcp_keepalive) {
+            warn!(%addr, "can't set TCP keepalive: {}", e);
+        }
+        let span = info_span!("connection", %addr, tls = tls.is_some());
+        let Some(acceptor) = tls.clone() else {
+            tokio::spawn(handle_connection(socket, Peer::Tcp(addr), router.clone(), connected, None).instrument(span));
+            continue;
+        };
+        let router = router.clone();
+        let cert_users = router.config().tls_auth_clients_user;
+        tokio::spawn(
+            async move {
+                let stream = match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake failed: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake timed out");
+                        return;
+                    }
+                };
+                let cert_user = if cert_users { tls::cert_user(stream.get_ref().1) } else { None };
+                handle_connection(stream, Peer::Tcp(addr), router, connected, cert_user).await;
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Accepts clients on the `unixsocket` at `path`.
+pub async fn run_unix(listener: UnixListener, path: String, router: Arc<ShardRouter>) {
+    let path: Arc<str> = Arc::from(path);
+    loop {
+        let socket = match listener.accept().await {
+            Ok((socket, _)) => socket,
+            Err(e) => {
+                error!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let addr = Peer::Unix(path.clone());
+        let Some(connected) = admit(&router) else {
+            warn!(%addr, "max number of clients reached");
+            let _ = socket.try_write(MAX_CLIENTS_REACHED);
+            continue;
+        };
+        let span = info_span!("connection", %addr, tls = false);
+        tokio::spawn(handle_connection(socket, addr, router.clone(), connected, None).instrument(span));
+    }
+}
+
+/// Accepts plain TCP clients on every io_uring worker at once. Each serves
+/// the clients it accepted itself. Workers and `listeners` are paired round
+/// robin, so each side gets at least one of the other.
+#[cfg(feature = "io-uring")]
+pub fn run_uring(listeners: Vec<std::net::TcpListener>, router: Arc<ShardRouter>) {
+    let listeners: Vec<_> = listeners.into_iter().map(uring::net::TcpListener::new).collect();
+    let workers = uring::workers();
+    for i in 0..listeners.len().max(workers.len()) {
+        let (listener, router) = (listeners[i % listeners.len()].clone(), router.clone());
+        workers[i % workers.len()].spawn(move || accept_uring(listener, router));
+    }
+}
+
+#[cfg(feature = "io-uring")]
+async fn accept_uring(listener: uring::net::TcpListener, router: Arc<ShardRouter>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("accept failed: {}", e);
+                continue;
+            }
+        };
+        let Some(connected) = admit(&router) else {
+            warn!(%addr, "max number of clients reached");
+            let _ = socket.try_write(MAX_CLIENTS_REACHED);
+            continue;
+        };
+        if let Err(e) = keepalive(&socket, router.config().tcp_keepalive) {
+            warn!(%addr, "can't set TCP keepalive: {}", e);
+        }
+        let span = info_span!("connection", %addr, tls = false);
+        tokio::task::spawn_local(handle_connection(socket, Peer::Tcp(addr), router.clone(), connected, None).instrument(span));
+    }
+}
+
+/// Counts a new client, or turns it away if that makes more than
+/// `maxclients`. Counted on accept rather than in the client's task, so a
+/// burst of connections can't all slip in before any of them shows up.
+fn admit(router: &ShardRouter) -> Option<ClientGuard> {
+    let connected = router.metrics().client_connected();
+    (router.metrics().connected_clients() <= router.config().maxclients as u64).then_some(connected)
+}
+
+/// Listens on `port` with `count` sockets. More than one share it through
+/// `SO_REUSEPORT`: the kernel hands each new connection to one of them, so
+/// their accept loops don't queue up behind a single socket when many
+/// clients connect at once.
+pub fn bind(port: u16, count: usize) -> io::Result<Vec<TcpListener>> {
+    (0..count)
+        .map(|_| {
+            let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))?;
+            socket.set_reuse_address(true)?;
+            if count > 1 {
+                socket.set_reuse_port(true)?;
+            }
+            socket.bind(&SocketAddr::from(([0, 0, 0, 0], port)).into())?;
+            socket.listen(BACKLOG)?;
+            socket.set_nonblocking(true)?;
+            TcpListener::from_std(socket.into())
+        })
+        .collect()
+}
+
+/// Listens on a Unix socket, replacing a stale one left at `path`, with
+/// mode `perm` if given. A socket something still answers on, or a file
+/// that isn't a socket, is left alone and fails the bind.
+pub fn bind_unix(path: &str, perm: Option<u32>) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, "another process is listening on it"));
+            }
+            fs::remove_file(path)?;
+        }
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "a file that isn't a socket is in the way")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(perm) = perm {
+        fs::set_permissions(path, Permissions::from_mode(perm))?;
+    }
+    Ok(listener)
+}
+
+/// Probes the client every `secs` seconds once it goes quiet, so a peer that
+/// vanished without closing is noticed. Redis probes three times as often
+/// after the first.
+fn keepalive(socket: &impl AsFd, secs: u64) -> io::Result<()> {
+    if secs == 0 {
+        return Ok(());
     }
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(secs))
+        .with_interval(Duration::from_secs((secs / 3).max(1)));
+    SockRef::from(socket).set_tcp_keepalive(&keepalive)
 }
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ServerConnection, WebPkiClientVerifier};
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+
+use crate::config::{Config, TlsAuthClients};
+
+/// The acceptor for `tls-port`, from the PEM files named in the config.
+pub fn acceptor(config: &Config) -> Result<TlsAcceptor, String> {
+    let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Err("tls-port needs tls-cert-file and tls-key-file".into());
+    };
+    let certs = read_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| format!("{}: {}", key_file, e))?;
+
+    let builder = ServerConfig::builder();
+    let builder = match (config.tls_auth_clients, &config.tls_ca_cert_file) {
+        (TlsAuthClients::No, _) | (_, None) => builder.with_no_client_auth(),
+        (auth, Some(ca_file)) => {
+            let mut roots = RootCertStore::empty();
+            for ca in read_certs(ca_file)? {
+                roots.add(ca).map_err(|e| format!("{}: {}", ca_file, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            let verifier = match auth {
+                TlsAuthClients::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| format!("{}: {}", ca_file, e))?)
+        }
+    };
+    let server = builder.with_single_cert(certs, key).map_err(|e| format!("{}: {}", cert_file, e))?;
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", path));
+    }
+    Ok(certs)
+}
+
+/// The subject CN of the client's certificate, which `tls-auth-clients-user`
+/// takes as its ACL user. The handshake already checked the certificate.
+pub fn cert_user(connection: &ServerConnection) -> Option<String> {
+    let cert = connection.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(name.to_string())
+}
@@ -0,0 +1,39 @@
+use std::collections::BTreeMap;
+use std::thread;
+
+use core_affinity::CoreId;
+use tokio::runtime::{Builder, Handle};
+use tracing::warn;
+
+/// Pins the calling thread to `core`. A core the machine doesn't have only
+/// gets a warning, and the thread runs unpinned.
+pub fn pin(core: usize) {
+    if !core_affinity::set_for_current(CoreId { id: core }) {
+        warn!(core, "can't pin thread to core");
+    }
+}
+
+/// Starts a thread pinned to each of `cores` running a current-thread
+/// runtime, and returns their handles by core. Tasks spawned there never
+/// leave that core.
+pub fn runtimes(cores: &[usize]) -> BTreeMap<usize, Handle> {
+    let mut runtimes = BTreeMap::new();
+    for &core in cores {
+        if runtimes.contains_key(&core) {
+            continue;
+        }
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("can't build a shard runtime");
+        runtimes.insert(core, runtime.handle().clone());
+        thread::Builder::new()
+            .name(format!("shard-core-{}", core))
+            .spawn(move || {
+                pin(core);
+                runtime.block_on(std::future::pending::<()>());
+            })
+            .expect("can't start a shard thread");
+    }
+    runtimes
+}
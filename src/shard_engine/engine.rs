@@ -1,17 +1,38 @@
+use std::sync::Arc;
+
+use tokio::runtime::Handle;
 use tokio::sync::mpsc;
 
-use crate::{engine, shard_engine::shard::Shard};
+use crate::{
+    config::Config,
+    engine::{self, raft::RAFT_MAGIC, script::ScriptState, wal::WAL_MAGIC},
+    metrics::ShardMetrics,
+    raft::RaftNet,
+    shard_engine::{cores, shard::Shard},
+};
 
 const CHANNEL_CAPACITY: usize = 100_000;
 
-pub fn spawn_shards(n: usize) -> Vec<Shard> {
+/// Starts `n` shards, each with its WAL task. With `shard-cores` set, a
+/// shard and its WAL run on their core's own thread; otherwise on the I/O
+/// workers.
+pub fn spawn_shards(n: usize, config: &Config, raft: Option<Arc<RaftNet>>) -> Vec<Shard> {
+    let magic = if raft.is_some() { RAFT_MAGIC } else { WAL_MAGIC };
+    let runtimes = config.shard_cores.as_deref().map(cores::runtimes);
     let mut shards = Vec::with_capacity(n);
     for id in 0..n {
+        let runtime = match (&config.shard_cores, &runtimes) {
+            (Some(cores), Some(runtimes)) => runtimes[&cores[id % cores.len()]].clone(),
+            _ => Handle::current(),
+        };
+        let _runtime = runtime.enter();
         let (cmd_tx, cmd_rx) = mpsc::channel(CHANNEL_CAPACITY);
         let (wal_tx, wal_rx) = mpsc::channel(CHANNEL_CAPACITY);
-        engine::start_wal_task(id, wal_rx);
-        engine::start_engine(id, cmd_rx, wal_tx);
-        shards.push(Shard::new(id, cmd_tx));
+        let script = Arc::new(ScriptState::default());
+        let metrics = Arc::new(ShardMetrics::default());
+        engine::start_wal_task(id, magic, wal_rx, metrics.clone());
+        engine::start_engine(id, cmd_rx, wal_tx, script.clone(), config.shard(n), raft.clone(), metrics.clone());
+        shards.push(Shard::new(id, cmd_tx, script, metrics));
     }
     shards
 }
@@ -1,3 +1,4 @@
+pub mod cores;
 pub mod engine;
 pub mod router;
 pub mod shard;
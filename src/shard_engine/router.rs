@@ -1,42 +1,256 @@
-use crate::engine::Command;
+use crate::acl::Acl;
+use crate::cluster::{Cluster, hash_tag};
+use crate::config::Config;
+use crate::engine::{Command, Queued, Resp, Stray};
+use crate::engine::script::ScriptCache;
+use crate::metrics::slowlog::SlowLog;
+use crate::metrics::{Metrics, ShardMetrics};
+use crate::raft::RaftNet;
+use crate::replication::Replication;
+use crate::server::clients::Clients;
 use crate::shard_engine::shard::Shard;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+use tracing::{Span, error};
 
 pub struct ShardRouter {
     shards: Vec<Shard>,
     shard_count: usize,
+    config: Config,
+    scripts: ScriptCache,
+    replication: Replication,
+    raft: Option<Arc<RaftNet>>,
+    cluster: Option<Arc<Cluster>>,
+    metrics: Arc<Metrics>,
+    slowlog: SlowLog,
+    clients: Arc<Clients>,
+    acl: Acl,
 }
 
 impl ShardRouter {
-    pub fn new(shards: Vec<Shard>) -> Self {
+    pub fn new(
+        shards: Vec<Shard>,
+        config: Config,
+        replication: Replication,
+        raft: Option<Arc<RaftNet>>,
+        cluster: Option<Arc<Cluster>>,
+        acl: Acl,
+    ) -> Self {
         let shard_count = shards.len();
+        let slower_than = u64::try_from(config.slowlog_log_slower_than).ok().map(Duration::from_micros);
+        let slowlog = SlowLog::new(slower_than, config.slowlog_max_len);
         Self {
             shards,
             shard_count,
+            config,
+            scripts: ScriptCache::default(),
+            replication,
+            raft,
+            cluster,
+            metrics: Arc::default(),
+            slowlog,
+            clients: Arc::default(),
+            acl,
         }
     }
 
+    pub fn shard_count(&self) -> usize {
+        self.shard_count
+    }
+
+    /// The settings the server was started with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    /// This node's Raft links, if running in Raft mode.
+    pub fn raft(&self) -> Option<&RaftNet> {
+        self.raft.as_deref()
+    }
+
+    /// This node's view of the cluster, if running in cluster mode.
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.as_deref()
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn slowlog(&self) -> &SlowLog {
+        &self.slowlog
+    }
+
+    /// The open client connections.
+    pub fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+
+    /// Users and their permissions.
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    pub fn shard_metrics(&self, shard_id: usize) -> &ShardMetrics {
+        &self.shards[shard_id].metrics
+    }
+
+    /// Messages, single commands or batches, sent to a shard that it hasn't
+    /// picked up yet.
+    pub fn queue_depth(&self, shard_id: usize) -> usize {
+        let cmd_tx = &self.shards[shard_id].cmd_tx;
+        cmd_tx.max_capacity() - cmd_tx.capacity()
+    }
+
+    pub fn scripts(&self) -> &ScriptCache {
+        &self.scripts
+    }
+
+    /// Stops whichever shard is currently running a script. Returns false if
+    /// none is.
+    pub fn kill_script(&self) -> bool {
+        self.shards.iter().any(|shard| shard.script.kill())
+    }
+
     pub async fn route(&self, cmd: Command) {
-        let key = cmd.primary_key();
-        let shard_id = self.compute_shard_id(key);
-        let shard = &self.shards[shard_id];
+        let shard_id = self.shard_of(cmd.primary_key());
+        if let Some(cmd) = self.admit(shard_id, cmd) {
+            self.send(shard_id, vec![(cmd, Span::current())]).await;
+        }
+    }
+
+    /// Sends a client's commands for one shard in a single message, each
+    /// with the span of its request. Each one's key has to be on `shard_id`.
+    pub async fn route_batch(&self, shard_id: usize, cmds: Vec<(Command, Span)>) {
+        let cmds: Vec<(Command, Span)> = cmds
+            .into_iter()
+            .filter_map(|(cmd, span)| Some((self.admit(shard_id, cmd)?, span)))
+            .collect();
+        if !cmds.is_empty() {
+            self.send(shard_id, cmds).await;
+        }
+    }
+
+    /// Counts a command headed for `shard_id`, or answers it right away if
+    /// it can't run there.
+    fn admit(&self, shard_id: usize, cmd: Command) -> Option<Command> {
+        // A script runs inside a single engine loop, so every key it declares
+        // has to live on that shard.
+        if let Command::Eval { keys, .. } = &cmd
+            && keys.iter().any(|k| self.shard_of(k) != shard_id)
+        {
+            cmd.reject("ERR CROSSSLOT Keys in script don't hash to the same shard\n");
+            return None;
+        }
+
+        self.shards[shard_id].metrics.command(&cmd);
+        Some(cmd)
+    }
+
+    /// Sends a command built by `make` to every shard and collects their
+    /// replies, skipping shards that went away. These are replies to admin
+    /// commands, so they're taken as text.
+    pub async fn broadcast(&self, make: impl Fn(Resp) -> Command) -> Vec<String> {
+        let mut pending = Vec::with_capacity(self.shard_count);
+        for shard_id in 0..self.shard_count {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            self.send_to(shard_id, make(resp_tx.into())).await;
+            pending.push(resp_rx);
+        }
 
-        match shard.cmd_tx.try_send(cmd) {
+        let mut replies = Vec::with_capacity(self.shard_count);
+        for resp_rx in pending {
+            if let Ok(reply) = resp_rx.await {
+                replies.push(String::from_utf8_lossy(&reply).into_owned());
+            }
+        }
+        replies
+    }
+
+    pub async fn send_to(&self, shard_id: usize, cmd: Command) {
+        self.send(shard_id, vec![(cmd, Span::current())]).await;
+    }
+
+    async fn send(&self, shard_id: usize, cmds: Vec<(Command, Span)>) {
+        let shard = &self.shards[shard_id];
+        let queued = Queued { cmds, at: Instant::now() };
+        match shard.cmd_tx.try_send(queued) {
             Ok(_) => {}
-            Err(TrySendError::Full(cmd)) => {
+            Err(TrySendError::Full(queued)) => {
                 // 3. BACKPRESSURE: Only await if we are truly flooded.
-                if let Err(e) = shard.cmd_tx.send(cmd).await {
-                    eprintln!("Shard {} channel closed: {}", shard_id, e);
+                if let Err(e) = shard.cmd_tx.send(queued).await {
+                    error!(shard = shard_id, "shard channel closed: {}", e);
                 }
             }
             Err(TrySendError::Closed(_)) => {
-                eprintln!("Shard {} channel closed", shard_id);
+                error!(shard = shard_id, "shard channel closed");
             }
         }
     }
 
-    fn compute_shard_id(&self, key: &str) -> usize {
-        let hash = fxhash::hash64(key.as_bytes());
-        (hash as usize) % self.shard_count
+    pub fn shard_of(&self, key: &[u8]) -> usize {
+        shard_for(key, self.shard_count)
     }
-}
\ No newline at end of file
+
+    /// Moves keys that sit on another shard than `shard_of` picks for them:
+    /// ones with a `{hash tag}` written before tags picked the shard. If
+    /// both shards have the key, the copy where it belongs wins. Returns how
+    /// many moved.
+    pub async fn move_strays(&self) -> usize {
+        let mut moved = 0;
+        for shard_id in 0..self.shard_count {
+            for stray in self.strays(shard_id).await {
+                let (resp_tx, resp_rx) = oneshot::channel();
+                let (key, value, ttl_ms) = (stray.key.clone(), stray.value, stray.ttl_ms);
+                self.route(Command::Restore { key, value, ttl_ms, replace: false, resp: resp_tx.into() }).await;
+                match resp_rx.await {
+                    Ok(reply) if reply == "OK\n" => moved += 1,
+                    Ok(reply) if reply.starts_with(b"BUSYKEY") => {}
+                    _ => continue,
+                }
+                let (resp_tx, resp_rx) = oneshot::channel();
+                let (key, version) = (stray.key, stray.version);
+                self.send_to(shard_id, Command::DelIfVer { key, version, resp: resp_tx.into() }).await;
+                let _ = resp_rx.await;
+            }
+        }
+        moved
+    }
+
+    /// The keys on `shard_id` that belong on another shard.
+    pub async fn strays(&self, shard_id: usize) -> Vec<Stray> {
+        let (out, strays) = oneshot::channel();
+        self.send_to(shard_id, Command::Strays { shards: self.shard_count, out }).await;
+        strays.await.unwrap_or_default()
+    }
+}
+
+/// The shard out of `shard_count` that `key` lives on. Keys sharing a
+/// `{hash tag}` land on the same shard, which is what lets a script work on
+/// several of them.
+pub fn shard_for(key: &[u8], shard_count: usize) -> usize {
+    let hash = fxhash::hash64(hash_tag(key));
+    (hash as usize) % shard_count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tagged_keys_share_a_shard() {
+        for shards in [1, 7, 16] {
+            let shard = shard_for(b"{user:42}:profile", shards);
+            assert_eq!(shard_for(b"{user:42}:cart", shards), shard);
+            assert_eq!(shard_for(b"user:42", shards), shard);
+            assert!(shard < shards);
+        }
+    }
+}
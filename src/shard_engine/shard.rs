@@ -1,14 +1,21 @@
+use std::sync::Arc;
+
 use tokio::sync::mpsc::Sender;
 
-use crate::engine::Command;
+use crate::engine::Queued;
+use crate::engine::script::ScriptState;
+use crate::metrics::ShardMetrics;
 
 pub struct Shard {
+    #[allow(dead_code)]
     id: usize,
-    pub cmd_tx: Sender<Command>,
+    pub cmd_tx: Sender<Queued>,
+    pub script: Arc<ScriptState>,
+    pub metrics: Arc<ShardMetrics>,
 }
 
 impl Shard {
-    pub fn new(id: usize, cmd_tx: Sender<Command>) -> Self {
-        Self { id, cmd_tx }
+    pub fn new(id: usize, cmd_tx: Sender<Queued>, script: Arc<ScriptState>, metrics: Arc<ShardMetrics>) -> Self {
+        Self { id, cmd_tx, script, metrics }
     }
 }
@@ -0,0 +1,128 @@
+use std::cell::RefCell;
+
+use io_uring::IoUring;
+use tracing::warn;
+
+/// Size of every buffer, registered or not.
+pub const BUFFER_SIZE: usize = 16 * 1024;
+// Registered buffers per ring. Past that, I/O falls back to heap buffers.
+const BUFFERS: usize = 256;
+
+thread_local! {
+    static POOL: RefCell<Pool> = const { RefCell::new(Pool { base: std::ptr::null_mut(), free: Vec::new() }) };
+}
+
+/// The thread's registered buffers: one region, carved into `BUFFERS`.
+struct Pool {
+    base: *mut u8,
+    free: Vec<u16>,
+}
+
+/// Registers this thread's buffers with `ring`. If the kernel won't take
+/// them (a low `RLIMIT_MEMLOCK` on older kernels), I/O still works, with
+/// plain heap buffers.
+pub(super) fn register(ring: &IoUring) {
+    // Lives as long as the thread, which is as long as the process.
+    let region: &'static mut [u8] = Box::leak(vec![0u8; BUFFERS * BUFFER_SIZE].into_boxed_slice());
+    let base = region.as_mut_ptr();
+    let iovecs: Vec<libc::iovec> = (0..BUFFERS)
+        .map(|i| libc::iovec {
+            // SAFETY: within the region.
+            iov_base: unsafe { base.add(i * BUFFER_SIZE) }.cast(),
+            iov_len: BUFFER_SIZE,
+        })
+        .collect();
+    // SAFETY: the region is never freed.
+    if let Err(e) = unsafe { ring.submitter().register_buffers(&iovecs) } {
+        warn!("can't register io_uring buffers, using unregistered ones: {}", e);
+        return;
+    }
+    POOL.with(|pool| {
+        *pool.borrow_mut() = Pool { base, free: (0..BUFFERS as u16).rev().collect() };
+    });
+}
+
+/// A buffer for one operation: a registered one if any is free. It goes
+/// back to the pool when dropped.
+pub struct IoBuf {
+    mem: Mem,
+    len: usize,
+}
+
+enum Mem {
+    Fixed { index: u16, ptr: *mut u8 },
+    Heap(Box<[u8]>),
+}
+
+impl IoBuf {
+    pub fn new() -> IoBuf {
+        let fixed = POOL.with(|pool| {
+            let mut pool = pool.borrow_mut();
+            let index = pool.free.pop()?;
+            // SAFETY: within the region.
+            let ptr = unsafe { pool.base.add(index as usize * BUFFER_SIZE) };
+            Some(Mem::Fixed { index, ptr })
+        });
+        IoBuf { mem: fixed.unwrap_or_else(|| Mem::Heap(vec![0; BUFFER_SIZE].into_boxed_slice())), len: 0 }
+    }
+
+    /// A buffer holding as much of `data` as fits.
+    pub fn copy_from(data: &[u8]) -> IoBuf {
+        let mut buf = IoBuf::new();
+        let len = data.len().min(BUFFER_SIZE);
+        // SAFETY: the buffer is BUFFER_SIZE long and nothing else uses it.
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), buf.as_mut_ptr(), len) };
+        buf.len = len;
+        buf
+    }
+
+    /// The registered buffer's index, for the `*Fixed` operations.
+    pub fn index(&self) -> Option<u16> {
+        match self.mem {
+            Mem::Fixed { index, .. } => Some(index),
+            Mem::Heap(_) => None,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        BUFFER_SIZE
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Marks the first `len` bytes as filled, after a read.
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(BUFFER_SIZE);
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        match &self.mem {
+            Mem::Fixed { ptr, .. } => *ptr,
+            Mem::Heap(heap) => heap.as_ptr(),
+        }
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        match &mut self.mem {
+            Mem::Fixed { ptr, .. } => *ptr,
+            Mem::Heap(heap) => heap.as_mut_ptr(),
+        }
+    }
+
+    /// The filled part.
+    pub fn filled(&self) -> &[u8] {
+        // SAFETY: `len` bytes were written, and the buffer is ours alone
+        // while no operation has it.
+        unsafe { std::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for IoBuf {
+    fn drop(&mut self) {
+        if let Mem::Fixed { index, .. } = self.mem {
+            let _ = POOL.try_with(|pool| pool.borrow_mut().free.push(index));
+        }
+    }
+}
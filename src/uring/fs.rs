@@ -0,0 +1,98 @@
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+
+use futures_util::future;
+
+use super::Op;
+use super::buf::{BUFFER_SIZE, IoBuf};
+
+/// A file appended to through the ring, like a WAL segment.
+pub struct File {
+    file: std::fs::File,
+    pos: u64,
+}
+
+impl File {
+    /// Appends go after whatever `file` already holds.
+    pub fn from_std(file: std::fs::File) -> io::Result<File> {
+        let pos = file.metadata()?.len();
+        Ok(File { file, pos })
+    }
+
+    /// Appends `data`. It's split into buffers that all go to the kernel in
+    /// one submission.
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let fd = self.file.as_raw_fd();
+        let writes = data
+            .chunks(BUFFER_SIZE)
+            .zip((self.pos..).step_by(BUFFER_SIZE))
+            .map(|(chunk, offset)| write_at(fd, IoBuf::copy_from(chunk), offset));
+        if let Err(e) = future::try_join_all(writes).await {
+            // The next append goes where this one should have, so it leaves
+            // no hole. Whatever part of this one landed is cut off.
+            let _ = self.file.set_len(self.pos);
+            return Err(e);
+        }
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /// Waits for what was appended to reach the disk.
+    pub async fn sync_data(&mut self) -> io::Result<()> {
+        Op::fsync(self.file.as_raw_fd()).await.map(drop)
+    }
+
+    /// Nothing is buffered here.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes all of `buf` at `offset`, picking up after short writes.
+async fn write_at(fd: RawFd, mut buf: IoBuf, offset: u64) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < buf.len() {
+        let mut op = Op::write(fd, buf, sent, offset + sent as u64);
+        let n = (&mut op).await? as usize;
+        buf = op.take_buf();
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        sent += n;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::super::Worker;
+    use super::*;
+
+    #[test]
+    fn failed_appends_leave_no_hole() {
+        let path = std::env::temp_dir().join(format!("rustkv-uring-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (done_tx, done_rx) = mpsc::channel();
+        let worker = Worker::start("uring-test".into(), None).unwrap();
+        let file_path = path.clone();
+        worker.spawn(move || async move {
+            let writable = || {
+                let mut options = std::fs::OpenOptions::new();
+                options.create(true).truncate(false).write(true).open(&file_path).unwrap()
+            };
+            let mut file = File::from_std(writable()).unwrap();
+            file.write_all(b"first ").await.unwrap();
+            // Appends through a read-only handle fail.
+            file.file = std::fs::File::open(&file_path).unwrap();
+            let failed = file.write_all(&vec![b'x'; BUFFER_SIZE * 2 + 1]).await;
+            file.file = writable();
+            file.write_all(b"second").await.unwrap();
+            let _ = done_tx.send(failed.is_err());
+        });
+        assert!(done_rx.recv().unwrap());
+        assert_eq!(std::fs::read(&path).unwrap(), b"first second");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,337 @@
+//! An io_uring backend for the plain TCP port and WAL appends, built with
+//! the `io-uring` feature.
+//!
+//! Each worker thread owns a ring and runs a current-thread runtime. I/O
+//! goes through a pool of buffers registered with the ring, and entries
+//! queued while the runtime is busy are submitted together, in one syscall,
+//! when it goes idle. Completions wake their tasks through the ring's fd,
+//! which the runtime polls like any other.
+
+pub mod buf;
+pub mod fs;
+pub mod net;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use io_uring::{IoUring, opcode, squeue};
+use tokio::io::unix::AsyncFd;
+use tokio::runtime::Builder;
+use tokio::sync::mpsc;
+use tokio::task::{self, LocalSet};
+
+use crate::shard_engine::cores;
+use buf::IoBuf;
+
+// Submission queue entries per ring; the queue is submitted early if it fills.
+const RING_ENTRIES: u32 = 1024;
+// `user_data` of cancellations, whose completions nobody waits for.
+const CANCEL: u64 = u64::MAX;
+
+static WORKERS: OnceLock<Vec<Worker>> = OnceLock::new();
+
+thread_local! {
+    static RING: RefCell<Option<Ring>> = const { RefCell::new(None) };
+}
+
+type Job = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// A thread with its own ring. Tasks given to `spawn` stay on it, so they
+/// can hold the thread's sockets and buffers.
+pub struct Worker {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl Worker {
+    /// Starts a worker thread called `name`, pinned to `core` if given.
+    fn start(name: String, core: Option<usize>) -> io::Result<Worker> {
+        let (jobs, mut job_rx) = mpsc::unbounded_channel::<Job>();
+        let (ready_tx, ready_rx) = std::sync::mpsc::channel();
+        thread::Builder::new().name(name).spawn(move || {
+            if let Some(core) = core {
+                cores::pin(core);
+            }
+            let runtime = Ring::install().and_then(|()| {
+                Builder::new_current_thread()
+                    .enable_all()
+                    .on_thread_park(submit)
+                    .build()
+            });
+            let runtime = match runtime {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    return;
+                }
+            };
+            let _ = ready_tx.send(Ok(()));
+            LocalSet::new().block_on(&runtime, async move {
+                task::spawn_local(reap());
+                while let Some(job) = job_rx.recv().await {
+                    task::spawn_local(job());
+                }
+                std::future::pending::<()>().await;
+            });
+        })?;
+        ready_rx.recv().map_err(|_| io::Error::other("io_uring worker died"))??;
+        Ok(Worker { jobs })
+    }
+
+    /// Runs the future `make` returns on the worker's thread.
+    pub fn spawn<F: Future<Output = ()> + 'static>(&self, make: impl FnOnce() -> F + Send + 'static) {
+        let _ = self.jobs.send(Box::new(move || Box::pin(make())));
+    }
+}
+
+/// Starts the I/O workers: one per entry of `cores`, pinned there, or else
+/// `count` unpinned ones.
+pub fn start(count: usize, cores: Option<&[usize]>) -> io::Result<()> {
+    let workers = match cores {
+        Some(cores) => cores
+            .iter()
+            .map(|&core| Worker::start(format!("uring-core-{}", core), Some(core)))
+            .collect::<io::Result<_>>()?,
+        None => (0..count)
+            .map(|i| Worker::start(format!("uring-{}", i), None))
+            .collect::<io::Result<_>>()?,
+    };
+    let _ = WORKERS.set(workers);
+    Ok(())
+}
+
+/// The I/O workers. Empty before `start`.
+pub fn workers() -> &'static [Worker] {
+    WORKERS.get().map_or(&[], Vec::as_slice)
+}
+
+/// A thread's ring and the operations in flight on it, by `user_data`.
+struct Ring {
+    ring: IoUring,
+    ops: Vec<Slot>,
+    free: Vec<usize>,
+}
+
+enum Slot {
+    Free,
+    Waiting(Option<Waker>),
+    Done(i32),
+    /// Its `Op` went away first. The buffer stays here until the kernel is
+    /// done with it.
+    Abandoned { _buf: Option<IoBuf> },
+}
+
+impl Ring {
+    /// Sets up this thread's ring and registers its buffers.
+    fn install() -> io::Result<()> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        buf::register(&ring);
+        RING.with(|r| *r.borrow_mut() = Some(Ring { ring, ops: Vec::new(), free: Vec::new() }));
+        Ok(())
+    }
+
+    /// Queues `entry`, returning its key. Goes to the kernel with the rest
+    /// of the queue on the next `submit`.
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<usize> {
+        let key = match self.free.pop() {
+            Some(key) => key,
+            None => {
+                self.ops.push(Slot::Free);
+                self.ops.len() - 1
+            }
+        };
+        self.ops[key] = Slot::Waiting(None);
+        let entry = entry.user_data(key as u64);
+        // SAFETY: whatever the entry points at is owned by its `Op`, or by
+        // the slot if that goes away first, until the completion comes in.
+        while unsafe { self.ring.submission().push(&entry) }.is_err() {
+            if let Err(e) = self.ring.submit() {
+                self.ops[key] = Slot::Free;
+                self.free.push(key);
+                return Err(e);
+            }
+            self.complete();
+        }
+        Ok(key)
+    }
+
+    /// Hands the completions that came in to their operations.
+    fn complete(&mut self) {
+        let mut done = Vec::new();
+        for cqe in self.ring.completion() {
+            if cqe.user_data() != CANCEL {
+                done.push((cqe.user_data() as usize, cqe.result()));
+            }
+        }
+        for (key, result) in done {
+            match std::mem::replace(&mut self.ops[key], Slot::Done(result)) {
+                Slot::Waiting(waker) => waker.into_iter().for_each(Waker::wake),
+                _ => self.release(key),
+            }
+        }
+    }
+
+    fn release(&mut self, key: usize) {
+        self.ops[key] = Slot::Free;
+        self.free.push(key);
+    }
+}
+
+/// Sends everything queued on this thread's ring to the kernel. Called
+/// whenever the runtime runs out of work, so one syscall submits all the
+/// I/O its tasks started since.
+fn submit() {
+    RING.with(|ring| {
+        if let Some(ring) = ring.borrow_mut().as_mut()
+            && !ring.ring.submission().is_empty()
+            && let Err(e) = ring.ring.submit()
+        {
+            tracing::error!("io_uring submit failed: {}", e);
+        }
+    });
+}
+
+/// Wakes the tasks whose operations completed, whenever the ring's fd says
+/// there are completions.
+async fn reap() {
+    let fd = RING.with(|ring| ring.borrow().as_ref().map(|r| r.ring.as_raw_fd()));
+    let fd: RawFd = fd.expect("no io_uring on this thread");
+    let fd = AsyncFd::new(fd).expect("can't poll the io_uring fd");
+    loop {
+        let Ok(mut ready) = fd.readable().await else {
+            return;
+        };
+        RING.with(|ring| ring.borrow_mut().as_mut().map(Ring::complete));
+        ready.clear_ready();
+    }
+}
+
+/// One operation on the ring, with the buffer it reads into or writes from.
+/// Resolves to the kernel's result; `take_buf` then hands the buffer back.
+pub struct Op {
+    key: Option<usize>,
+    error: Option<io::Error>,
+    buf: Option<IoBuf>,
+}
+
+impl Op {
+    fn new(entry: squeue::Entry, buf: Option<IoBuf>) -> Op {
+        let pushed = RING.with(|ring| match ring.borrow_mut().as_mut() {
+            Some(ring) => ring.push(entry),
+            None => Err(io::Error::other("no io_uring on this thread")),
+        });
+        match pushed {
+            Ok(key) => Op { key: Some(key), error: None, buf },
+            Err(e) => Op { key: None, error: Some(e), buf },
+        }
+    }
+
+    /// Reads from `fd` into `buf`, from its start.
+    pub fn read(fd: RawFd, mut buf: IoBuf) -> Op {
+        let fd = io_uring::types::Fd(fd);
+        let (ptr, cap) = (buf.as_mut_ptr(), buf.capacity() as u32);
+        let entry = match buf.index() {
+            Some(index) => opcode::ReadFixed::new(fd, ptr, cap, index).build(),
+            None => opcode::Read::new(fd, ptr, cap).build(),
+        };
+        Op::new(entry, Some(buf))
+    }
+
+    /// Writes `buf` from byte `from` on, at `offset` in a file (0 for a
+    /// socket).
+    pub fn write(fd: RawFd, buf: IoBuf, from: usize, offset: u64) -> Op {
+        let fd = io_uring::types::Fd(fd);
+        // SAFETY: `from` is within the buffer's filled part.
+        let ptr = unsafe { buf.as_ptr().add(from) };
+        let len = (buf.len() - from) as u32;
+        let entry = match buf.index() {
+            Some(index) => opcode::WriteFixed::new(fd, ptr, len, index).offset(offset).build(),
+            None => opcode::Write::new(fd, ptr, len).offset(offset).build(),
+        };
+        Op::new(entry, Some(buf))
+    }
+
+    /// Syncs the data written to the file `fd`.
+    pub fn fsync(fd: RawFd) -> Op {
+        let entry = opcode::Fsync::new(io_uring::types::Fd(fd)).flags(io_uring::types::FsyncFlags::DATASYNC).build();
+        Op::new(entry, None)
+    }
+
+    /// Accepts a connection on the listening socket `fd`.
+    pub fn accept(fd: RawFd) -> Op {
+        let entry = opcode::Accept::new(io_uring::types::Fd(fd), std::ptr::null_mut(), std::ptr::null_mut())
+            .flags(libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK)
+            .build();
+        Op::new(entry, None)
+    }
+
+    /// The buffer back, once the operation is done.
+    pub fn take_buf(&mut self) -> IoBuf {
+        self.buf.take().expect("operation without a buffer")
+    }
+}
+
+impl Future for Op {
+    type Output = io::Result<u32>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u32>> {
+        let Some(key) = self.key else {
+            return Poll::Ready(Err(self.error.take().unwrap_or_else(|| io::Error::other("operation failed"))));
+        };
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            let ring = ring.as_mut().expect("no io_uring on this thread");
+            match &mut ring.ops[key] {
+                Slot::Waiting(waker) => {
+                    *waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                Slot::Done(result) => {
+                    let result = *result;
+                    ring.release(key);
+                    self.key = None;
+                    Poll::Ready(match result {
+                        n if n >= 0 => Ok(n as u32),
+                        errno => Err(io::Error::from_raw_os_error(-errno)),
+                    })
+                }
+                _ => unreachable!("operation slot reused while in flight"),
+            }
+        })
+    }
+}
+
+/// An operation dropped before it completed is cancelled, so a read doesn't
+/// keep a closed client's socket open. Its buffer waits for the completion.
+impl Drop for Op {
+    fn drop(&mut self) {
+        let Some(key) = self.key else {
+            return;
+        };
+        let buf = self.buf.take();
+        let _ = RING.try_with(|ring| {
+            let mut ring = ring.borrow_mut();
+            let Some(ring) = ring.as_mut() else {
+                return;
+            };
+            match ring.ops[key] {
+                Slot::Done(_) => ring.release(key),
+                _ => {
+                    ring.ops[key] = Slot::Abandoned { _buf: buf };
+                    let cancel = opcode::AsyncCancel::new(key as u64).build().user_data(CANCEL);
+                    // SAFETY: a cancellation points at nothing.
+                    while unsafe { ring.ring.submission().push(&cancel) }.is_err() {
+                        if ring.ring.submit().is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,127 @@
+use std::io::{self, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::Op;
+use super::buf::IoBuf;
+
+/// A listening socket every worker accepts on.
+#[derive(Clone)]
+pub struct TcpListener(Arc<std::net::TcpListener>);
+
+impl TcpListener {
+    pub fn new(listener: std::net::TcpListener) -> TcpListener {
+        TcpListener(Arc::new(listener))
+    }
+
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let fd = Op::accept(self.0.as_raw_fd()).await?;
+        // SAFETY: the kernel just opened it for us.
+        let socket = unsafe { std::net::TcpStream::from_raw_fd(fd as RawFd) };
+        let addr = socket.peer_addr()?;
+        Ok((TcpStream { socket, read: None, unread: None, write: None }, addr))
+    }
+}
+
+/// A client connection whose reads and writes go through the worker's
+/// ring. Writes are handed over as soon as they're in a buffer, like
+/// `tokio::fs::File` does; an error shows up on the next write or flush.
+pub struct TcpStream {
+    socket: std::net::TcpStream,
+    read: Option<Op>,
+    /// Read but not yet taken, from the offset on.
+    unread: Option<(IoBuf, usize)>,
+    /// The write in flight, and how much of its buffer it started at.
+    write: Option<(Op, usize)>,
+}
+
+impl TcpStream {
+    /// Writes what fits into the socket right now, without the ring.
+    pub fn try_write(&self, data: &[u8]) -> io::Result<usize> {
+        (&self.socket).write(data)
+    }
+
+    /// Waits for the write in flight, if any, and sends on what it left.
+    fn poll_written(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some((op, from)) = &mut self.write {
+            let result = ready!(Pin::new(&mut *op).poll(cx));
+            let buf = op.take_buf();
+            let from = *from;
+            self.write = None;
+            let sent = from + result? as usize;
+            if sent == from {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            if sent < buf.len() {
+                self.write = Some((Op::write(self.socket.as_raw_fd(), buf, sent, 0), sent));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsFd for TcpStream {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.unread.is_none() {
+            let fd = this.socket.as_raw_fd();
+            let op = this.read.get_or_insert_with(|| Op::read(fd, IoBuf::new()));
+            let result = ready!(Pin::new(&mut *op).poll(cx));
+            let mut buf = op.take_buf();
+            this.read = None;
+            buf.set_len(result? as usize);
+            this.unread = Some((buf, 0));
+        }
+        let (buf, offset) = this.unread.as_mut().expect("just read");
+        let n = (buf.len() - *offset).min(out.remaining());
+        out.put_slice(&buf.filled()[*offset..*offset + n]);
+        *offset += n;
+        if *offset == buf.len() {
+            this.unread = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(self.poll_written(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let buf = IoBuf::copy_from(data);
+        let n = buf.len();
+        self.write = Some((Op::write(self.socket.as_raw_fd(), buf, 0, 0), 0));
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_written(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_written(cx))?;
+        Poll::Ready(self.socket.shutdown(Shutdown::Write))
+    }
+}
+
+/// Operations still in flight are cancelled, and everything queued goes to
+/// the kernel before the socket closes, so none of it lands on a reused fd.
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.read = None;
+        self.write = None;
+        super::submit();
+    }
+}